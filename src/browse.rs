@@ -0,0 +1,227 @@
+//! A minimal web UI for exploring the build graph, like `ninja -t browse`.
+//! Each page shows one file: the build that generates it, with that build's
+//! inputs and outputs, and the builds that use it as an input.
+
+use crate::graph::{FileId, Graph};
+use anyhow::anyhow;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+
+fn write_html_str(out: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// Encode a path for use as a URL path, leaving '/' unescaped so that
+/// links read naturally.
+fn encode_url(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for &b in path.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            _ => write!(out, "%{:02X}", b).unwrap(),
+        }
+    }
+    out
+}
+
+/// Decode a URL path, as encoded by encode_url or a browser.
+fn decode_url(url: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(url.len());
+    let mut rest = url.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn write_file_list(out: &mut String, graph: &Graph, title: &str, ids: &[FileId]) {
+    if ids.is_empty() {
+        return;
+    }
+    writeln!(out, "<h2>{}</h2>\n<ul>", title).unwrap();
+    for &id in ids {
+        let name = &graph.file(id).name;
+        write!(out, "<li><a href=\"/{}\">", encode_url(name)).unwrap();
+        write_html_str(out, name);
+        out.push_str("</a></li>\n");
+    }
+    out.push_str("</ul>\n");
+}
+
+const HEADER: &str = "<!DOCTYPE html>
+<html><head><meta charset=\"utf-8\"><style>
+body { font-family: sans-serif; }
+pre { background: #eee; padding: 0.5em; white-space: pre-wrap; }
+</style>";
+
+/// Render the page for the file named `name`, or None if there's no such
+/// file in the graph.
+pub fn page(graph: &Graph, name: &str) -> Option<String> {
+    let id = graph.lookup_file_id(name)?;
+    let file = graph.file(id);
+    let mut out = String::from(HEADER);
+    out.push_str("<title>");
+    write_html_str(&mut out, &file.name);
+    out.push_str("</title></head><body>\n<h1>");
+    write_html_str(&mut out, &file.name);
+    out.push_str("</h1>\n");
+
+    match file.input {
+        None => out.push_str("<p>Not generated by any build.</p>\n"),
+        Some(bid) => {
+            let build = graph.build(bid);
+            out.push_str("<p>Generated by rule <b>");
+            write_html_str(&mut out, &build.rule);
+            out.push_str("</b> at ");
+            write_html_str(&mut out, &build.location.to_string());
+            out.push_str("</p>\n");
+            if let Some(cmdline) = &build.cmdline {
+                out.push_str("<pre>");
+                write_html_str(&mut out, cmdline);
+                out.push_str("</pre>\n");
+            }
+            write_file_list(&mut out, graph, "Inputs", build.dirtying_ins());
+            write_file_list(&mut out, graph, "Order-only inputs", build.order_only_ins());
+            write_file_list(&mut out, graph, "Discovered inputs", build.discovered_ins());
+            let others: Vec<FileId> = build
+                .outs()
+                .iter()
+                .copied()
+                .filter(|&out| out != id)
+                .collect();
+            write_file_list(&mut out, graph, "Other outputs", &others);
+        }
+    }
+
+    let mut users: Vec<FileId> = Vec::new();
+    for &bid in &file.dependents {
+        for &out in graph.build(bid).outs() {
+            if !users.contains(&out) {
+                users.push(out);
+            }
+        }
+    }
+    write_file_list(&mut out, graph, "Used by", &users);
+    out.push_str("</body></html>\n");
+    Some(out)
+}
+
+/// Render the index page, listing files that nothing depends on.
+fn index(graph: &Graph) -> String {
+    let roots: Vec<FileId> = graph
+        .files()
+        .filter(|(_, file)| file.input.is_some() && file.dependents.is_empty())
+        .map(|(id, _)| id)
+        .collect();
+    let mut out = String::from(HEADER);
+    out.push_str("<title>n2</title></head><body>\n");
+    write_file_list(&mut out, graph, "Top-level outputs", &roots);
+    out.push_str("</body></html>\n");
+    out
+}
+
+fn respond(mut stream: TcpStream, graph: &Graph, target: Option<&str>) -> std::io::Result<()> {
+    let mut request = String::new();
+    BufReader::new(&stream).read_line(&mut request)?;
+    // The request line is e.g. "GET /path HTTP/1.1".
+    let path = request.split(' ').nth(1).unwrap_or("/");
+    let path = path.split('?').next().unwrap();
+    let name = decode_url(path.strip_prefix('/').unwrap_or(path));
+    let body = match name.as_deref() {
+        Some("") => match target {
+            Some(target) => page(graph, target),
+            None => Some(index(graph)),
+        },
+        Some(name) => page(graph, name),
+        None => None,
+    };
+    let (status, body) = match body {
+        Some(body) => ("200 OK", body),
+        None => ("404 Not Found", "no such file\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.0 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Serve the graph browser on localhost until interrupted.  `target`, if
+/// given, is the file shown at the root URL.
+pub fn serve(graph: &Graph, port: u16, target: Option<&str>) -> anyhow::Result<()> {
+    if let Some(target) = target {
+        if graph.lookup_file_id(target).is_none() {
+            anyhow::bail!("unknown path requested: {:?}", target);
+        }
+    }
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .map_err(|err| anyhow!("bind port {}: {}", port, err))?;
+    println!(
+        "n2: serving http://localhost:{}/, press Ctrl-C to stop",
+        port
+    );
+    for stream in listener.incoming() {
+        if let Err(err) = stream.and_then(|stream| respond(stream, graph, target)) {
+            println!("n2: warning: {}", err);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_round_trip() {
+        for path in ["foo/bar.o", "a b&c", "100%", "ü"] {
+            assert_eq!(decode_url(&encode_url(path)).as_deref(), Some(path));
+        }
+        assert_eq!(encode_url("a b/c"), "a%20b/c");
+        assert_eq!(decode_url("%zz"), None);
+    }
+
+    #[test]
+    fn pages() -> anyhow::Result<()> {
+        let graph = crate::load::parse(
+            "build.ninja",
+            "
+rule cc
+  command = cc $in -o $out
+build a.o: cc a<b.c
+build app: cc a.o
+"
+            .as_bytes(),
+        )?;
+        let a = page(&graph, "a.o").unwrap();
+        assert!(a.contains("<pre>cc a&lt;b.c -o a.o</pre>"));
+        assert!(a.contains("<a href=\"/a%3Cb.c\">a&lt;b.c</a>"));
+        assert!(a.contains("<h2>Used by</h2>\n<ul>\n<li><a href=\"/app\">"));
+        assert!(page(&graph, "a<b.c")
+            .unwrap()
+            .contains("Not generated by any build."));
+        assert!(page(&graph, "missing").is_none());
+        assert!(index(&graph).contains("<a href=\"/app\">"));
+        Ok(())
+    }
+}
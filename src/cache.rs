@@ -0,0 +1,220 @@
+//! A local cache of build outputs, keyed by the content of a build's inputs
+//! and its command, so a build can be satisfied by copying outputs from a
+//! previous identical execution instead of running the command.
+//!
+//! Each entry is a directory named by the key, holding a copy of each output
+//! and a manifest of the build's discovered dependencies.  Discovered deps
+//! aren't part of the key, because they're only known after the build runs;
+//! instead the manifest records their content hashes, and an entry is only
+//! used if those still match.
+
+use crate::graph::{self, Build, FileId, Graph};
+use anyhow::{anyhow, bail};
+use std::collections::HashSet;
+use std::hash::Hasher;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const MANIFEST: &str = "manifest";
+
+pub struct Cache {
+    dir: PathBuf,
+}
+
+/// 64-bit FNV-1a.  Unlike DefaultHasher, whose output may change between
+/// Rust releases, this keeps cache entries valid across rebuilds of n2.
+struct StableHasher(u64);
+
+impl StableHasher {
+    fn new() -> Self {
+        StableHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Hash the content of a file.
+fn hash_file(path: &str) -> std::io::Result<u64> {
+    let mut hasher = StableHasher::new();
+    hasher.write(&std::fs::read(path)?);
+    Ok(hasher.finish())
+}
+
+/// Hash the names and contents of input files.  The output of a phony build
+/// stands for the phony's own inputs, which are hashed in its place.  The
+/// inputs of a phony build may be missing source files, as for hash_build();
+/// any other file that can't be read makes this return false.
+fn hash_ins(
+    hasher: &mut StableHasher,
+    graph: &Graph,
+    ids: &[FileId],
+    phony: bool,
+    expanded: &mut HashSet<FileId>,
+) -> bool {
+    for &id in ids {
+        let file = graph.file(id);
+        hasher.write_u8(0);
+        hasher.write(file.name.as_bytes());
+        match file.input {
+            Some(bid) if graph.build(bid).cmdline.is_none() => {
+                let ins = graph.build(bid).dirtying_ins();
+                if expanded.insert(id) && !hash_ins(hasher, graph, ins, true, expanded) {
+                    return false;
+                }
+            }
+            input => match hash_file(&file.name) {
+                Ok(hash) => hasher.write_u64(hash),
+                Err(err)
+                    if phony && input.is_none() && err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(_) => return false,
+            },
+        }
+    }
+    true
+}
+
+fn out_path(entry: &Path, index: usize) -> PathBuf {
+    entry.join(format!("out{}", index))
+}
+
+impl Cache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Cache { dir: dir.into() }
+    }
+
+    /// Compute the cache key for a build: a hash of what hash_build() covers,
+    /// with the contents of the inputs in place of their mtimes, and the
+    /// names of the outputs.  `root` is as for hash_build().  Returns None if
+    /// an input can't be read, e.g. because it is a directory, in which case
+    /// the build isn't cached.
+    fn key(
+        &self,
+        graph: &Graph,
+        build: &Build,
+        root: Option<&str>,
+    ) -> anyhow::Result<Option<String>> {
+        let mut hasher = StableHasher::new();
+        graph::hash_command(&mut hasher, build, root)?;
+        if !hash_ins(
+            &mut hasher,
+            graph,
+            build.dirtying_ins(),
+            false,
+            &mut HashSet::new(),
+        ) {
+            return Ok(None);
+        }
+        for &id in build.outs() {
+            hasher.write_u8(0);
+            hasher.write(graph.file(id).name.as_bytes());
+        }
+        Ok(Some(format!("{:016x}", hasher.finish())))
+    }
+
+    /// Look for a cached execution of a build, and if present copy its outputs
+    /// into place.  Returns the discovered deps of the cached execution.
+    pub fn restore(
+        &self,
+        graph: &Graph,
+        build: &Build,
+        root: Option<&str>,
+    ) -> anyhow::Result<Option<Vec<String>>> {
+        let entry = match self.key(graph, build, root)? {
+            Some(key) => self.dir.join(key),
+            None => return Ok(None),
+        };
+        let manifest = match std::fs::read_to_string(entry.join(MANIFEST)) {
+            Ok(manifest) => manifest,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => bail!("read {}: {}", entry.display(), err),
+        };
+        let mut deps = Vec::new();
+        for line in manifest.lines() {
+            let (hash, path) = match line.split_once(' ') {
+                Some(dep) => dep,
+                None => bail!("{}: corrupt manifest", entry.display()),
+            };
+            match hash_file(path) {
+                Ok(h) if format!("{:016x}", h) == hash => {}
+                // A dependency changed (or vanished) since the entry was made.
+                _ => return Ok(None),
+            }
+            deps.push(path.to_string());
+        }
+        for (i, &id) in build.outs().iter().enumerate() {
+            let path = Path::new(&graph.file(id).name);
+            let cached = out_path(&entry, i);
+            // An optional output the cached execution didn't produce.
+            if build.optional_outs.contains(&id) && !cached.exists() {
+                match std::fs::remove_file(path) {
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                        bail!("restore {}: {}", path.display(), err)
+                    }
+                    _ => continue,
+                }
+            }
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::copy(cached, path)
+                .map_err(|err| anyhow!("restore {}: {}", path.display(), err))?;
+        }
+        Ok(Some(deps))
+    }
+
+    /// Store the outputs of a build that just ran successfully and was
+    /// recorded as up to date.
+    pub fn store(&self, graph: &Graph, build: &Build, root: Option<&str>) -> anyhow::Result<()> {
+        let key = match self.key(graph, build, root)? {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        // Write the entry under a temporary name and then move it into place,
+        // so a concurrent reader never sees a partial entry.
+        let tmp = self.dir.join(format!("tmp-{}-{}", key, std::process::id()));
+        if let Err(err) = write_entry(&tmp, graph, build) {
+            let _ = std::fs::remove_dir_all(&tmp);
+            return Err(err);
+        }
+
+        let entry = self.dir.join(&key);
+        if entry.exists() {
+            std::fs::remove_dir_all(&entry)?;
+        }
+        std::fs::rename(&tmp, &entry)?;
+        Ok(())
+    }
+}
+
+fn write_entry(dir: &Path, graph: &Graph, build: &Build) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    for (i, &id) in build.outs().iter().enumerate() {
+        let name = &graph.file(id).name;
+        match std::fs::copy(name, out_path(dir, i)) {
+            Ok(_) => {}
+            // An optional output that wasn't produced is left out.
+            Err(err)
+                if err.kind() == std::io::ErrorKind::NotFound
+                    && build.optional_outs.contains(&id) => {}
+            Err(err) => bail!("{}: {}", name, err),
+        }
+    }
+    let mut manifest = std::fs::File::create(dir.join(MANIFEST))?;
+    for &id in build.discovered_ins() {
+        let name = &graph.file(id).name;
+        let hash = hash_file(name).map_err(|err| anyhow!("{}: {}", name, err))?;
+        writeln!(manifest, "{:016x} {}", hash, name)?;
+    }
+    Ok(())
+}
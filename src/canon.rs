@@ -54,7 +54,8 @@ pub fn canon_path_in_place(path: &mut String) {
         if src == end {
             return;
         }
-        if *src == b'/' {
+        let absolute = *src == b'/';
+        if absolute {
             src = src.add(1);
             dst = dst.add(1);
         }
@@ -89,6 +90,8 @@ pub fn canon_path_in_place(path: &mut String) {
                             // ".." component, try to back up.
                             if let Some(ofs) = components.pop() {
                                 dst = ofs;
+                            } else if absolute {
+                                // Can't go above the root; "/.." is "/".
                             } else {
                                 *dst = b'.';
                                 dst = dst.add(1);
@@ -164,5 +167,10 @@ mod tests {
         assert_eq!(canon_path("../foo"), "../foo");
         assert_eq!(canon_path("../foo/../bar"), "../bar");
         assert_eq!(canon_path("../../bar"), "../../bar");
+
+        assert_eq!(canon_path("/.."), "/");
+        assert_eq!(canon_path("/../foo"), "/foo");
+        assert_eq!(canon_path("/a/../../b"), "/b");
+        assert_eq!(canon_path("/../../a/.."), "/");
     }
 }
@@ -0,0 +1,145 @@
+//! Export of build commands as a compilation database, the
+//! compile_commands.json format understood by clang tooling.
+
+use crate::graph::{Build, FileId, Graph};
+use std::collections::HashSet;
+use std::fmt::Write;
+
+/// Which builds to include in a compilation database.
+#[derive(Default)]
+pub struct Filter<'a> {
+    /// Only include builds using one of these rules; all rules if empty.
+    pub rules: &'a [String],
+    /// Only include builds needed to bring these files up to date; all builds
+    /// if None.
+    pub targets: Option<&'a [FileId]>,
+}
+
+/// Collect the builds that `targets` transitively depend on.
+fn reachable_builds<'a>(graph: &'a Graph, targets: &[FileId]) -> Vec<&'a Build> {
+    let mut seen = HashSet::new();
+    let mut builds = Vec::new();
+    let mut stack = targets.to_vec();
+    while let Some(id) = stack.pop() {
+        let bid = match graph.file(id).input {
+            None => continue,
+            Some(bid) => bid,
+        };
+        if !seen.insert(bid) {
+            continue;
+        }
+        let build = graph.build(bid);
+        builds.push(build);
+        stack.extend_from_slice(build.ordering_ins());
+    }
+    builds
+}
+
+pub(crate) fn write_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Generate a compilation database for the builds in the graph selected by
+/// `filter`.  Entries are sorted by output path, so the result is stable.
+pub fn write(graph: &Graph, directory: &str, filter: &Filter) -> String {
+    let builds: Vec<&Build> = match filter.targets {
+        Some(targets) => reachable_builds(graph, targets),
+        None => graph.builds().map(|(_, build)| build).collect(),
+    };
+    let mut entries: Vec<(&str, &str, &str)> = builds
+        .into_iter()
+        .filter(|build| filter.rules.is_empty() || filter.rules.contains(&build.rule))
+        .filter_map(|build| {
+            let command = build.cmdline.as_deref()?;
+            let file = match build.explicit_ins().first() {
+                Some(&id) => graph.file(id).name.as_str(),
+                None => "",
+            };
+            let output = graph.file(*build.outs().first()?).name.as_str();
+            Some((output, file, command))
+        })
+        .collect();
+    entries.sort();
+
+    let mut out = String::from("[");
+    for (i, (output, file, command)) in entries.into_iter().enumerate() {
+        out.push_str(if i == 0 { "\n" } else { ",\n" });
+        out.push_str("  {\n    \"directory\": ");
+        write_json_str(&mut out, directory);
+        out.push_str(",\n    \"command\": ");
+        write_json_str(&mut out, command);
+        out.push_str(",\n    \"file\": ");
+        write_json_str(&mut out, file);
+        out.push_str(",\n    \"output\": ");
+        write_json_str(&mut out, output);
+        out.push_str("\n  }");
+    }
+    out.push_str("\n]\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = "
+rule cc
+  command = cc -c $in -o $out
+rule link
+  command = ld $in -o $out
+build b.o: cc b.c
+build a.o: cc a.c
+build unused.o: cc \"q\".c
+build app: link a.o
+";
+
+    #[test]
+    fn filters() -> anyhow::Result<()> {
+        let mut graph = crate::load::parse("build.ninja", MANIFEST.as_bytes())?;
+        let outputs = |graph: &Graph, filter: &Filter| -> String {
+            write(graph, "/d", filter)
+                .lines()
+                .filter_map(|l| l.trim().strip_prefix("\"output\": "))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+
+        let all = outputs(&graph, &Filter::default());
+        assert_eq!(all, r#""a.o" "app" "b.o" "unused.o""#);
+
+        let rules = ["cc".to_string()];
+        let cc = Filter {
+            rules: &rules,
+            targets: None,
+        };
+        assert_eq!(outputs(&graph, &cc), r#""a.o" "b.o" "unused.o""#);
+
+        let app = [graph.file_id(&mut "app".to_string())];
+        let cc_app = Filter {
+            rules: &rules,
+            targets: Some(&app),
+        };
+        assert_eq!(outputs(&graph, &cc_app), r#""a.o""#);
+        Ok(())
+    }
+
+    #[test]
+    fn escapes() -> anyhow::Result<()> {
+        let graph = crate::load::parse("build.ninja", MANIFEST.as_bytes())?;
+        let json = write(&graph, "C:\\dir", &Filter::default());
+        assert!(json.contains(r#""directory": "C:\\dir""#));
+        assert!(json.contains(r#""command": "cc -c \"q\".c -o unused.o""#));
+        Ok(())
+    }
+}
@@ -15,6 +15,7 @@ use std::fs::File;
 use std::io::BufReader;
 use std::io::Read;
 use std::io::Write;
+use std::time::{Duration, SystemTime};
 
 /// Files are identified by integers that are stable across n2 executions.
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
@@ -118,19 +119,28 @@ impl WriteBuf {
     }
 }
 
+/// Records start with a u16: a filename length, a build's output count with
+/// the high bit set, or this value for a content digest record.
+const DIGEST_MARK: u16 = 0b0111_1111_1111_1111;
+
+/// Content digests of files, with the mtime each had when its digest was
+/// recorded.
+type Digests = HashMap<FileId, (SystemTime, u64)>;
+
 /// An opened database, ready for writes.
 pub struct Writer {
     ids: IdMap,
+    digests: Digests,
     w: File,
 }
 
 impl Writer {
-    fn new(ids: IdMap, w: File) -> Self {
-        Writer { ids, w }
+    fn new(ids: IdMap, digests: Digests, w: File) -> Self {
+        Writer { ids, digests, w }
     }
 
     fn write_file(&mut self, name: &str) -> std::io::Result<()> {
-        if name.len() >= 0b1000_0000_0000_0000 {
+        if name.len() >= DIGEST_MARK as usize {
             panic!("filename too long");
         }
         let mut buf = WriteBuf::new();
@@ -173,6 +183,36 @@ impl Writer {
 
         buf.flush(&mut self.w)
     }
+
+    /// The last recorded content digest of a file, along with its mtime at
+    /// the time.
+    pub fn digest(&self, id: FileId) -> Option<(SystemTime, u64)> {
+        self.digests.get(&id).copied()
+    }
+
+    /// Record the content digest of a file with the given mtime.
+    pub fn write_digest(
+        &mut self,
+        graph: &Graph,
+        fileid: FileId,
+        mtime: SystemTime,
+        digest: u64,
+    ) -> std::io::Result<()> {
+        let nanos = match mtime.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(since) => since.as_nanos() as u64,
+            // Not worth the trouble of representing.
+            Err(_) => return Ok(()),
+        };
+        let id = self.ensure_id(graph, fileid)?;
+        let mut buf = WriteBuf::new();
+        buf.write_u16(DIGEST_MARK);
+        buf.write_id(id);
+        buf.write_u64(nanos);
+        buf.write_u64(digest);
+        buf.flush(&mut self.w)?;
+        self.digests.insert(fileid, (mtime, digest));
+        Ok(())
+    }
 }
 
 /// Provides lower-level methods for reading serialized data.
@@ -218,6 +258,16 @@ impl<'a> BReader<'a> {
         self.read_u24().map(|n| Id(n as u32))
     }
 
+    /// Read an id and map it to its file, failing if the id is unknown, as
+    /// in a corrupt database.
+    fn read_fileid(&mut self, ids: &IdMap) -> anyhow::Result<FileId> {
+        let id = self.read_id()?;
+        match ids.fileids.lookup(id) {
+            Some(&fileid) => Ok(fileid),
+            None => bail!("corrupt database: unknown file id {}", id.0),
+        }
+    }
+
     fn read_str(&mut self, len: usize) -> std::io::Result<String> {
         let mut buf = Vec::with_capacity(len);
         // Safety: buf contents are uninitialized here, but we never read them
@@ -234,6 +284,7 @@ fn read(mut f: File, graph: &mut Graph, hashes: &mut Hashes) -> anyhow::Result<W
         r: std::io::BufReader::new(&mut f),
     };
     let mut ids = IdMap::new();
+    let mut digests = Digests::new();
 
     loop {
         let mut len = match r.read_u16() {
@@ -242,7 +293,12 @@ fn read(mut f: File, graph: &mut Graph, hashes: &mut Hashes) -> anyhow::Result<W
             Err(err) => bail!(err),
         };
         let mask = 0b1000_0000_0000_0000;
-        if len & mask == 0 {
+        if len == DIGEST_MARK {
+            let id = r.read_fileid(&ids)?;
+            let mtime = SystemTime::UNIX_EPOCH + Duration::from_nanos(r.read_u64()?);
+            let digest = r.read_u64()?;
+            digests.insert(id, (mtime, digest));
+        } else if len & mask == 0 {
             let mut name = r.read_str(len as usize)?;
             let fileid = graph.file_id(&mut name);
             let dbid = ids.fileids.push(fileid);
@@ -254,8 +310,8 @@ fn read(mut f: File, graph: &mut Graph, hashes: &mut Hashes) -> anyhow::Result<W
             // In the common case, there is only one.
             let mut bids = HashSet::new();
             for _ in 0..len {
-                let id = r.read_id()?;
-                if let Some(bid) = graph.file(*ids.fileids.get(id)).input {
+                let id = r.read_fileid(&ids)?;
+                if let Some(bid) = graph.file(id).input {
                     bids.insert(bid);
                 }
             }
@@ -263,8 +319,7 @@ fn read(mut f: File, graph: &mut Graph, hashes: &mut Hashes) -> anyhow::Result<W
             let len = r.read_u16()?;
             let mut deps = Vec::new();
             for _ in 0..len {
-                let id = r.read_id()?;
-                deps.push(*ids.fileids.get(id));
+                deps.push(r.read_fileid(&ids)?);
             }
 
             let hash = Hash(r.read_u64()?);
@@ -280,7 +335,7 @@ fn read(mut f: File, graph: &mut Graph, hashes: &mut Hashes) -> anyhow::Result<W
         }
     }
 
-    Ok(Writer::new(ids, f))
+    Ok(Writer::new(ids, digests, f))
 }
 
 /// Opens or creates an on-disk database, loading its state into the provided Graph.
@@ -293,7 +348,7 @@ pub fn open(path: &str, graph: &mut Graph, hashes: &mut Hashes) -> anyhow::Resul
         Ok(f) => read(f, graph, hashes),
         Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
             let f = std::fs::File::create(path)?;
-            Ok(Writer::new(IdMap::new(), f))
+            Ok(Writer::new(IdMap::new(), Digests::new(), f))
         }
         Err(err) => Err(anyhow!(err)),
     }
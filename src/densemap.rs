@@ -21,14 +21,25 @@ impl<K: Index, V> DenseMap<K, V> {
         }
     }
 
+    /// Reserve space for at least `additional` more values.
+    pub fn reserve(&mut self, additional: usize) {
+        self.vec.reserve(additional);
+    }
+
+    /// Get the value for a key, panicking if the key is out of range.  Use
+    /// this for keys handed out by this map, which are always in range.
     pub fn get(&self, k: K) -> &V {
         &self.vec[k.index()]
     }
 
+    /// Like get(), but mutable.
     pub fn get_mut(&mut self, k: K) -> &mut V {
         &mut self.vec[k.index()]
     }
 
+    /// Get the value for a key, or None if the key is out of range, as with
+    /// keys from outside this map (e.g. read from disk) or for a map that is
+    /// grown on demand.
     pub fn lookup(&self, k: K) -> Option<&V> {
         self.vec.get(k.index())
     }
@@ -37,6 +48,16 @@ impl<K: Index, V> DenseMap<K, V> {
         K::from(self.vec.len())
     }
 
+    /// Iterate over the values in key order.
+    pub fn values(&self) -> std::slice::Iter<'_, V> {
+        self.vec.iter()
+    }
+
+    /// Iterate over the keys and values in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (K, &V)> {
+        self.vec.iter().enumerate().map(|(i, v)| (K::from(i), v))
+    }
+
     pub fn push(&mut self, val: V) -> K {
         let id = self.next_id();
         self.vec.push(val);
@@ -58,3 +79,33 @@ impl<K: Index, V: Clone> DenseMap<K, V> {
         self.vec[k.index()] = v
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy)]
+    struct Id(usize);
+    impl Index for Id {
+        fn index(&self) -> usize {
+            self.0
+        }
+    }
+    impl From<usize> for Id {
+        fn from(u: usize) -> Id {
+            Id(u)
+        }
+    }
+
+    #[test]
+    fn reserve_avoids_reallocation() {
+        let n = 100_000;
+        let mut map: DenseMap<Id, u64> = DenseMap::new();
+        map.reserve(n);
+        let capacity = map.vec.capacity();
+        for i in 0..n {
+            map.push(i as u64);
+        }
+        assert_eq!(map.vec.capacity(), capacity);
+    }
+}
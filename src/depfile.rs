@@ -77,7 +77,7 @@ pub fn parse<'a>(scanner: &mut Scanner<'a>) -> ParseResult<Deps<'a>> {
 mod tests {
     use super::*;
 
-    fn must_parse(buf: &mut Vec<u8>) -> Deps {
+    fn must_parse(buf: &[u8]) -> Deps {
         let mut scanner = Scanner::new(buf);
         match parse(&mut scanner) {
             Err(err) => {
@@ -90,8 +90,8 @@ mod tests {
 
     #[test]
     fn test_parse() {
-        let mut file = b"build/browse.o: src/browse.cc src/browse.h build/browse_py.h\n".to_vec();
-        let deps = must_parse(&mut file);
+        let file = b"build/browse.o: src/browse.cc src/browse.h build/browse_py.h\n";
+        let deps = must_parse(file);
         println!("{:?}", deps);
         assert_eq!(deps.target, "build/browse.o");
         assert_eq!(deps.deps.len(), 3);
@@ -99,24 +99,24 @@ mod tests {
 
     #[test]
     fn test_parse_space_suffix() {
-        let mut file = b"build/browse.o: src/browse.cc   ".to_vec();
-        let deps = must_parse(&mut file);
+        let file = b"build/browse.o: src/browse.cc   ";
+        let deps = must_parse(file);
         assert_eq!(deps.target, "build/browse.o");
         assert_eq!(deps.deps.len(), 1);
     }
 
     #[test]
     fn test_parse_multiline() {
-        let mut file = b"build/browse.o: src/browse.cc\\\n  build/browse_py.h".to_vec();
-        let deps = must_parse(&mut file);
+        let file = b"build/browse.o: src/browse.cc\\\n  build/browse_py.h";
+        let deps = must_parse(file);
         assert_eq!(deps.target, "build/browse.o");
         assert_eq!(deps.deps.len(), 2);
     }
 
     #[test]
     fn test_parse_without_final_newline() {
-        let mut file = b"build/browse.o: src/browse.cc".to_vec();
-        let deps = must_parse(&mut file);
+        let file = b"build/browse.o: src/browse.cc";
+        let deps = must_parse(file);
         assert_eq!(deps.target, "build/browse.o");
         assert_eq!(deps.deps.len(), 1);
     }
@@ -1,6 +1,7 @@
 //! Represents parsed Ninja strings with embedded variable references, e.g.
 //! `c++ $in -o $out`, and mechanisms for expanding those into plain strings.
 
+use crate::parse::is_ident_char;
 use std::{borrow::Cow, collections::HashMap};
 
 /// An environment providing a mapping of variable name to variable value.
@@ -45,6 +46,57 @@ impl<T: AsRef<str>> EvalString<T> {
         val
     }
 }
+/// Whether a character may appear in a bare `$var` reference that means the
+/// same thing to both n2 and Ninja.
+fn is_simple_var_char(c: char) -> bool {
+    matches!(c, 'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-')
+}
+
+impl<T: AsRef<str>> EvalString<T> {
+    /// Serialize back to .ninja syntax, with `$`-escapes as needed.
+    /// Paths additionally need spaces and colons escaped.
+    pub fn to_ninja(&self, path: bool) -> String {
+        let mut out = String::new();
+        for (i, part) in self.0.iter().enumerate() {
+            match part {
+                EvalPart::Literal(s) => {
+                    for c in s.as_ref().chars() {
+                        match c {
+                            '$' => out.push_str("$$"),
+                            ' ' | ':' if path => {
+                                out.push('$');
+                                out.push(c);
+                            }
+                            // Leading spaces in a value would otherwise be
+                            // skipped when parsing.
+                            ' ' if out.is_empty() => out.push_str("$ "),
+                            c => out.push(c),
+                        }
+                    }
+                }
+                EvalPart::VarRef(v) => {
+                    let v = v.as_ref();
+                    let next_is_simple = match self.0.get(i + 1) {
+                        Some(EvalPart::Literal(s)) => {
+                            matches!(s.as_ref().bytes().next(), Some(c) if is_ident_char(c))
+                        }
+                        _ => false,
+                    };
+                    if next_is_simple || v.is_empty() || !v.chars().all(is_simple_var_char) {
+                        out.push_str("${");
+                        out.push_str(v);
+                        out.push('}');
+                    } else {
+                        out.push('$');
+                        out.push_str(v);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
 impl EvalString<&str> {
     pub fn into_owned(self) -> EvalString<String> {
         EvalString(
@@ -73,6 +125,9 @@ impl<'text> Vars<'text> {
     pub fn get(&self, key: &'text str) -> Option<&String> {
         self.0.get(key)
     }
+    pub fn keyvals(&self) -> &HashMap<&'text str, String> {
+        &self.0
+    }
 }
 impl<'a> Env for Vars<'a> {
     fn get_var(&self, var: &str) -> Option<Cow<str>> {
@@ -80,6 +135,16 @@ impl<'a> Env for Vars<'a> {
     }
 }
 
+/// Variables from the process environment.
+/// Not consulted by default; loading can opt in to using it as a fallback for
+/// variables not otherwise defined in the manifest.
+pub struct OsEnv;
+impl Env for OsEnv {
+    fn get_var(&self, var: &str) -> Option<Cow<'_, str>> {
+        std::env::var(var).ok().map(Cow::Owned)
+    }
+}
+
 /// A single scope's worth of variable definitions, before $-expansion.
 /// For variables attached to a rule we keep them unexpanded in memory because
 /// they may be expanded in multiple different ways depending on which rule uses
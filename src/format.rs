@@ -0,0 +1,363 @@
+//! Formatter for .ninja files, rewriting them with canonical whitespace.
+//!
+//! Unlike the parser in parse.rs, this keeps paths and variable values
+//! unexpanded so that the output means the same thing as the input.
+
+use crate::eval::{EvalPart, EvalString};
+use crate::parse::{is_ident_char, is_path_char};
+use crate::scanner::{ParseResult, Scanner};
+
+/// The kind of the previously written statement, used to decide on blank
+/// lines between statements.
+#[derive(Clone, Copy, PartialEq)]
+enum Prev {
+    Start,
+    Comment,
+    /// A rule or pool, which are always surrounded by blank lines.
+    Block,
+    Line,
+}
+
+struct Formatter<'text> {
+    scanner: Scanner<'text>,
+    out: String,
+    prev: Prev,
+    /// Whether the input had a blank line before the current statement.
+    blank: bool,
+}
+
+impl<'text> Formatter<'text> {
+    fn start_statement(&mut self, kind: Prev) {
+        let separate = match (self.prev, kind) {
+            (Prev::Start, _) => false,
+            (Prev::Block, _) => true,
+            // Keep a comment attached to the block that follows it.
+            (Prev::Comment, Prev::Block) => self.blank,
+            (_, Prev::Block) => true,
+            _ => self.blank,
+        };
+        if separate {
+            self.out.push('\n');
+        }
+        self.prev = kind;
+        self.blank = false;
+    }
+
+    fn format(&mut self) -> ParseResult<()> {
+        loop {
+            match self.scanner.peek() {
+                '\0' => return Ok(()),
+                '\n' => {
+                    self.scanner.next();
+                    self.blank = true;
+                }
+                '#' => {
+                    self.start_statement(Prev::Comment);
+                    let start = self.scanner.ofs;
+                    while !matches!(self.scanner.peek(), '\n' | '\0') {
+                        self.scanner.next();
+                    }
+                    let comment = self.scanner.slice(start, self.scanner.ofs);
+                    self.out.push_str(comment.trim_end());
+                    self.out.push('\n');
+                    self.scanner.skip('\n');
+                }
+                ' ' | '\t' => return self.scanner.parse_error("unexpected whitespace"),
+                _ => self.format_statement()?,
+            }
+        }
+    }
+
+    fn format_statement(&mut self) -> ParseResult<()> {
+        let ident = self.read_ident()?;
+        self.scanner.skip_spaces();
+        match ident {
+            "rule" | "pool" => {
+                self.start_statement(Prev::Block);
+                let name = self.read_ident()?;
+                self.scanner.skip_spaces();
+                self.scanner.expect('\n')?;
+                self.out.push_str(ident);
+                self.out.push(' ');
+                self.out.push_str(name);
+                self.out.push('\n');
+                self.format_scoped_vars()?;
+            }
+            "build" => {
+                self.start_statement(Prev::Line);
+                self.out.push_str("build");
+                self.format_paths()?;
+                if self.scanner.skip('|') {
+                    self.out.push_str(" |");
+                    self.format_paths()?;
+                }
+                self.scanner.expect(':')?;
+                self.scanner.skip_spaces();
+                let rule = self.read_ident()?;
+                self.out.push_str(": ");
+                self.out.push_str(rule);
+                self.format_paths()?;
+                if self.scanner.peek() == '|' {
+                    self.scanner.next();
+                    if self.scanner.skip('|') {
+                        self.out.push_str(" ||");
+                    } else {
+                        self.out.push_str(" |");
+                        self.format_paths()?;
+                        if self.scanner.skip('|') {
+                            self.scanner.expect('|')?;
+                            self.out.push_str(" ||");
+                        }
+                    }
+                    self.format_paths()?;
+                }
+                self.scanner.expect('\n')?;
+                self.out.push('\n');
+                self.format_scoped_vars()?;
+            }
+            "default" | "include" | "subninja" => {
+                self.start_statement(Prev::Line);
+                self.out.push_str(ident);
+                self.format_paths()?;
+                self.scanner.expect('\n')?;
+                self.out.push('\n');
+            }
+            name => {
+                self.start_statement(Prev::Line);
+                self.format_vardef(name)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn format_scoped_vars(&mut self) -> ParseResult<()> {
+        while self.scanner.peek() == ' ' {
+            self.scanner.skip_spaces();
+            let name = self.read_ident()?;
+            self.scanner.skip_spaces();
+            self.out.push_str("  ");
+            self.format_vardef(name)?;
+        }
+        Ok(())
+    }
+
+    fn format_vardef(&mut self, name: &str) -> ParseResult<()> {
+        self.scanner.expect('=')?;
+        self.scanner.skip_spaces();
+        let val = self.read_eval()?.to_ninja(false);
+        self.out.push_str(name);
+        self.out.push_str(" =");
+        if !val.is_empty() {
+            self.out.push(' ');
+            self.out.push_str(&val);
+        }
+        self.out.push('\n');
+        Ok(())
+    }
+
+    /// Copy a space-separated list of paths, each preceded by a space.
+    fn format_paths(&mut self) -> ParseResult<()> {
+        self.scanner.skip_spaces();
+        while let Some(path) = self.read_path()? {
+            self.out.push(' ');
+            self.out.push_str(&path.to_ninja(true));
+            self.scanner.skip_spaces();
+        }
+        Ok(())
+    }
+
+    fn read_ident(&mut self) -> ParseResult<&'text str> {
+        let start = self.scanner.ofs;
+        while is_ident_char(self.scanner.read() as u8) {}
+        self.scanner.back();
+        let end = self.scanner.ofs;
+        if end == start {
+            return self.scanner.parse_error("failed to scan ident");
+        }
+        Ok(self.scanner.slice(start, end))
+    }
+
+    fn read_eval(&mut self) -> ParseResult<EvalString<&'text str>> {
+        let mut parts = Vec::new();
+        let mut ofs = self.scanner.ofs;
+        loop {
+            match self.scanner.read() {
+                '\0' => return self.scanner.parse_error("unexpected EOF"),
+                '\n' => break,
+                '$' => {
+                    let end = self.scanner.ofs - 1;
+                    if end > ofs {
+                        parts.push(EvalPart::Literal(self.scanner.slice(ofs, end)));
+                    }
+                    parts.push(self.read_escape()?);
+                    ofs = self.scanner.ofs;
+                }
+                _ => {}
+            }
+        }
+        let end = self.scanner.ofs - 1;
+        if end > ofs {
+            parts.push(EvalPart::Literal(self.scanner.slice(ofs, end)));
+        }
+        Ok(EvalString::new(parts))
+    }
+
+    fn read_path(&mut self) -> ParseResult<Option<EvalString<&'text str>>> {
+        let mut parts = Vec::new();
+        let mut ofs = self.scanner.ofs;
+        loop {
+            let c = self.scanner.read();
+            if is_path_char(c as u8) {
+                continue;
+            }
+            let end = self.scanner.ofs - 1;
+            if end > ofs {
+                parts.push(EvalPart::Literal(self.scanner.slice(ofs, end)));
+            }
+            match c {
+                '$' => {
+                    parts.push(self.read_escape()?);
+                    ofs = self.scanner.ofs;
+                }
+                '\0' => {
+                    self.scanner.back();
+                    return self.scanner.parse_error("unexpected EOF");
+                }
+                _ => {
+                    self.scanner.back();
+                    break;
+                }
+            }
+        }
+        if parts.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(EvalString::new(parts)))
+    }
+
+    fn read_escape(&mut self) -> ParseResult<EvalPart<&'text str>> {
+        Ok(match self.scanner.read() {
+            '\0' => {
+                // Point the error at the '$'.
+                self.scanner.back();
+                self.scanner.back();
+                return self.scanner.parse_error("unexpected end of file after '$'");
+            }
+            '\n' => {
+                self.scanner.skip_spaces();
+                EvalPart::Literal(self.scanner.slice(0, 0))
+            }
+            ' ' | '$' | ':' => {
+                EvalPart::Literal(self.scanner.slice(self.scanner.ofs - 1, self.scanner.ofs))
+            }
+            '{' => {
+                let start = self.scanner.ofs;
+                loop {
+                    match self.scanner.read() {
+                        '\0' => return self.scanner.parse_error("unexpected EOF"),
+                        '}' => break,
+                        _ => {}
+                    }
+                }
+                let end = self.scanner.ofs - 1;
+                EvalPart::VarRef(self.scanner.slice(start, end))
+            }
+            _ => {
+                self.scanner.back();
+                EvalPart::VarRef(self.read_ident()?)
+            }
+        })
+    }
+}
+
+/// Format the contents of a .ninja file.  `filename` is used in error
+/// messages.
+pub fn format(filename: &str, buf: &[u8]) -> anyhow::Result<String> {
+    let mut formatter = Formatter {
+        scanner: Scanner::new(buf),
+        out: String::new(),
+        prev: Prev::Start,
+        blank: false,
+    };
+    formatter
+        .format()
+        .map_err(|err| anyhow::anyhow!(formatter.scanner.format_parse_error(filename, err)))?;
+    Ok(formatter.out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn must_format(text: &str) -> String {
+        format("test", text.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn canonical_unchanged() {
+        let text = "# A comment.
+cflags = -O2 -g
+
+rule cc
+  command = cc $cflags -c $in -o ${out}_tmp
+  description = CC $out
+
+pool link
+  depth = 1
+
+build foo.o: cc foo.c | foo.h || gen
+build bar$ baz.o | bar.d: cc bar$:baz.c
+  cflags = $cflags -Wall
+
+build all: phony foo.o
+default all
+";
+        assert_eq!(must_format(text), text);
+    }
+
+    #[test]
+    fn normalize_whitespace() {
+        let text = "
+
+
+x=1
+rule   cc
+     command   =   cc $
+         $in -o $out
+
+
+
+build  foo.o :cc  foo.c|foo.h   ||  gen
+    flags =   $x
+";
+        assert_eq!(
+            must_format(text),
+            "x = 1
+
+rule cc
+  command = cc $in -o $out
+
+build foo.o: cc foo.c | foo.h || gen
+  flags = $x
+"
+        );
+    }
+
+    #[test]
+    fn idempotent() {
+        let text = "rule cc
+  command = cc $in
+build a.o: cc a.c
+# About b.
+rule other
+  command = $$HOME/${x}y
+";
+        let once = must_format(text);
+        assert_eq!(must_format(&once), once);
+    }
+
+    #[test]
+    fn escape_leading_space() {
+        assert_eq!(must_format("x = $ y\n"), "x = $ y\n");
+    }
+}
@@ -0,0 +1,112 @@
+//! Progress reporting for a separate frontend program, like Ninja's
+//! `--frontend`: instead of drawing to the console, build events are written
+//! as newline-delimited JSON objects, one per event, for the frontend to
+//! render however it likes.
+//!
+//! Events are:
+//! - `{"event":"status","total":N,"done":N,"running":N}` when counts change;
+//! - `{"event":"started","id":N,"description":S,"command":S}` when a command
+//!   starts;
+//! - `{"event":"finished","id":N,"success":B,"exit_code":N|null,"output":S}`
+//!   when it completes, with the same id as the matching "started";
+//! - `{"event":"warning","message":S}` for a warning about the build;
+//! - `{"event":"done"}` when the build is over.
+
+use crate::compdb::write_json_str;
+use crate::densemap::Index;
+use crate::graph::{Build, BuildId};
+use crate::progress::{build_message, Progress};
+use crate::work::{BuildState, StateCounts};
+use std::fmt::Write as _;
+use std::io::Write;
+use std::time::Duration;
+
+pub struct JsonProgress<W: Write> {
+    out: W,
+    /// Last (total, done, running) reported, to avoid repeating status events.
+    last_status: (usize, usize, usize),
+}
+
+impl<W: Write> JsonProgress<W> {
+    pub fn new(out: W) -> Self {
+        JsonProgress {
+            out,
+            last_status: (0, 0, 0),
+        }
+    }
+
+    fn emit(&mut self, mut event: String) {
+        event.push('\n');
+        // A frontend that went away shouldn't stop the build.
+        let _ = self.out.write_all(event.as_bytes());
+    }
+}
+
+impl<W: Write> Progress for JsonProgress<W> {
+    fn update(&mut self, counts: &StateCounts) {
+        // Failed and skipped builds are finished too, as far as the total goes.
+        let done = counts.finished();
+        let running = counts.get(BuildState::Running);
+        let status = (counts.total(), done, running);
+        if status == self.last_status {
+            return;
+        }
+        self.last_status = status;
+        self.emit(format!(
+            "{{\"event\":\"status\",\"total\":{},\"done\":{},\"running\":{}}}",
+            status.0, status.1, status.2
+        ));
+    }
+
+    fn flush(&mut self) -> Option<Duration> {
+        let _ = self.out.flush();
+        None
+    }
+
+    fn task_state(&mut self, id: BuildId, build: &Build, state: BuildState) {
+        if state == BuildState::Running {
+            let mut event = format!("{{\"event\":\"started\",\"id\":{}", id.index());
+            event.push_str(",\"description\":");
+            write_json_str(&mut event, build_message(build));
+            event.push_str(",\"command\":");
+            write_json_str(&mut event, build.cmdline.as_deref().unwrap_or(""));
+            event.push('}');
+            self.emit(event);
+        }
+    }
+
+    fn completed(
+        &mut self,
+        id: BuildId,
+        _build: &Build,
+        success: bool,
+        exit_code: Option<i32>,
+        output: &[u8],
+    ) {
+        let mut event = format!(
+            "{{\"event\":\"finished\",\"id\":{},\"success\":{},\"exit_code\":",
+            id.index(),
+            success
+        );
+        match exit_code {
+            Some(code) => write!(event, "{}", code).unwrap(),
+            None => event.push_str("null"),
+        }
+        event.push_str(",\"output\":");
+        write_json_str(&mut event, &String::from_utf8_lossy(output));
+        event.push('}');
+        self.emit(event);
+    }
+
+    fn warning(&mut self, message: &str) {
+        let mut event = String::from("{\"event\":\"warning\",\"message\":");
+        write_json_str(&mut event, message);
+        event.push('}');
+        self.emit(event);
+    }
+
+    fn finish(&mut self) {
+        self.emit("{\"event\":\"done\"}".to_string());
+        let _ = self.out.flush();
+    }
+}
@@ -2,9 +2,12 @@
 
 use crate::canon::{canon_path, canon_path_in_place};
 use crate::densemap::{self, DenseMap};
+use crate::normalize::Normalizers;
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::hash::{self, Hasher};
-use std::time::SystemTime;
+use std::collections::HashSet;
+use std::hash::Hasher;
+use std::time::{Duration, SystemTime};
 
 /// Hash value used to identify a given instance of a Build's execution;
 /// compared to verify whether a Build is up to date.
@@ -50,25 +53,44 @@ pub struct File {
     pub dependents: Vec<BuildId>,
 }
 
+/// What to do about a build claiming an output that an earlier build already
+/// generates, like Ninja's `-w dupbuild`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DupBuild {
+    /// Fail to load the build file.
+    #[default]
+    Err,
+    /// Warn, and leave the output to the earlier build.
+    Warn,
+}
+
 /// A textual location within a build.ninja file, used in error messages.
 #[derive(Debug)]
 pub struct FileLoc {
     pub filename: std::rc::Rc<String>,
     pub line: usize,
 }
+impl FileLoc {
+    /// Format the location with a column on its line, for errors about a
+    /// particular token.
+    pub fn with_col(&self, col: usize) -> String {
+        format!("{}:{}", self, col)
+    }
+}
 impl std::fmt::Display for FileLoc {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         write!(f, "{}:{}", self.filename, self.line)
     }
 }
 
-#[derive(Debug, Clone, Hash)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct RspFile {
     pub path: std::path::PathBuf,
     pub content: String,
 }
 
 /// Input files to a Build.
+#[derive(PartialEq)]
 pub struct BuildIns {
     /// Internally we stuff explicit/implicit/order-only ins all into one Vec.
     /// This is mostly to simplify some of the iteration and is a little more
@@ -77,11 +99,11 @@ pub struct BuildIns {
     pub ids: Vec<FileId>,
     pub explicit: usize,
     pub implicit: usize,
-    // order_only count implied by other counts.
-    // pub order_only: usize,
+    pub order_only: usize,
 }
 
 /// Output files from a Build.
+#[derive(PartialEq)]
 pub struct BuildOuts {
     /// Similar to ins, we keep both explicit and implicit outs in one Vec.
     pub ids: Vec<FileId>,
@@ -93,6 +115,9 @@ pub struct Build {
     /// Source location this Build was declared.
     pub location: FileLoc,
 
+    /// Name of the rule used by this build.
+    pub rule: String,
+
     /// User-provided description of the build step.
     pub desc: Option<String>,
 
@@ -108,6 +133,29 @@ pub struct Build {
     /// Pool to execute this build in, if any.
     pub pool: Option<String>,
 
+    /// Number of slots of the pool this build occupies while running.
+    pub pool_weight: usize,
+
+    /// Environment variables to set for the command, from `env.NAME`
+    /// bindings, sorted by name.
+    pub env: Vec<(String, String)>,
+
+    /// Whether `env` is part of the build's hash, so that changing it
+    /// dirties the build.
+    pub hash_env: bool,
+
+    /// Whether the build is always dirty, along with everything depending on
+    /// it, like a Make `.PHONY` target.  Set by `always_dirty = 1`.
+    pub always_dirty: bool,
+
+    /// File whose content is part of the build's hash, from `salt_file`, so
+    /// that changing it dirties the build without it being an input.
+    pub salt_file: Option<String>,
+
+    /// Implicit outputs the command may not produce, chosen by extension with
+    /// `optional_outputs`.  The build is up to date without them.
+    pub optional_outs: Vec<FileId>,
+
     pub ins: BuildIns,
 
     /// Additional inputs discovered from a previous build.
@@ -115,19 +163,37 @@ pub struct Build {
 
     /// Output files.
     pub outs: BuildOuts,
+
+    /// Outputs dropped from `outs` by `DupBuild::Warn` because an earlier
+    /// build generates them.  The command still writes them.
+    pub dup_outs: Vec<FileId>,
 }
 impl Build {
     pub fn new(loc: FileLoc, ins: BuildIns, outs: BuildOuts) -> Self {
+        assert_eq!(
+            ins.ids.len(),
+            ins.explicit + ins.implicit + ins.order_only,
+            "{}: input counts don't match inputs",
+            loc
+        );
         Build {
             location: loc,
+            rule: String::new(),
             desc: None,
             cmdline: None,
             depfile: None,
             rspfile: None,
             pool: None,
+            pool_weight: 1,
+            env: Vec::new(),
+            hash_env: false,
+            always_dirty: false,
+            salt_file: None,
+            optional_outs: Vec::new(),
             ins,
             discovered_ins: Vec::new(),
             outs,
+            dup_outs: Vec::new(),
         }
     }
 
@@ -144,7 +210,8 @@ impl Build {
 
     /// Order-only inputs: inputs that are only used for ordering execution.
     pub fn order_only_ins(&self) -> &[FileId] {
-        &self.ins.ids[(self.ins.explicit + self.ins.implicit)..]
+        let start = self.ins.explicit + self.ins.implicit;
+        &self.ins.ids[start..(start + self.ins.order_only)]
     }
 
     /// Inputs that are needed before building.
@@ -156,10 +223,18 @@ impl Build {
     }
 
     /// Potentially update discovered_ins with a new set of deps, returning true if they changed.
+    /// Only the set of deps matters: tools may list the same deps in a
+    /// different order from run to run, which isn't a change.
     pub fn update_discovered(&mut self, mut deps: Vec<FileId>) -> bool {
         // Filter out any deps that were already listed in the build file.
-        deps.retain(|id| !self.ins.ids.contains(id));
-        if deps == self.discovered_ins {
+        // Order-only inputs don't dirty the build, so deps listed only as
+        // order-only are kept.  A build's own outputs, which a broken depfile
+        // may list, would make it depend on itself.
+        deps.retain(|id| !self.dirtying_ins().contains(id) && !self.outs().contains(id));
+        if deps == self.discovered_ins
+            || deps.iter().collect::<HashSet<_>>()
+                == self.discovered_ins.iter().collect::<HashSet<_>>()
+        {
             false
         } else {
             self.set_discovered_ins(deps);
@@ -181,11 +256,29 @@ impl Build {
         &self.outs.ids[0..self.outs.explicit]
     }
 
+    pub fn implicit_outs(&self) -> &[FileId] {
+        &self.outs.ids[self.outs.explicit..]
+    }
+
     /// Output paths that are updated when the build runs.
     pub fn outs(&self) -> &[FileId] {
         &self.outs.ids
     }
 
+    /// Whether two builds do exactly the same work: the same command, with
+    /// the same inputs, producing the same outputs.
+    pub fn same_work(&self, other: &Build) -> bool {
+        self.cmdline.is_some()
+            && self.cmdline == other.cmdline
+            && self.rspfile == other.rspfile
+            && self.salt_file == other.salt_file
+            && self.optional_outs == other.optional_outs
+            && self.env == other.env
+            && self.always_dirty == other.always_dirty
+            && self.ins == other.ins
+            && self.outs == other.outs
+    }
+
     pub fn debug_name(&self, graph: &Graph) -> String {
         format!(
             "{} ({}, ...)",
@@ -213,6 +306,14 @@ impl Graph {
         }
     }
 
+    /// Reserve space for at least the given number of additional files and
+    /// builds, to avoid repeated reallocation while loading.
+    pub fn reserve(&mut self, files: usize, builds: usize) {
+        self.files.reserve(files);
+        self.file_to_id.reserve(files);
+        self.builds.reserve(builds);
+    }
+
     /// Add a new file, generating a new FileId for it.
     fn add_file(&mut self, name: String) -> FileId {
         self.files.push(File {
@@ -227,6 +328,41 @@ impl Graph {
         self.files.get(id)
     }
 
+    /// Iterate over all files, in FileId order.
+    pub fn files(&self) -> impl Iterator<Item = (FileId, &File)> {
+        self.files.iter()
+    }
+
+    /// Iterate over all builds, in BuildId order.
+    pub fn builds(&self) -> impl Iterator<Item = (BuildId, &Build)> {
+        self.builds.iter()
+    }
+
+    /// Compute a summary of the graph's size.  Rules and pools are counted
+    /// by how many distinct ones are used by builds.
+    pub fn stats(&self) -> GraphStats {
+        let mut stats = GraphStats::default();
+        let mut rules = HashSet::new();
+        let mut pools = HashSet::new();
+        for (_, build) in self.builds() {
+            let ins = build.ordering_ins().len();
+            stats.builds += 1;
+            stats.edges += ins;
+            stats.max_fan_in = stats.max_fan_in.max(ins);
+            rules.insert(build.rule.as_str());
+            if let Some(pool) = &build.pool {
+                pools.insert(pool.as_str());
+            }
+        }
+        for (_, file) in self.files() {
+            stats.files += 1;
+            stats.max_fan_out = stats.max_fan_out.max(file.dependents.len());
+        }
+        stats.rules = rules.len();
+        stats.pools = pools.len();
+        stats
+    }
+
     /// Canonicalize a path and get/generate its FileId.
     pub fn file_id(&mut self, canon: &mut String) -> FileId {
         canon_path_in_place(canon);
@@ -248,24 +384,60 @@ impl Graph {
     }
 
     /// Add a new Build, generating a BuildId for it.
-    pub fn add_build(&mut self, build: Build) {
+    /// `dupbuild` determines what happens if the build claims an output that
+    /// an earlier build already generates.  `out_cols` gives the column of
+    /// each output in the build file, where known, for messages about them.
+    pub fn add_build(
+        &mut self,
+        mut build: Build,
+        dupbuild: DupBuild,
+        out_cols: &[usize],
+    ) -> anyhow::Result<()> {
+        let mut i = 0;
+        // The index of outs.ids[i] as declared, before any were removed.
+        let mut declared = 0;
+        while i < build.outs.ids.len() {
+            let f = self.files.get(build.outs.ids[i]);
+            declared += 1;
+            if f.input.is_none() {
+                i += 1;
+                continue;
+            }
+            let loc = match out_cols.get(declared - 1) {
+                Some(&col) => build.location.with_col(col),
+                None => build.location.to_string(),
+            };
+            match dupbuild {
+                DupBuild::Err => {
+                    anyhow::bail!("{}: multiple rules generate {}", loc, f.name)
+                }
+                DupBuild::Warn => {
+                    println!(
+                        "n2: warning: {}: multiple rules generate {}; \
+                         builds involving it will not be correct, continuing anyway",
+                        loc, f.name
+                    );
+                    // Keep the earlier build as the output's generator.
+                    build.dup_outs.push(build.outs.ids.remove(i));
+                    if i < build.outs.explicit {
+                        build.outs.explicit -= 1;
+                    }
+                }
+            }
+        }
+        if build.outs.ids.is_empty() {
+            return Ok(());
+        }
+
         let id = self.builds.next_id();
         for &inf in &build.ins.ids {
             self.files.get_mut(inf).dependents.push(id);
         }
         for &out in &build.outs.ids {
-            let f = self.files.get_mut(out);
-            match f.input {
-                Some(b) => {
-                    // TODO this occurs when two builds claim the same output
-                    // file, which is an ordinary user error and which should
-                    // be pretty-printed to the user as such.
-                    panic!("double link {:?}", b);
-                }
-                None => f.input = Some(id),
-            }
+            self.files.get_mut(out).input = Some(id);
         }
         self.builds.push(build);
+        Ok(())
     }
 
     /// Look up a Build by BuildId.
@@ -276,6 +448,139 @@ impl Graph {
     pub fn build_mut(&mut self, id: BuildId) -> &mut Build {
         self.builds.get_mut(id)
     }
+
+    /// Add `input` as an order-only input of build `id`, as if the build file
+    /// had listed it after `||`.
+    pub fn add_order_only_in(&mut self, id: BuildId, input: FileId) {
+        let build = self.builds.get_mut(id);
+        if build.ins.ids.contains(&input) {
+            return;
+        }
+        build.ins.ids.push(input);
+        build.ins.order_only += 1;
+        self.files.get_mut(input).dependents.push(id);
+    }
+
+    /// Find dependency cycles, reporting one cycle from each group of files
+    /// in cycles with each other.  Each cycle is listed from a file, through
+    /// the inputs of the builds generating each file, back to itself.
+    pub fn cycles(&self) -> Vec<Vec<FileId>> {
+        // Files visited, mapped to whether they're on the stack.
+        let mut visited: HashMap<FileId, bool> = HashMap::new();
+        let mut reported: HashSet<FileId> = HashSet::new();
+        let mut cycles = Vec::new();
+        for (root, _) in self.files() {
+            if visited.contains_key(&root) {
+                continue;
+            }
+            visited.insert(root, true);
+            // Files being visited, each with the index of its next input.
+            let mut stack = vec![(root, 0)];
+            while let Some((id, index)) = stack.last_mut() {
+                let id = *id;
+                let inputs = match self.file(id).input {
+                    Some(bid) => self.build(bid).ordering_ins(),
+                    None => &[],
+                };
+                let input = match inputs.get(*index) {
+                    Some(&input) => input,
+                    None => {
+                        visited.insert(id, false);
+                        stack.pop();
+                        continue;
+                    }
+                };
+                *index += 1;
+                match visited.get(&input) {
+                    None => {
+                        visited.insert(input, true);
+                        stack.push((input, 0));
+                    }
+                    Some(true) if !reported.contains(&input) => {
+                        let start = stack.iter().position(|&(id, _)| id == input).unwrap();
+                        let mut cycle: Vec<FileId> =
+                            stack[start..].iter().map(|&(id, _)| id).collect();
+                        cycle.push(input);
+                        reported.extend(self.cycle_component(input));
+                        cycles.push(cycle);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        cycles
+    }
+
+    /// Find the strongly-connected component containing `id` in the graph of
+    /// files and the inputs of the builds generating them: every file that
+    /// `id` depends on which in turn depends on `id`.  For a file in a cycle
+    /// this is every file in any cycle through it; otherwise it's just `id`.
+    /// Returned in FileId order.
+    pub fn cycle_component(&self, id: FileId) -> Vec<FileId> {
+        let inputs = |id: FileId| match self.file(id).input {
+            Some(bid) => self.build(bid).ordering_ins(),
+            None => &[],
+        };
+
+        // Files `id` depends on, with the reverse edges among them.
+        let mut reachable = HashSet::from([id]);
+        let mut users: HashMap<FileId, Vec<FileId>> = HashMap::new();
+        let mut stack = vec![id];
+        while let Some(file) = stack.pop() {
+            for &input in inputs(file) {
+                users.entry(input).or_default().push(file);
+                if reachable.insert(input) {
+                    stack.push(input);
+                }
+            }
+        }
+
+        // Of those, the ones that depend on `id`.
+        let mut component = HashSet::from([id]);
+        let mut stack = vec![id];
+        while let Some(file) = stack.pop() {
+            for &user in users.get(&file).map_or(&[][..], Vec::as_slice) {
+                if component.insert(user) {
+                    stack.push(user);
+                }
+            }
+        }
+        let mut component: Vec<FileId> = component.into_iter().collect();
+        component.sort_by_key(|id| id.0);
+        component
+    }
+}
+
+/// Summary of the size and shape of a graph, for --print-graph-stats.
+#[derive(Debug, Default, PartialEq)]
+pub struct GraphStats {
+    pub files: usize,
+    pub builds: usize,
+    /// Total number of inputs across all builds.
+    pub edges: usize,
+    /// Most inputs of any build.
+    pub max_fan_in: usize,
+    /// Most builds depending on any one file.
+    pub max_fan_out: usize,
+    pub rules: usize,
+    pub pools: usize,
+}
+
+impl std::fmt::Display for GraphStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, count) in [
+            ("files", self.files),
+            ("builds", self.builds),
+            ("edges", self.edges),
+            ("max fan-in", self.max_fan_in),
+            ("max fan-out", self.max_fan_out),
+            ("rules", self.rules),
+            ("pools", self.pools),
+        ] {
+            writeln!(f, "{:<12} {:>10}", name, count)?;
+        }
+        Ok(())
+    }
 }
 
 /// MTime info gathered for a file.  This also models "file is absent".
@@ -294,7 +599,11 @@ pub fn stat(path: &str) -> std::io::Result<MTime> {
     Ok(match std::fs::metadata(path) {
         Ok(meta) => MTime::Stamp(meta.modified().unwrap()),
         Err(err) => {
-            if err.kind() == std::io::ErrorKind::NotFound {
+            // A path through a file, e.g. "foo/bar" where "foo" is a file,
+            // doesn't exist either.
+            if err.kind() == std::io::ErrorKind::NotFound
+                || err.kind() == std::io::ErrorKind::NotADirectory
+            {
                 MTime::Missing
             } else {
                 return Err(err);
@@ -303,75 +612,335 @@ pub fn stat(path: &str) -> std::io::Result<MTime> {
     })
 }
 
+/// Round a time down to a multiple of `granularity` since the epoch.
+fn quantize(time: SystemTime, granularity: Duration) -> SystemTime {
+    if granularity.is_zero() {
+        return time;
+    }
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since) => {
+            let nanos = since.as_nanos();
+            let nanos = nanos - nanos % granularity.as_nanos();
+            SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos as u64)
+        }
+        // Times before the epoch aren't worth the trouble; leave them exact.
+        Err(_) => time,
+    }
+}
+
 /// Gathered state of on-disk files.
 /// Due to discovered deps this map may grow after graph initialization.
-pub struct FileState(DenseMap<FileId, Option<MTime>>);
+pub struct FileState {
+    mtimes: DenseMap<FileId, Option<MTime>>,
+    /// Whether we've already warned about a file with an mtime in the future.
+    warned_future_mtime: bool,
+    /// Warnings not yet reported.  They're collected rather than printed so
+    /// that the caller can show them without garbling a progress display.
+    warnings: Vec<String>,
+    /// Number of stat() calls made, for -d stats.
+    stat_count: usize,
+    /// Granularity mtimes are rounded down to, or zero for exact mtimes.
+    mtime_granularity: Duration,
+    /// How file content is normalized before digesting it.
+    normalizers: Normalizers,
+}
+
+/// How far in the future an mtime may be before we warn about clock skew.
+/// Allows for some imprecision between the filesystem's clock and ours.
+const FUTURE_MTIME_SLOP: Duration = Duration::from_secs(2);
 
 impl FileState {
     pub fn new(graph: &Graph) -> Self {
-        FileState(DenseMap::new_sized(graph.files.next_id(), None))
+        FileState {
+            mtimes: DenseMap::new_sized(graph.files.next_id(), None),
+            warned_future_mtime: false,
+            warnings: Vec::new(),
+            stat_count: 0,
+            mtime_granularity: Duration::ZERO,
+            normalizers: Normalizers::default(),
+        }
+    }
+
+    /// Normalize file content with `normalizers` before digesting it, e.g.
+    /// to ignore the timestamps embedded in archives.  By default content is
+    /// digested as is.
+    pub fn set_normalizers(&mut self, normalizers: Normalizers) {
+        self.normalizers = normalizers;
+    }
+
+    /// Compute a digest of a file's content, for --check-content.
+    pub fn digest_file(&self, path: &str) -> std::io::Result<u64> {
+        self.normalizers.digest_file(path)
+    }
+
+    /// Round mtimes down to a multiple of `granularity`, so that mtimes that
+    /// differ only by noise below it (e.g. after restoring files from a cache
+    /// or a filesystem that rounds timestamps) are considered equal.  Zero,
+    /// the default, compares mtimes exactly.
+    pub fn set_mtime_granularity(&mut self, granularity: Duration) {
+        self.mtime_granularity = granularity;
     }
 
     pub fn get(&self, id: FileId) -> Option<MTime> {
-        *self.0.lookup(id).unwrap_or(&None)
+        *self.mtimes.lookup(id).unwrap_or(&None)
     }
 
     pub fn restat(&mut self, id: FileId, path: &str) -> std::io::Result<MTime> {
-        let mtime = stat(path)?;
-        self.0.set_grow(id, Some(mtime), None);
+        let mtime = match stat(path)? {
+            MTime::Stamp(mtime) => MTime::Stamp(quantize(mtime, self.mtime_granularity)),
+            MTime::Missing => MTime::Missing,
+        };
+        self.stat_count += 1;
+        if !self.warned_future_mtime {
+            self.check_future_mtime(path, mtime);
+        }
+        self.mtimes.set_grow(id, Some(mtime), None);
         Ok(mtime)
     }
+
+    /// Override the known mtime of a file, e.g. to present an input whose
+    /// content is unchanged with the mtime it had when last built.
+    pub fn set(&mut self, id: FileId, mtime: MTime) {
+        self.mtimes.set_grow(id, Some(mtime), None);
+    }
+
+    /// Get the mtime of a file, stat()ing it only if it hasn't been already.
+    /// Missing files are cached too, so each file is stat()ed at most once.
+    pub fn get_or_stat(&mut self, id: FileId, path: &str) -> std::io::Result<MTime> {
+        match self.get(id) {
+            Some(mtime) => Ok(mtime),
+            None => self.restat(id, path),
+        }
+    }
+
+    pub fn stat_count(&self) -> usize {
+        self.stat_count
+    }
+
+    /// Take the warnings collected since the last call.
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Warn (once) if a file's mtime is in the future, which indicates clock
+    /// skew, e.g. with a network filesystem.  n2 doesn't compare mtimes, so
+    /// this doesn't affect what is rebuilt, but it's a useful hint when
+    /// incremental builds otherwise behave confusingly.
+    fn check_future_mtime(&mut self, path: &str, mtime: MTime) {
+        let mtime = match mtime {
+            MTime::Stamp(mtime) => mtime,
+            MTime::Missing => return,
+        };
+        if let Ok(ahead) = mtime.duration_since(SystemTime::now()) {
+            if ahead > FUTURE_MTIME_SLOP {
+                self.warnings.push(format!(
+                    "{} has modification time {:.1}s in the future; check for clock skew",
+                    path,
+                    ahead.as_secs_f64()
+                ));
+                self.warned_future_mtime = true;
+            }
+        }
+    }
 }
 
 const UNIT_SEPARATOR: u8 = 0x1F;
 
 // Add a list of files to a hasher; used by hash_build.
+// `loc` is the location of the build being hashed, for errors.  The files
+// should all have been stat()ed and be present, so errors mean a bug in n2.
 fn hash_files(
     hasher: &mut std::collections::hash_map::DefaultHasher,
     graph: &Graph,
     file_state: &mut FileState,
+    loc: &FileLoc,
     ids: &[FileId],
-) {
+) -> anyhow::Result<()> {
     for &id in ids {
         let name = &graph.file(id).name;
-        let mtime = file_state
-            .get(id)
-            .unwrap_or_else(|| panic!("no state for {:?}", name));
-        let mtime = match mtime {
-            MTime::Stamp(mtime) => mtime,
-            MTime::Missing => panic!("missing file: {:?}", name),
+        let mtime = match file_state.get(id) {
+            Some(mtime) => mtime,
+            None => anyhow::bail!("{}: hashing build: {} was never stat()ed", loc, name),
         };
-        hasher.write(graph.file(id).name.as_bytes());
-        std::hash::Hash::hash(&mtime, hasher);
+        hasher.write(name.as_bytes());
+        match mtime {
+            MTime::Stamp(mtime) => std::hash::Hash::hash(&mtime, hasher),
+            MTime::Missing => match graph.file(id).input.map(|bid| graph.build(bid)) {
+                // A phony output usually doesn't exist as a file; it stands in
+                // for the inputs of the phony build, so hash those instead.
+                Some(phony) if phony.cmdline.is_none() => {
+                    hash_phony_ins(hasher, graph, file_state, loc, phony.dirtying_ins())?
+                }
+                _ => anyhow::bail!("{}: hashing build: {} is missing", loc, name),
+            },
+        }
         hasher.write_u8(UNIT_SEPARATOR);
     }
+    Ok(())
+}
+
+// Like hash_files, but for the inputs of a phony build, which are allowed to
+// be missing.
+fn hash_phony_ins(
+    hasher: &mut std::collections::hash_map::DefaultHasher,
+    graph: &Graph,
+    file_state: &mut FileState,
+    loc: &FileLoc,
+    ids: &[FileId],
+) -> anyhow::Result<()> {
+    for &id in ids {
+        if file_state.get(id) == Some(MTime::Missing) && graph.file(id).input.is_none() {
+            hasher.write(graph.file(id).name.as_bytes());
+            hasher.write_u8(UNIT_SEPARATOR);
+        } else {
+            hash_files(hasher, graph, file_state, loc, &[id])?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether `c` can be part of a path component, for relativize_cmdline.
+fn is_path_char(c: char) -> bool {
+    c.is_alphanumeric() || "._-+~".contains(c)
+}
+
+/// Rewrite absolute paths under `root` in a command line to relative ones,
+/// so that the hash of a command naming files by absolute path is the same
+/// wherever the build directory is.  The transformation is textual: `root`
+/// (without trailing separators) followed by a path separator is dropped
+/// along with the separator, and `root` standing alone, i.e. followed by
+/// anything that can't continue a path component, becomes `.`.  An
+/// occurrence of `root` within a longer path, e.g. `/src` in `/src2` or
+/// `/x/src`, is left as is; one glued to a flag, as in `-I/src/inc`, is not.
+pub fn relativize_cmdline<'a>(cmdline: &'a str, root: &str) -> Cow<'a, str> {
+    let is_sep = |c: char| c == '/' || (cfg!(windows) && c == '\\');
+    let root = root.trim_end_matches(is_sep);
+    if root.is_empty() {
+        return Cow::Borrowed(cmdline);
+    }
+    let mut out = String::new();
+    let mut copied = 0;
+    for (start, _) in cmdline.match_indices(root) {
+        if start < copied {
+            continue;
+        }
+        // A flag like -I may be glued to the path, but a separator before
+        // it means the match is the tail of some other path.
+        let mut before = cmdline[..start]
+            .chars()
+            .rev()
+            .skip_while(|&c| is_path_char(c));
+        if matches!(before.next(), Some(c) if is_sep(c)) {
+            continue;
+        }
+        let end = start + root.len();
+        let replacement = match cmdline[end..].chars().next() {
+            Some(c) if is_sep(c) => ("", end + c.len_utf8()),
+            Some(c) if is_path_char(c) => continue,
+            _ => (".", end),
+        };
+        out.push_str(&cmdline[copied..start]);
+        out.push_str(replacement.0);
+        copied = replacement.1;
+    }
+    if copied == 0 {
+        return Cow::Borrowed(cmdline);
+    }
+    out.push_str(&cmdline[copied..]);
+    Cow::Owned(out)
 }
 
 // Hashes the inputs of a build to compute a signature.
 // Prerequisite: all referenced files have already been stat()ed and are present.
 // (It doesn't make sense to hash a build with missing files, because it's out
 // of date regardless of the state of the other files.)
+// A violated prerequisite is reported as an error rather than a panic.
+// If hash_outs is false, the outputs' mtimes are left out, so the build is
+// up to date as long as its inputs and command are unchanged and its outputs
+// exist, whatever their timestamps.
+// If root is given, absolute paths under it in the command are hashed as
+// relative ones; see relativize_cmdline.
 pub fn hash_build(
     graph: &Graph,
     file_state: &mut FileState,
     build: &Build,
-) -> std::io::Result<Hash> {
+    hash_outs: bool,
+    root: Option<&str>,
+) -> anyhow::Result<Hash> {
+    let loc = &build.location;
     let mut hasher = std::collections::hash_map::DefaultHasher::new();
-    hash_files(&mut hasher, graph, file_state, build.dirtying_ins());
-    hasher.write_u8(UNIT_SEPARATOR);
-    hash_files(&mut hasher, graph, file_state, build.discovered_ins());
+    hash_files(&mut hasher, graph, file_state, loc, build.dirtying_ins())?;
     hasher.write_u8(UNIT_SEPARATOR);
-    hasher.write(build.cmdline.as_ref().map(|c| c.as_bytes()).unwrap_or(b""));
+    hash_files(&mut hasher, graph, file_state, loc, build.discovered_ins())?;
     hasher.write_u8(UNIT_SEPARATOR);
-    hash::Hash::hash(&build.rspfile, &mut hasher);
-    hasher.write_u8(UNIT_SEPARATOR);
-    hash_files(&mut hasher, graph, file_state, build.outs());
+    hash_command(&mut hasher, build, root)?;
+    if hash_outs {
+        hasher.write_u8(UNIT_SEPARATOR);
+        for &id in build.outs() {
+            // An optional output that wasn't produced is hashed by name, so
+            // that it showing up later dirties the build.
+            if build.optional_outs.contains(&id) && file_state.get(id) == Some(MTime::Missing) {
+                hasher.write(graph.file(id).name.as_bytes());
+                hasher.write_u8(UNIT_SEPARATOR);
+            } else {
+                hash_files(&mut hasher, graph, file_state, loc, &[id])?;
+            }
+        }
+    }
     Ok(Hash(hasher.finish()))
 }
 
+/// Hash what a build runs, apart from the files it reads and writes: its
+/// command (see hash_build for `root`), its rspfile, and where they apply,
+/// its environment and salt file.  This is shared with cache keys, which
+/// must stay stable across Rust releases, so everything is written as bytes
+/// rather than through Hash impls.
+pub fn hash_command<H: Hasher>(
+    hasher: &mut H,
+    build: &Build,
+    root: Option<&str>,
+) -> anyhow::Result<()> {
+    let cmdline = build.cmdline.as_deref().unwrap_or("");
+    let cmdline = match root {
+        Some(root) => relativize_cmdline(cmdline, root),
+        None => Cow::Borrowed(cmdline),
+    };
+    hasher.write(cmdline.as_bytes());
+    // Only the content of an rspfile matters.  The file is rewritten on
+    // every execution, so where it's written doesn't matter beyond any
+    // reference to it in the command.
+    if let Some(rspfile) = &build.rspfile {
+        hasher.write_u8(UNIT_SEPARATOR);
+        hasher.write(rspfile.content.as_bytes());
+    }
+    if build.hash_env {
+        for (key, value) in &build.env {
+            hasher.write_u8(UNIT_SEPARATOR);
+            hasher.write(key.as_bytes());
+            hasher.write_u8(b'=');
+            hasher.write(value.as_bytes());
+        }
+    }
+    if let Some(path) = &build.salt_file {
+        hasher.write_u8(UNIT_SEPARATOR);
+        match std::fs::read(path) {
+            Ok(salt) => {
+                hasher.write_u64(salt.len() as u64);
+                hasher.write(&salt);
+            }
+            // A missing salt file is a state of its own; creating it dirties
+            // the build.
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => anyhow::bail!("{}: read salt_file {}: {}", build.location, path, err),
+        }
+    }
+    Ok(())
+}
+
 pub struct Hashes(HashMap<BuildId, Hash>);
 
 impl Hashes {
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Hashes {
         Hashes(HashMap::new())
     }
@@ -380,19 +949,344 @@ impl Hashes {
         self.0.insert(id, hash);
     }
 
-    pub fn changed(&self, id: BuildId, hash: Hash) -> bool {
-        let last_hash = match self.0.get(&id) {
-            None => return true,
-            Some(h) => *h,
-        };
-        hash != last_hash
+    /// Whether there is a recorded hash for a build, i.e. it was built before.
+    pub fn has(&self, id: BuildId) -> bool {
+        self.0.contains_key(&id)
+    }
+
+    pub fn changed(&self, id: BuildId, hash: Hash) -> HashChange {
+        match self.0.get(&id) {
+            None => HashChange::NeverBuilt,
+            Some(&last_hash) if last_hash != hash => HashChange::Changed,
+            Some(_) => HashChange::Unchanged,
+        }
     }
 }
 
+/// The result of comparing a build's hash against the last recorded one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashChange {
+    /// No hash was recorded; the build has never run.
+    NeverBuilt,
+    Changed,
+    Unchanged,
+}
+
 #[test]
-fn stat_mtime_resolution() {
-    use std::time::Duration;
+fn hash_rspfile_content() {
+    let graph = Graph::new();
+    let mut file_state = FileState::new(&graph);
+    let mut hash = |path: &str, content: &str| {
+        let mut build = Build::new(
+            FileLoc {
+                filename: std::rc::Rc::new("build.ninja".to_string()),
+                line: 1,
+            },
+            BuildIns {
+                ids: Vec::new(),
+                explicit: 0,
+                implicit: 0,
+                order_only: 0,
+            },
+            BuildOuts {
+                ids: Vec::new(),
+                explicit: 0,
+            },
+        );
+        build.cmdline = Some("cat rsp".to_string());
+        build.rspfile = Some(RspFile {
+            path: std::path::PathBuf::from(path),
+            content: content.to_string(),
+        });
+        hash_build(&graph, &mut file_state, &build, true, None).unwrap()
+    };
+    assert_ne!(hash("rsp", "a b"), hash("rsp", "a c"));
+    assert_eq!(hash("rsp", "a b"), hash("other/rsp", "a b"));
+}
+
+#[test]
+fn hash_unstatted_input_is_error() {
+    let mut graph = Graph::new();
+    let input = graph.file_id(&mut "in".to_string());
+    let mut file_state = FileState::new(&graph);
+    let mut build = Build::new(
+        FileLoc {
+            filename: std::rc::Rc::new("build.ninja".to_string()),
+            line: 3,
+        },
+        BuildIns {
+            ids: vec![input],
+            explicit: 1,
+            implicit: 0,
+            order_only: 0,
+        },
+        BuildOuts {
+            ids: Vec::new(),
+            explicit: 0,
+        },
+    );
+    build.cmdline = Some("cat in".to_string());
+    let err = hash_build(&graph, &mut file_state, &build, true, None).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "build.ninja:3: hashing build: in was never stat()ed"
+    );
+    file_state.set(input, MTime::Missing);
+    let err = hash_build(&graph, &mut file_state, &build, true, None).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "build.ninja:3: hashing build: in is missing"
+    );
+}
+
+#[test]
+fn relativize_cmdline_paths() {
+    let root = "/home/u/src/";
+    let rel = |cmdline| relativize_cmdline(cmdline, root);
+    assert_eq!(rel("cc -c /home/u/src/a.c"), "cc -c a.c");
+    assert_eq!(rel("cd /home/u/src && make"), "cd . && make");
+    assert_eq!(rel("cc -I/home/u/src/inc x.c"), "cc -Iinc x.c");
+    assert_eq!(rel("cp '/home/u/src' a"), "cp '.' a");
+    // Not the root, just paths that contain it.
+    assert_eq!(rel("cc /home/u/src2/a.c"), "cc /home/u/src2/a.c");
+    assert_eq!(rel("cc /x/home/u/src/a.c"), "cc /x/home/u/src/a.c");
+    assert!(matches!(rel("cc a.c"), Cow::Borrowed(_)));
+}
+
+#[test]
+fn iterate_in_id_order() {
+    let mut graph = Graph::new();
+    let b = graph.file_id(&mut "b".to_string());
+    let a = graph.file_id(&mut "a".to_string());
+    let files: Vec<(FileId, &str)> = graph
+        .files()
+        .map(|(id, file)| (id, file.name.as_str()))
+        .collect();
+    assert_eq!(files, vec![(b, "b"), (a, "a")]);
+    assert_eq!(graph.builds().count(), 0);
+}
+
+#[test]
+fn add_order_only_in() {
+    let mut graph = Graph::new();
+    let mut id = |name: &str| graph.file_id(&mut name.to_string());
+    let (input, gen, out) = (id("in"), id("gen.h"), id("out"));
+    let mut build = Build::new(
+        FileLoc {
+            filename: std::rc::Rc::new("build.ninja".to_string()),
+            line: 1,
+        },
+        BuildIns {
+            ids: vec![input],
+            explicit: 1,
+            implicit: 0,
+            order_only: 0,
+        },
+        BuildOuts {
+            ids: vec![out],
+            explicit: 1,
+        },
+    );
+    build.set_discovered_ins(vec![gen]);
+    graph.add_build(build, DupBuild::Err, &[]).unwrap();
+    let bid = graph.file(out).input.unwrap();
+    graph.add_order_only_in(bid, gen);
+    graph.add_order_only_in(bid, gen);
+    let build = graph.build(bid);
+    assert_eq!(build.order_only_ins(), [gen]);
+    assert_eq!(build.dirtying_ins(), [input]);
+    // Still discovered, so changes to it still dirty the build.
+    assert_eq!(build.discovered_ins(), [gen]);
+    assert_eq!(graph.file(gen).dependents, [bid]);
+}
+
+#[test]
+fn cycles() -> anyhow::Result<()> {
+    let graph = crate::load::parse(
+        "build.ninja",
+        "
+build a: phony b
+build b: phony c d
+build c: phony a
+build d: phony b
+build x: phony y
+build y: phony x
+build z: phony a x
+"
+        .as_bytes(),
+    )?;
+    let cycles: Vec<Vec<&str>> = graph
+        .cycles()
+        .iter()
+        .map(|cycle| {
+            cycle
+                .iter()
+                .map(|&id| graph.file(id).name.as_str())
+                .collect()
+        })
+        .collect();
+    assert_eq!(cycles, [vec!["a", "b", "c", "a"], vec!["x", "y", "x"]]);
+    Ok(())
+}
+
+#[test]
+fn cycle_component() -> anyhow::Result<()> {
+    let graph = crate::load::parse(
+        "build.ninja",
+        "
+rule r
+  command = x
+build a: r b
+build b: r c d
+build c: r a
+build d: r b e
+build e: r
+build f: r a
+"
+        .as_bytes(),
+    )?;
+    let names = |id: &str| -> Vec<String> {
+        let id = graph.lookup_file_id(id).unwrap();
+        let mut names: Vec<String> = graph
+            .cycle_component(id)
+            .into_iter()
+            .map(|id| graph.file(id).name.clone())
+            .collect();
+        names.sort();
+        names
+    };
+    assert_eq!(names("a"), ["a", "b", "c", "d"]);
+    assert_eq!(names("d"), ["a", "b", "c", "d"]);
+    assert_eq!(names("e"), ["e"]);
+    assert_eq!(names("f"), ["f"]);
+    Ok(())
+}
+
+#[test]
+fn discovered_deps_order() {
+    let mut graph = Graph::new();
+    let mut id = |name: &str| graph.file_id(&mut name.to_string());
+    let (input, a, b, c) = (id("in"), id("a.h"), id("b.h"), id("c.h"));
+    let mut build = Build::new(
+        FileLoc {
+            filename: std::rc::Rc::new("build.ninja".to_string()),
+            line: 1,
+        },
+        BuildIns {
+            ids: vec![input],
+            explicit: 1,
+            implicit: 0,
+            order_only: 0,
+        },
+        BuildOuts {
+            ids: vec![],
+            explicit: 0,
+        },
+    );
+    assert!(build.update_discovered(vec![input, a, b]));
+    assert_eq!(build.discovered_ins(), &[a, b]);
+    // The same deps in another order aren't a change, and keep their order.
+    assert!(!build.update_discovered(vec![b, a, input]));
+    assert_eq!(build.discovered_ins(), &[a, b]);
+    assert!(build.update_discovered(vec![b, c]));
+    assert_eq!(build.discovered_ins(), &[b, c]);
+}
+
+#[test]
+fn discovered_deps_skip_outputs() {
+    let mut graph = Graph::new();
+    let mut id = |name: &str| graph.file_id(&mut name.to_string());
+    let (input, out, a) = (id("in"), id("out"), id("a.h"));
+    let mut build = Build::new(
+        FileLoc {
+            filename: std::rc::Rc::new("build.ninja".to_string()),
+            line: 1,
+        },
+        BuildIns {
+            ids: vec![input],
+            explicit: 1,
+            implicit: 0,
+            order_only: 0,
+        },
+        BuildOuts {
+            ids: vec![out],
+            explicit: 1,
+        },
+    );
+    assert!(build.update_discovered(vec![out, input, a]));
+    assert_eq!(build.discovered_ins(), &[a]);
+}
 
+#[test]
+fn hashes_changed() {
+    let id = BuildId::from(0);
+    let mut hashes = Hashes::new();
+    assert_eq!(hashes.changed(id, Hash(1)), HashChange::NeverBuilt);
+    hashes.set(id, Hash(1));
+    assert_eq!(hashes.changed(id, Hash(1)), HashChange::Unchanged);
+    assert_eq!(hashes.changed(id, Hash(2)), HashChange::Changed);
+}
+
+#[test]
+fn quantize_mtime() {
+    let t = SystemTime::UNIX_EPOCH + Duration::new(1_000, 123_456_789);
+    assert_eq!(quantize(t, Duration::ZERO), t);
+    assert_eq!(
+        quantize(t, Duration::from_micros(1)),
+        SystemTime::UNIX_EPOCH + Duration::new(1_000, 123_456_000)
+    );
+    assert_eq!(
+        quantize(t, Duration::from_millis(1)),
+        SystemTime::UNIX_EPOCH + Duration::new(1_000, 123_000_000)
+    );
+    assert_eq!(
+        quantize(t, Duration::from_secs(2)),
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_000)
+    );
+    // Noise below the granularity compares equal.
+    let noisy = t + Duration::from_nanos(100);
+    assert_ne!(quantize(noisy, Duration::ZERO), t);
+    assert_eq!(
+        quantize(noisy, Duration::from_micros(1)),
+        quantize(t, Duration::from_micros(1))
+    );
+}
+
+#[test]
+fn graph_stats() -> anyhow::Result<()> {
+    let graph = crate::load::parse(
+        "build.ninja",
+        b"
+pool link_pool
+  depth = 1
+rule cc
+  command = cc $in -o $out
+rule link
+  command = link $in -o $out
+  pool = link_pool
+build a.o: cc a.c | common.h
+build b.o: cc b.c | common.h
+build app: link a.o b.o
+build all: phony app
+",
+    )?;
+    assert_eq!(
+        graph.stats(),
+        GraphStats {
+            files: 7,
+            builds: 4,
+            edges: 7,
+            max_fan_in: 2,
+            max_fan_out: 2,
+            rules: 3,
+            pools: 1,
+        }
+    );
+    Ok(())
+}
+
+#[test]
+fn stat_mtime_resolution() {
     let temp_dir = tempfile::tempdir().unwrap();
     let filename = temp_dir.path().join("dummy");
     let filename = filename.to_str().unwrap();
@@ -1,10 +1,18 @@
+pub mod browse;
+pub mod cache;
 pub mod canon;
+pub mod compdb;
 mod db;
 mod densemap;
 mod depfile;
 mod eval;
-mod graph;
+pub mod format;
+pub mod frontend;
+pub mod graph;
 pub mod load;
+#[cfg(unix)]
+mod mmap;
+pub mod normalize;
 pub mod parse;
 pub mod progress;
 mod scanner;
@@ -2,10 +2,15 @@
 
 use crate::graph::{FileId, RspFile};
 use crate::parse::Statement;
-use crate::{db, eval, graph, parse, trace};
+use crate::{canon, db, eval, graph, parse, trace};
 use anyhow::{anyhow, bail};
 use std::borrow::Cow;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+
+#[cfg(unix)]
+use crate::mmap;
 
 /// A variable lookup environment for magic $in/$out variables.
 struct BuildImplicitVars<'a> {
@@ -36,12 +41,33 @@ impl<'a> eval::Env for BuildImplicitVars<'a> {
     }
 }
 
+/// Prefix of build variables that set environment variables for the command.
+const ENV_PREFIX: &str = "env.";
+
+/// Estimated manifest bytes per distinct file, used to presize the graph.
+const BYTES_PER_FILE: usize = 100;
+/// Estimated manifest bytes per build statement, used to presize the graph.
+const BYTES_PER_BUILD: usize = 200;
+
 /// Internal state used while loading.
 struct Loader {
+    options: Options,
     graph: graph::Graph,
     default: Vec<FileId>,
     rules: HashMap<String, eval::LazyVars>,
+    /// For each subninja being merged, the rules it defined along with the
+    /// definitions they shadowed, so they can be restored at its end.
+    rule_scopes: Vec<Vec<(String, Option<eval::LazyVars>)>>,
     pools: Vec<(String, usize)>,
+    /// If set, errors in individual builds are collected here rather than
+    /// stopping the load, for --manifest-check.
+    errors: Option<Vec<String>>,
+    /// Parses subninjas in the background, started on the first one.
+    subninjas: Option<SubninjaPool>,
+    /// Paths of the build files loaded so far.
+    manifests: Vec<String>,
+    /// Set by a `# n2: hash = content` directive.
+    check_content: bool,
 }
 
 impl parse::Loader for Loader {
@@ -52,12 +78,18 @@ impl parse::Loader for Loader {
 }
 
 impl Loader {
-    fn new() -> Self {
+    fn new(options: Options) -> Self {
         let mut loader = Loader {
+            options,
             graph: graph::Graph::new(),
             default: Vec::new(),
             rules: HashMap::new(),
+            rule_scopes: Vec::new(),
             pools: Vec::new(),
+            errors: None,
+            subninjas: None,
+            manifests: Vec::new(),
+            check_content: false,
         };
 
         loader
@@ -67,17 +99,22 @@ impl Loader {
         loader
     }
 
-    fn add_build<'a>(
+    fn add_build(
         &mut self,
         filename: std::rc::Rc<String>,
-        env: &eval::Vars<'a>,
-        b: parse::Build<FileId>,
+        env: &dyn eval::Env,
+        mut b: parse::Build<FileId>,
     ) -> anyhow::Result<()> {
+        if b.rule == "phony" {
+            // Like Ninja, ignore a phony build naming itself as an input,
+            // which older CMake versions generate.
+            drop_self_inputs(&mut b);
+        }
         let ins = graph::BuildIns {
             ids: b.ins,
             explicit: b.explicit_ins,
             implicit: b.implicit_ins,
-            // order_only is unused
+            order_only: b.order_only_ins,
         };
         let outs = graph::BuildOuts {
             ids: b.outs,
@@ -94,7 +131,11 @@ impl Loader {
 
         let rule = match self.rules.get(b.rule) {
             Some(r) => r,
-            None => bail!("unknown rule {:?}", b.rule),
+            None => bail!(
+                "{}: unknown rule {:?}",
+                build.location.with_col(b.rule_col),
+                b.rule
+            ),
         };
 
         let implicit_vars = BuildImplicitVars {
@@ -102,19 +143,83 @@ impl Loader {
             build: &build,
         };
         let build_vars = &b.vars;
-        let envs: [&dyn eval::Env; 4] = [&implicit_vars, build_vars, rule, env];
+        let envs: [&dyn eval::Env; 5] = [&implicit_vars, build_vars, rule, env, &eval::OsEnv];
+        // The process environment is consulted last, and only if requested.
+        let envs = if self.options.os_env {
+            &envs[..]
+        } else {
+            &envs[..4]
+        };
 
         let lookup = |key: &str| {
             build_vars
                 .get(key)
                 .or_else(|| rule.get(key))
-                .map(|var| var.evaluate(&envs))
+                .map(|var| var.evaluate(envs))
         };
 
         let cmdline = lookup("command");
         let desc = lookup("description");
-        let depfile = lookup("depfile");
+        // Like inputs and outputs, the depfile path is relative to the build
+        // root, whichever manifest declared it; canonicalize it the same way
+        // so it names the file the command wrote however it was spelled.
+        let depfile = lookup("depfile").map(canon::canon_path);
         let pool = lookup("pool");
+        let pool_weight = match lookup("pool_weight") {
+            None => 1,
+            Some(weight) => match weight.parse::<usize>() {
+                // A weight of 0 would let the build run without counting
+                // against its pool at all.
+                Ok(weight) if weight > 0 => weight,
+                _ => bail!("{}: bad pool_weight {:?}", build.location, weight),
+            },
+        };
+
+        let always_dirty = match lookup("always_dirty") {
+            None => false,
+            Some(value) => parse_flag(
+                &build.location.filename,
+                build.location.line,
+                "always_dirty",
+                &value,
+            )?,
+        };
+
+        let salt_file = lookup("salt_file");
+
+        // Implicit outputs with these extensions may be left unproduced.
+        let optional_outs = match lookup("optional_outputs") {
+            None => Vec::new(),
+            Some(exts) => {
+                let exts: Vec<&str> = exts
+                    .split_whitespace()
+                    .map(|ext| ext.trim_start_matches('.'))
+                    .collect();
+                build
+                    .implicit_outs()
+                    .iter()
+                    .copied()
+                    .filter(|&id| {
+                        let name = &self.graph.file(id).name;
+                        let ext = std::path::Path::new(name).extension();
+                        matches!(ext, Some(ext) if exts.iter().any(|e| ext == *e))
+                    })
+                    .collect()
+            }
+        };
+
+        // Variables named "env.NAME" set NAME in the command's environment.
+        let env_keys: std::collections::BTreeSet<&str> = rule
+            .keyvals()
+            .iter()
+            .chain(build_vars.keyvals())
+            .map(|(key, _)| key.as_str())
+            .filter(|key| key.starts_with(ENV_PREFIX))
+            .collect();
+        let env = env_keys
+            .into_iter()
+            .map(|key| (key[ENV_PREFIX.len()..].to_owned(), lookup(key).unwrap()))
+            .collect();
 
         let rspfile_path = lookup("rspfile");
         let rspfile_content = lookup("rspfile_content");
@@ -124,32 +229,138 @@ impl Loader {
                 path: std::path::PathBuf::from(path),
                 content,
             }),
-            _ => bail!("rspfile and rspfile_content need to be both specified"),
+            _ => bail!(
+                "{}: rspfile and rspfile_content need to be both specified",
+                build.location
+            ),
         };
 
+        // A build that reads its own output, like an in-place transform, could
+        // never be up to date; Ninja reports it as a dependency cycle.
+        if let Some(&id) = build.outs().iter().find(|id| build.ins.ids.contains(id)) {
+            bail!(
+                "{}: {} is both an input and an output of the same build",
+                build.location,
+                self.graph.file(id).name
+            );
+        }
+
+        build.rule = b.rule.to_owned();
         build.cmdline = cmdline;
         build.desc = desc;
         build.depfile = depfile;
         build.rspfile = rspfile;
         build.pool = pool;
+        build.pool_weight = pool_weight;
+        build.env = env;
+        build.hash_env = self.options.hash_env;
+        build.always_dirty = always_dirty;
+        build.salt_file = salt_file;
+        build.optional_outs = optional_outs;
 
-        self.graph.add_build(build);
-        Ok(())
+        if self.options.dedup_builds {
+            let first = build
+                .outs()
+                .first()
+                .and_then(|&id| self.graph.file(id).input)
+                .map(|bid| self.graph.build(bid));
+            if let Some(first) = first.filter(|first| first.same_work(&build)) {
+                println!(
+                    "n2: warning: {}: duplicates the build at {}; running it once",
+                    build.location, first.location
+                );
+                return Ok(());
+            }
+        }
+
+        self.graph
+            .add_build(build, self.options.dupbuild, &b.out_cols)
     }
 
     fn read_file(&mut self, id: FileId) -> anyhow::Result<()> {
-        let path = self.graph.file(id).name.clone();
-        let bytes = match trace::scope("fs::read", || std::fs::read(&path)) {
+        let path = find_manifest(&self.graph.file(id).name, &self.options.include_dirs)?;
+        let bytes = match trace::scope("fs::read", || read_manifest(&path, self.options.mmap)) {
             Ok(b) => b,
             Err(e) => bail!("read {}: {}", path, e),
         };
-        self.parse(path, bytes)
+        self.parse_transcoded(path, &bytes)
     }
 
-    fn parse(&mut self, path: String, mut bytes: Vec<u8>) -> anyhow::Result<()> {
-        let filename = std::rc::Rc::new(path);
+    /// Read the top-level build file: build.ninja, the file given by
+    /// Options::manifest, or stdin if that is "-".
+    fn read_top_file(&mut self) -> anyhow::Result<()> {
+        match self.options.manifest.as_deref() {
+            Some("-") => {
+                let bytes = read_stdin()?;
+                self.parse_transcoded(STDIN_NAME.to_string(), &bytes)?;
+            }
+            manifest => {
+                let mut name = manifest.unwrap_or(DEFAULT_MANIFEST).to_string();
+                let id = self.graph.file_id(&mut name);
+                self.read_file(id)?;
+            }
+        }
+        self.check_optional_outs()
+    }
 
-        let mut parser = parse::Parser::new(&mut bytes);
+    /// Reject builds that depend on an optional output: it may never be
+    /// produced, and a build can't run without its inputs.
+    fn check_optional_outs(&mut self) -> anyhow::Result<()> {
+        let mut errors = Vec::new();
+        for (_, build) in self.graph.builds() {
+            for &id in &build.optional_outs {
+                let file = self.graph.file(id);
+                for &dependent in &file.dependents {
+                    let dependent = self.graph.build(dependent);
+                    if dependent.dirtying_ins().contains(&id) {
+                        errors.push(anyhow!(
+                            "{}: input {} is an optional output of the build at {}",
+                            dependent.location,
+                            file.name,
+                            build.location
+                        ));
+                    }
+                }
+            }
+        }
+        for err in errors {
+            self.collect_error(Err(err))?;
+        }
+        Ok(())
+    }
+
+    fn parse_transcoded(&mut self, path: String, bytes: &[u8]) -> anyhow::Result<()> {
+        match transcode_utf16(&path, bytes)? {
+            Some(utf8) => self.parse(path, &utf8),
+            None => self.parse(path, bytes),
+        }
+    }
+
+    fn parse(&mut self, path: String, bytes: &[u8]) -> anyhow::Result<()> {
+        // Size the graph up front based on the manifest size, rather than
+        // growing it one push at a time.  These are rough guesses at the
+        // number of bytes per entity in a typical generated build file; being
+        // off only costs some memory or an extra reallocation.
+        self.graph
+            .reserve(bytes.len() / BYTES_PER_FILE, bytes.len() / BYTES_PER_BUILD);
+
+        // A file with subninjas is parsed up front like the subninjas are, so
+        // that they're all queued to be parsed in the background while we
+        // merge what comes before them.  Other files are parsed straight into
+        // the graph, which is faster.
+        if mentions_subninja(bytes) {
+            let spawner = self.spawner().clone();
+            let file = parse_detached_bytes(path, bytes, &spawner)?;
+            return self.merge(file);
+        }
+
+        if path != STDIN_NAME {
+            self.manifests.push(path.clone());
+        }
+        let filename = std::rc::Rc::new(path);
+        let overrides = self.options.overrides.clone();
+        let mut parser = parse::Parser::new(bytes);
+        parser.set_overrides(&overrides);
         loop {
             let stmt = match parser
                 .read(self)
@@ -159,23 +370,497 @@ impl Loader {
                 Some(s) => s,
             };
             match stmt {
-                Statement::Include(id) => trace::scope("include", || self.read_file(id))?,
-                // TODO: implement scoping for subninja
-                Statement::Subninja(id) => trace::scope("subninja", || self.read_file(id))?,
+                Statement::Include(id) => {
+                    trace::scope("include", || self.scope_pragmas(|l| l.read_file(id)))?
+                }
+                Statement::Subninja(id) => {
+                    let path = self.graph.file(id).name.clone();
+                    let file = self.spawner().spawn(path).wait()?;
+                    trace::scope("subninja", || self.merge_subninja(file))?;
+                }
                 Statement::Default(defaults) => {
                     self.default.extend(defaults);
                 }
-                Statement::Rule(rule) => {
-                    self.rules.insert(rule.name.to_owned(), rule.vars);
+                Statement::Rule(rule) => self.add_rule(rule.name.to_owned(), rule.vars),
+                Statement::Build(build) => {
+                    let result = self.add_build(filename.clone(), &parser.vars, build);
+                    self.collect_error(result)?
                 }
-                Statement::Build(build) => self.add_build(filename.clone(), &parser.vars, build)?,
                 Statement::Pool(pool) => {
                     self.pools.push((pool.name.to_string(), pool.depth));
                 }
+                Statement::Pragma(pragma) => {
+                    self.pragma(&filename, pragma.line, pragma.key, pragma.value)?
+                }
+            };
+        }
+        Ok(())
+    }
+
+    fn spawner(&mut self) -> &Spawner {
+        let options = &self.options;
+        &self
+            .subninjas
+            .get_or_insert_with(|| SubninjaPool::new(options.clone()))
+            .spawner
+    }
+
+    /// When collecting errors, record a failure to add a build and carry on
+    /// without it.
+    fn collect_error(&mut self, result: anyhow::Result<()>) -> anyhow::Result<()> {
+        match (result, &mut self.errors) {
+            (Err(err), Some(errors)) => {
+                errors.push(err.to_string());
+                Ok(())
+            }
+            (result, _) => result,
+        }
+    }
+
+    fn add_rule(&mut self, name: String, vars: eval::LazyVars) {
+        let prev = self.rules.insert(name.clone(), vars);
+        if let Some(scope) = self.rule_scopes.last_mut() {
+            scope.push((name, prev));
+        }
+    }
+
+    /// Merge a subninja, whose rules are only visible within it and the
+    /// files it includes or nests.
+    fn merge_subninja(&mut self, file: DetachedFile) -> anyhow::Result<()> {
+        self.rule_scopes.push(Vec::new());
+        let result = self.scope_pragmas(|l| l.merge(file));
+        for (name, prev) in self.rule_scopes.pop().unwrap().into_iter().rev() {
+            match prev {
+                Some(vars) => self.rules.insert(name, vars),
+                None => self.rules.remove(&name),
             };
         }
+        result
+    }
+
+    /// Add the statements of a file parsed by parse_detached() to the graph.
+    fn merge(&mut self, file: DetachedFile) -> anyhow::Result<()> {
+        if file.filename != STDIN_NAME {
+            self.manifests.push(file.filename.clone());
+        }
+        let filename = std::rc::Rc::new(file.filename);
+        for stmt in file.statements {
+            match stmt {
+                DetachedStatement::Rule(name, vars) => self.add_rule(name, vars),
+                DetachedStatement::Build(b, env) => {
+                    let build = parse::Build {
+                        rule: &b.rule,
+                        line: b.line,
+                        rule_col: b.rule_col,
+                        outs: self.file_ids(b.outs),
+                        out_cols: b.out_cols,
+                        explicit_outs: b.explicit_outs,
+                        ins: self.file_ids(b.ins),
+                        explicit_ins: b.explicit_ins,
+                        implicit_ins: b.implicit_ins,
+                        order_only_ins: b.order_only_ins,
+                        vars: b.vars,
+                    };
+                    let result = self.add_build(filename.clone(), &*env, build);
+                    self.collect_error(result)?;
+                }
+                DetachedStatement::Default(paths) => {
+                    let ids = self.file_ids(paths);
+                    self.default.extend(ids);
+                }
+                DetachedStatement::Pool(name, depth) => {
+                    self.pools.push((name, depth));
+                }
+                DetachedStatement::Pragma(line, key, value) => {
+                    self.pragma(&filename, line, &key, &value)?
+                }
+                DetachedStatement::Include(file) => self.scope_pragmas(|l| l.merge(file))?,
+                DetachedStatement::Subninja(pending) => {
+                    let file = pending.wait()?;
+                    trace::scope("subninja", || self.merge_subninja(file))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Load a nested build file, undoing the effect of its directives on the
+    /// options at its end, so that they apply only within it and the files
+    /// it includes or nests in turn.
+    fn scope_pragmas(
+        &mut self,
+        load: impl FnOnce(&mut Self) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let (os_env, hash_env) = (self.options.os_env, self.options.hash_env);
+        let result = load(self);
+        self.options.os_env = os_env;
+        self.options.hash_env = hash_env;
+        result
+    }
+
+    /// Apply a `# n2: key = value` directive.  Most affect the loading of
+    /// everything after it in the same file, and the files that includes
+    /// or nests; see scope_pragmas().  `hash` and `default_pool_depth`
+    /// apply to the whole build, wherever they appear.
+    fn pragma(
+        &mut self,
+        filename: &str,
+        line: usize,
+        key: &str,
+        value: &str,
+    ) -> anyhow::Result<()> {
+        match key {
+            "env_vars" => self.options.os_env = parse_flag(filename, line, key, value)?,
+            "hash_env" => self.options.hash_env = parse_flag(filename, line, key, value)?,
+            "hash" => match value {
+                "content" => self.check_content = true,
+                "mtime" => {}
+                _ => bail!("{}:{}: bad value for {}: {:?}", filename, line, key, value),
+            },
+            "default_pool_depth" => match value.parse::<usize>() {
+                // The default pool is the one with the empty name.
+                Ok(depth) => self.pools.push((String::new(), depth)),
+                Err(_) => bail!("{}:{}: bad value for {}: {:?}", filename, line, key, value),
+            },
+            _ => println!(
+                "n2: warning: {}:{}: ignoring unknown directive {:?}",
+                filename, line, key
+            ),
+        }
         Ok(())
     }
+
+    fn file_ids(&mut self, paths: Vec<String>) -> Vec<FileId> {
+        paths
+            .into_iter()
+            .map(|mut path| self.graph.file_id(&mut path))
+            .collect()
+    }
+}
+
+/// Parse the value of a boolean pragma.
+fn parse_flag(filename: &str, line: usize, key: &str, value: &str) -> anyhow::Result<bool> {
+    match value {
+        "1" | "true" => Ok(true),
+        "0" | "false" => Ok(false),
+        _ => bail!("{}:{}: bad value for {}: {:?}", filename, line, key, value),
+    }
+}
+
+/// A snapshot of the file-level variables in scope at a build statement.
+struct FileVars(HashMap<String, String>);
+impl eval::Env for FileVars {
+    fn get_var(&self, var: &str) -> Option<Cow<'_, str>> {
+        self.0.get(var).map(|val| Cow::Borrowed(val.as_str()))
+    }
+}
+impl FileVars {
+    fn matches(&self, vars: &eval::Vars) -> bool {
+        let vars = vars.keyvals();
+        self.0.len() == vars.len() && vars.iter().all(|(&key, val)| self.0.get(key) == Some(val))
+    }
+}
+
+/// A build statement parsed off the main thread.  It owns its text, as the
+/// parser's input doesn't outlive the parse.
+struct DetachedBuild {
+    rule: String,
+    line: usize,
+    rule_col: usize,
+    outs: Vec<String>,
+    out_cols: Vec<usize>,
+    explicit_outs: usize,
+    ins: Vec<String>,
+    explicit_ins: usize,
+    implicit_ins: usize,
+    order_only_ins: usize,
+    vars: eval::LazyVars,
+}
+
+/// A statement parsed off the main thread, by parse_detached().
+enum DetachedStatement {
+    Rule(String, eval::LazyVars),
+    Build(DetachedBuild, Arc<FileVars>),
+    Default(Vec<String>),
+    Pool(String, usize),
+    /// A pragma, as (line, key, value).
+    Pragma(usize, String, String),
+    /// An included file, in place of the statement that referenced it.
+    Include(DetachedFile),
+    /// A nested subninja file, in place of the statement that referenced it.
+    Subninja(PendingFile),
+}
+
+/// A file parsed off the main thread.  Paths are canonicalized but not yet
+/// converted to FileIds, as that requires access to the Graph.
+struct DetachedFile {
+    filename: String,
+    statements: Vec<DetachedStatement>,
+}
+
+/// A parse::Loader that keeps paths as strings, for use off the main thread.
+struct PathLoader {}
+impl parse::Loader for PathLoader {
+    type Path = String;
+    fn path(&mut self, path: &mut String) -> Self::Path {
+        canon::canon_path_in_place(path);
+        path.clone()
+    }
+}
+
+/// A subninja queued on the SubninjaPool.
+struct PendingFile {
+    path: String,
+    result: mpsc::Receiver<anyhow::Result<DetachedFile>>,
+}
+
+impl PendingFile {
+    /// Wait for the file to be parsed.
+    fn wait(self) -> anyhow::Result<DetachedFile> {
+        match self.result.recv() {
+            Ok(result) => result,
+            // The worker parsing it panicked, dropping the sender.
+            Err(_) => bail!("read {}: parser thread died", self.path),
+        }
+    }
+}
+
+/// A file for a SubninjaPool worker to parse, or None to stop the worker.
+type Job = Option<(String, mpsc::Sender<anyhow::Result<DetachedFile>>)>;
+
+/// What parse_detached() needs to parse a file and queue its subninjas.
+#[derive(Clone)]
+struct Spawner {
+    options: Arc<Options>,
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Spawner {
+    /// Queue a subninja to be parsed before the main thread reaches it.
+    fn spawn(&self, path: String) -> PendingFile {
+        let (tx, rx) = mpsc::channel();
+        // Sending only fails once the workers are gone, in which case the
+        // dropped sender fails wait().
+        let _ = self.jobs.send(Some((path.clone(), tx)));
+        PendingFile { path, result: rx }
+    }
+}
+
+/// Worker threads, one per CPU, parsing subninjas in the order they're
+/// found.  Each parsed file is merged when the main thread reaches the
+/// statement that referenced it, so the graph matches a serial load.
+struct SubninjaPool {
+    spawner: Spawner,
+    workers: Vec<std::thread::JoinHandle<()>>,
+    /// Set when the pool is dropped, so that files still queued after a
+    /// failed load are skipped rather than parsed.
+    cancelled: Arc<AtomicBool>,
+}
+
+impl SubninjaPool {
+    fn new(options: Options) -> Self {
+        let (tx, rx) = mpsc::channel::<Job>();
+        let jobs = Arc::new(Mutex::new(rx));
+        let spawner = Spawner {
+            options: Arc::new(options),
+            jobs: tx,
+        };
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let threads = std::thread::available_parallelism()
+            .map(usize::from)
+            .unwrap_or(1);
+        let workers = (0..threads)
+            .map(|_| {
+                let jobs = jobs.clone();
+                let spawner = spawner.clone();
+                let cancelled = cancelled.clone();
+                std::thread::spawn(move || loop {
+                    let job = jobs.lock().unwrap().recv();
+                    let (path, result) = match job {
+                        Ok(Some(job)) => job,
+                        _ => break,
+                    };
+                    if !cancelled.load(Ordering::Relaxed) {
+                        let _ = result.send(parse_detached(path, &spawner));
+                    }
+                })
+            })
+            .collect();
+        SubninjaPool {
+            spawner,
+            workers,
+            cancelled,
+        }
+    }
+}
+
+impl Drop for SubninjaPool {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        for _ in &self.workers {
+            let _ = self.spawner.jobs.send(None);
+        }
+        for worker in self.workers.drain(..) {
+            // A worker that panicked already failed the load.
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Whether a file may contain subninja statements.
+fn mentions_subninja(bytes: &[u8]) -> bool {
+    const KEYWORD: &[u8] = b"subninja";
+    bytes.windows(KEYWORD.len()).any(|window| window == KEYWORD)
+}
+
+/// Read and parse a file without access to the Graph, so that it can run on
+/// a worker thread.
+fn parse_detached(path: String, spawner: &Spawner) -> anyhow::Result<DetachedFile> {
+    let options = &spawner.options;
+    let path = find_manifest(&path, &options.include_dirs)?;
+    let bytes = match read_manifest(&path, options.mmap) {
+        Ok(b) => b,
+        Err(e) => bail!("read {}: {}", path, e),
+    };
+    let utf8 = transcode_utf16(&path, &bytes)?;
+    parse_detached_bytes(path, utf8.as_deref().unwrap_or(&bytes), spawner)
+}
+
+/// Parse a file's content without access to the Graph.  Included files are
+/// parsed inline, while subninjas are queued on `spawner`'s pool.
+fn parse_detached_bytes(
+    path: String,
+    bytes: &[u8],
+    spawner: &Spawner,
+) -> anyhow::Result<DetachedFile> {
+    let mut statements = Vec::new();
+    let mut parser = parse::Parser::new(bytes);
+    parser.set_overrides(&spawner.options.overrides);
+    let mut env = Arc::new(FileVars(HashMap::new()));
+    loop {
+        let stmt = match parser
+            .read(&mut PathLoader {})
+            .map_err(|err| anyhow!(parser.format_parse_error(&path, err)))?
+        {
+            None => break,
+            Some(s) => s,
+        };
+        statements.push(match stmt {
+            Statement::Include(path) => DetachedStatement::Include(parse_detached(path, spawner)?),
+            Statement::Subninja(path) => DetachedStatement::Subninja(spawner.spawn(path)),
+            Statement::Default(paths) => DetachedStatement::Default(paths),
+            Statement::Rule(rule) => DetachedStatement::Rule(rule.name.to_owned(), rule.vars),
+            Statement::Build(b) => {
+                if !env.matches(&parser.vars) {
+                    env = Arc::new(FileVars(
+                        parser
+                            .vars
+                            .keyvals()
+                            .iter()
+                            .map(|(&key, val)| (key.to_owned(), val.clone()))
+                            .collect(),
+                    ));
+                }
+                let build = DetachedBuild {
+                    rule: b.rule.to_owned(),
+                    line: b.line,
+                    rule_col: b.rule_col,
+                    outs: b.outs,
+                    out_cols: b.out_cols,
+                    explicit_outs: b.explicit_outs,
+                    ins: b.ins,
+                    explicit_ins: b.explicit_ins,
+                    implicit_ins: b.implicit_ins,
+                    order_only_ins: b.order_only_ins,
+                    vars: b.vars,
+                };
+                DetachedStatement::Build(build, env.clone())
+            }
+            Statement::Pool(pool) => DetachedStatement::Pool(pool.name.to_owned(), pool.depth),
+            Statement::Pragma(pragma) => DetachedStatement::Pragma(
+                pragma.line,
+                pragma.key.to_owned(),
+                pragma.value.to_owned(),
+            ),
+        });
+    }
+    Ok(DetachedFile {
+        filename: path,
+        statements,
+    })
+}
+
+/// Locate an included .ninja file: at its literal path if present, else in
+/// the first of `include_dirs` that has it.
+fn find_manifest(path: &str, include_dirs: &[String]) -> anyhow::Result<String> {
+    if include_dirs.is_empty() || std::path::Path::new(path).is_absolute() {
+        return Ok(path.to_owned());
+    }
+    let mut tried = vec![path.to_owned()];
+    for dir in include_dirs {
+        tried.push(canon::canon_path(format!("{}/{}", dir, path)));
+    }
+    match tried.iter().find(|p| std::fs::metadata(p).is_ok()) {
+        Some(found) => Ok(found.clone()),
+        None => bail!("read {}: not found; tried {}", path, tried.join(", ")),
+    }
+}
+
+/// A .ninja file's contents, as read by read_manifest().
+enum Manifest {
+    Read(Vec<u8>),
+    #[cfg(unix)]
+    Mapped(mmap::Mmap),
+}
+
+impl std::ops::Deref for Manifest {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match self {
+            Manifest::Read(bytes) => bytes,
+            #[cfg(unix)]
+            Manifest::Mapped(map) => map,
+        }
+    }
+}
+
+/// Read a .ninja file's contents.  With `mmap`, where supported, a regular
+/// file is memory-mapped and parsed in place, as generated manifests can be
+/// very large; anything else, like a pipe, is read as usual.
+fn read_manifest(path: &str, mmap: bool) -> std::io::Result<Manifest> {
+    #[cfg(unix)]
+    {
+        if mmap && std::fs::metadata(path)?.is_file() {
+            return Ok(Manifest::Mapped(mmap::Mmap::open(path)?));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = mmap;
+    std::fs::read(path).map(Manifest::Read)
+}
+
+/// If a file starts with a UTF-16 byte order mark, as written by some Windows
+/// tools, transcode it to UTF-8.  Returns None for any other file, which is
+/// parsed as-is.
+fn transcode_utf16(path: &str, bytes: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+    let from_bytes: fn([u8; 2]) -> u16 = match bytes {
+        [0xFF, 0xFE, ..] => u16::from_le_bytes,
+        [0xFE, 0xFF, ..] => u16::from_be_bytes,
+        _ => return Ok(None),
+    };
+    let units = &bytes[2..];
+    if !units.len().is_multiple_of(2) {
+        bail!("read {}: truncated UTF-16", path);
+    }
+    let units = units.chunks(2).map(|c| from_bytes([c[0], c[1]]));
+    let mut utf8 = String::with_capacity(units.len());
+    for c in char::decode_utf16(units) {
+        match c {
+            Ok(c) => utf8.push(c),
+            Err(err) => bail!("read {}: {}", path, err),
+        }
+    }
+    Ok(Some(utf8.into_bytes()))
 }
 
 /// State loaded by read().
@@ -185,15 +870,109 @@ pub struct State {
     pub hashes: graph::Hashes,
     pub default: Vec<FileId>,
     pub pools: Vec<(String, usize)>,
+    /// Paths of every build file loaded: the top-level one along with those
+    /// it includes or nests.
+    pub manifests: Vec<String>,
+    /// Whether a build file asked for content checking, as --check-content
+    /// does.
+    pub check_content: bool,
+}
+
+/// Remove any inputs of a build that are also its outputs.
+fn drop_self_inputs(b: &mut parse::Build<FileId>) {
+    if !b.ins.iter().any(|id| b.outs.contains(id)) {
+        return;
+    }
+    let mut ins = Vec::with_capacity(b.ins.len());
+    let mut counts = [0; 3];
+    let sections = [b.explicit_ins, b.implicit_ins, b.order_only_ins];
+    let mut rest = &b.ins[..];
+    for (section, &len) in sections.iter().enumerate() {
+        let (section_ins, tail) = rest.split_at(len);
+        for &id in section_ins {
+            if !b.outs.contains(&id) {
+                ins.push(id);
+                counts[section] += 1;
+            }
+        }
+        rest = tail;
+    }
+    b.ins = ins;
+    b.explicit_ins = counts[0];
+    b.implicit_ins = counts[1];
+    b.order_only_ins = counts[2];
+}
+
+/// Options that affect how build files are loaded.
+#[derive(Clone, Default)]
+pub struct Options {
+    /// Resolve variables not defined in the build file from the process
+    /// environment when expanding build commands.
+    pub os_env: bool,
+    /// Directories to search, in order, for included and subninja files not
+    /// found at their literal path.
+    pub include_dirs: Vec<String>,
+    /// Collapse builds that repeat an earlier build's command, inputs, and
+    /// outputs into the earlier build, rather than rejecting them.
+    pub dedup_builds: bool,
+    /// Include builds' `env.NAME` variables in their hashes, so changing them
+    /// reruns the builds.
+    pub hash_env: bool,
+    /// What to do about multiple builds generating the same output.
+    pub dupbuild: graph::DupBuild,
+    /// Top-level variables set from outside the build files, overriding
+    /// their bindings in each file.
+    pub overrides: Vec<(String, String)>,
+    /// The top-level build file, if not build.ninja; "-" reads it from
+    /// stdin, naming it `<stdin>` in messages.  The db is still .n2_db in
+    /// the current directory either way.
+    pub manifest: Option<String>,
+    /// Memory-map build files that are regular files rather than reading
+    /// them, which saves copying large ones.  n2 crashes (with SIGBUS) if a
+    /// mapped file is truncated while it's being loaded.
+    pub mmap: bool,
+}
+
+pub const DEFAULT_MANIFEST: &str = "build.ninja";
+const STDIN_NAME: &str = "<stdin>";
+
+fn read_stdin() -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    std::io::Read::read_to_end(&mut std::io::stdin(), &mut bytes)
+        .map_err(|err| anyhow!("read {}: {}", STDIN_NAME, err))?;
+    Ok(bytes)
+}
+
+/// Read the raw bytes of the top-level build file named as in
+/// Options::manifest, along with the name to use for it in messages.
+pub fn read_top_bytes(manifest: Option<&str>) -> anyhow::Result<(String, Vec<u8>)> {
+    match manifest {
+        Some("-") => Ok((STDIN_NAME.to_string(), read_stdin()?)),
+        manifest => {
+            let path = manifest.unwrap_or(DEFAULT_MANIFEST);
+            let bytes = std::fs::read(path).map_err(|err| anyhow!("read {}: {}", path, err))?;
+            Ok((path.to_string(), bytes))
+        }
+    }
 }
 
 /// Load build.ninja/.n2_db and return the loaded build graph and state.
-pub fn read() -> anyhow::Result<State> {
-    let mut loader = Loader::new();
-    trace::scope("loader.read_file", || {
-        let id = loader.graph.file_id(&mut "build.ninja".to_string());
-        loader.read_file(id)
-    })?;
+pub fn read(options: &Options) -> anyhow::Result<State> {
+    // A missing top-level manifest usually means the project hasn't been
+    // configured yet, which deserves more help than a missing include.
+    if options.manifest.is_none() {
+        if let Err(err) = std::fs::metadata(DEFAULT_MANIFEST) {
+            if err.kind() == std::io::ErrorKind::NotFound {
+                bail!(
+                    "no build.ninja in the current directory; \
+                 you may need to run your build generator (e.g. cmake, gn, meson) first, \
+                 or use -C to build in another directory"
+                );
+            }
+        }
+    }
+    let mut loader = Loader::new(options.clone());
+    trace::scope("loader.read_file", || loader.read_top_file())?;
     let mut hashes = graph::Hashes::new();
     let db = trace::scope("db::open", || {
         db::open(".n2_db", &mut loader.graph, &mut hashes)
@@ -205,13 +984,197 @@ pub fn read() -> anyhow::Result<State> {
         hashes,
         default: loader.default,
         pools: loader.pools,
+        manifests: loader.manifests,
+        check_content: loader.check_content,
     })
 }
 
-/// Parse a single file's content.
-#[cfg(test)]
-pub fn parse(name: String, content: Vec<u8>) -> anyhow::Result<graph::Graph> {
-    let mut loader = Loader::new();
-    trace::scope("loader.read_file", || loader.parse(name, content))?;
+/// Load build.ninja as read() does, without the db, checking it for problems
+/// for --manifest-check.  Rather than stopping at the first problem, this
+/// finds as many as it can and returns a message for each, starting with
+/// its location.  A parse error still ends the check, as the rest of the
+/// file can't be read reliably after one.
+pub fn check(options: &Options) -> Vec<String> {
+    let mut loader = Loader::new(options.clone());
+    loader.errors = Some(Vec::new());
+    let result = loader.read_top_file();
+    let mut errors = loader.errors.take().unwrap();
+    if let Err(err) = result {
+        errors.push(err.to_string());
+        return errors;
+    }
+
+    let graph = &loader.graph;
+    for (_, build) in graph.builds() {
+        if build.cmdline.is_none() && build.rule != "phony" {
+            errors.push(format!(
+                "{}: rule {:?} has no command",
+                build.location, build.rule
+            ));
+        }
+        if let Some(pool) = &build.pool {
+            let builtin = pool.is_empty() || pool == "console";
+            if !builtin && !loader.pools.iter().any(|(name, _)| name == pool) {
+                errors.push(format!("{}: unknown pool {:?}", build.location, pool));
+            }
+        }
+    }
+    for cycle in graph.cycles() {
+        let names: Vec<&str> = cycle
+            .iter()
+            .map(|&id| graph.file(id).name.as_str())
+            .collect();
+        let build = graph.build(graph.file(cycle[0]).input.unwrap());
+        errors.push(format!(
+            "{}: dependency cycle: {}",
+            build.location,
+            names.join(" -> ")
+        ));
+    }
+    errors
+}
+
+/// Parse a single file's content into a build graph, without consulting or
+/// updating the database.  `name` is used in error messages and as the
+/// location of the parsed builds.  Any `include`/`subninja` statements are
+/// read relative to the current directory.
+pub fn parse(name: &str, content: &[u8]) -> anyhow::Result<graph::Graph> {
+    let mut loader = Loader::new(Options::default());
+    trace::scope("loader.read_file", || {
+        loader.parse(name.to_string(), content)
+    })?;
+    Ok(loader.graph)
+}
+
+/// Read and parse the top-level build file, named as in Options::manifest,
+/// into a build graph, without consulting or updating the database.
+pub fn parse_top_file(manifest: Option<&str>) -> anyhow::Result<graph::Graph> {
+    let mut loader = Loader::new(Options {
+        manifest: manifest.map(String::from),
+        ..Options::default()
+    });
+    trace::scope("loader.read_file", || loader.read_top_file())?;
     Ok(loader.graph)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_bytes() -> anyhow::Result<()> {
+        let graph = parse(
+            "test.ninja",
+            b"
+rule cc
+  command = cc $in -o $out
+build foo.o: cc foo.c
+",
+        )?;
+        let id = graph.lookup_file_id("foo.o").unwrap();
+        let build = graph.build(graph.file(id).input.unwrap());
+        assert_eq!(build.cmdline.as_deref(), Some("cc foo.c -o foo.o"));
+        assert_eq!(build.location.to_string(), "test.ninja:4");
+        Ok(())
+    }
+
+    #[test]
+    fn input_kinds() -> anyhow::Result<()> {
+        let graph = parse(
+            "test.ninja",
+            b"build out: phony a b | c || d e f
+",
+        )?;
+        let id = graph.lookup_file_id("out").unwrap();
+        let build = graph.build(graph.file(id).input.unwrap());
+        let names = |ids: &[FileId]| {
+            ids.iter()
+                .map(|&id| graph.file(id).name.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(build.explicit_ins()), ["a", "b"]);
+        assert_eq!(names(build.dirtying_ins()), ["a", "b", "c"]);
+        assert_eq!(names(build.order_only_ins()), ["d", "e", "f"]);
+        assert_eq!(names(build.ordering_ins()), ["a", "b", "c", "d", "e", "f"]);
+        Ok(())
+    }
+
+    #[test]
+    fn utf16() -> anyhow::Result<()> {
+        let text = "build out: phony in\n";
+        let mut le = vec![0xFF, 0xFE];
+        let mut be = vec![0xFE, 0xFF];
+        for unit in text.encode_utf16() {
+            le.extend(unit.to_le_bytes());
+            be.extend(unit.to_be_bytes());
+        }
+        assert_eq!(transcode_utf16("le", &le)?.unwrap(), text.as_bytes());
+        assert_eq!(transcode_utf16("be", &be)?.unwrap(), text.as_bytes());
+        assert!(transcode_utf16("utf8", text.as_bytes())?.is_none());
+        assert!(transcode_utf16("odd", &le[..le.len() - 1]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_error() {
+        let err = parse("test.ninja", b"build foo.o: nosuchrule\n")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "test.ninja:1:14: unknown rule \"nosuchrule\""
+        );
+    }
+
+    #[test]
+    fn duplicate_output_col() {
+        let err = parse(
+            "test.ninja",
+            b"rule r\n  command = r\nbuild a b: r\nbuild c | b: r\n",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "test.ninja:4:11: multiple rules generate b"
+        );
+    }
+
+    #[test]
+    fn output_is_input() {
+        let err = parse(
+            "test.ninja",
+            b"rule gen\n  command = gen $in\nbuild foo.tmp: gen ./foo.tmp\n",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "test.ninja:3: foo.tmp is both an input and an output of the same build"
+        );
+    }
+
+    #[test]
+    fn zero_pool_weight() {
+        let err = parse(
+            "test.ninja",
+            b"rule r\n  command = r\nbuild out: r\n  pool_weight = 0\n",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.to_string(), "test.ninja:3: bad pool_weight \"0\"");
+    }
+
+    #[test]
+    fn phony_names_itself() -> anyhow::Result<()> {
+        let graph = parse("test.ninja", b"build all: phony all a || all b\n")?;
+        let all = graph.lookup_file_id("all").unwrap();
+        let build = graph.build(graph.file(all).input.unwrap());
+        let names = |ids: &[FileId]| -> Vec<String> {
+            ids.iter().map(|&id| graph.file(id).name.clone()).collect()
+        };
+        assert_eq!(names(build.explicit_ins()), vec!["a"]);
+        assert_eq!(names(build.order_only_ins()), vec!["b"]);
+        Ok(())
+    }
+}
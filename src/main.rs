@@ -1,21 +1,97 @@
 extern crate getopts;
 
 use anyhow::anyhow;
+use n2::browse;
+use n2::cache;
+use n2::compdb;
+use n2::format;
+use n2::frontend;
+use n2::graph;
 use n2::load;
+use n2::normalize;
+use n2::progress;
 use n2::progress::ConsoleProgress;
+use n2::progress::Progress;
 use n2::trace;
 use n2::work;
 use std::path::Path;
+use std::time::Duration;
 
 // The result of starting a build.
 enum BuildResult {
     /// A build task failed.
     Failed,
+    /// The build was interrupted, e.g. by Ctrl-C.
+    Interrupted,
     /// Renerated build.ninja rather than the requested build.  The caller must
     /// reload build.ninja to continue with building.
     Regen,
     /// Build succeeded, and the number is the count of executed tasks.
     Success(usize),
+    /// A --no-rebuild check found out of date tasks.
+    OutOfDate,
+    /// --print-dirty listed this many builds that would run.
+    Dirty(usize),
+    /// --stop-after stopped the build after this many tasks.
+    Stopped(usize),
+}
+
+/// Options that apply to each load and build of the manifest.
+struct BuildOptions {
+    load: load::Options,
+    parallelism: usize,
+    /// Print internal counters after building, for -d stats.
+    stats: bool,
+    /// List every file involved in a dependency cycle, for -d graph-cycle.
+    explain_cycles: bool,
+    /// Add dependencies found missing by depfiles, for --add-missing-deps.
+    add_missing_deps: bool,
+    /// Failed commands to stop the build at, or 0 for no limit, for -k.
+    keep_going: usize,
+    /// Fail if a wanted file is missing after the build, for
+    /// --verify-targets.
+    verify_targets: bool,
+    /// Leave the outputs of failed commands, for --keep-failed-outputs.
+    keep_failed_outputs: bool,
+    /// Run commands with only their declared inputs, for --sandbox.
+    sandbox: bool,
+    /// Tasks to run before stopping, for --stop-after.
+    stop_after: Option<usize>,
+    /// Ignore inputs touched without changing, for --check-content.
+    check_content: bool,
+    /// Ignore timestamps embedded in archives when comparing content, for
+    /// --normalize-content.
+    normalize_content: bool,
+    /// Leave output mtimes out of build hashes, for --ignore-output-mtimes.
+    ignore_output_mtimes: bool,
+    /// Hash absolute paths in commands relative to the build directory, for
+    /// --hash-relative-paths.
+    hash_relative_paths: bool,
+    /// Run only the builds that generate the targets, for --only.
+    single: bool,
+    /// Rebuild the targets regardless of whether they're up to date, for
+    /// -B/--force-all.  The bool is true if everything they depend on should
+    /// also be rebuilt.
+    force: Option<bool>,
+    /// Mark dirty builds up to date without running them, for --touch.
+    touch: bool,
+    /// See --mtime-granularity.
+    mtime_granularity: Duration,
+    /// See --depfile-dir.
+    depfile_dir: Option<String>,
+    /// Print a summary of the graph after loading, for --print-graph-stats.
+    graph_stats: bool,
+    /// See --cache-dir.
+    cache_dir: Option<String>,
+    /// Fail if anything would be built, for --no-rebuild.
+    no_rebuild: bool,
+    /// List the builds that would run without running them, for
+    /// --print-dirty.
+    print_dirty: bool,
+    /// See --command-log.
+    command_log: Option<String>,
+    /// Ask commands for colored output, for --color-diagnostics.
+    color_diagnostics: bool,
 }
 
 // Build a given set of targets.  If regen is true, build "build.ninja" first if
@@ -23,12 +99,18 @@ enum BuildResult {
 // BuildResult::Regen to signal to the caller that we need to start the whole
 // build over.
 fn build(
-    progress: &mut ConsoleProgress,
-    parallelism: usize,
+    progress: &mut dyn Progress,
+    options: &BuildOptions,
     regen: bool,
     target_names: &[String],
 ) -> anyhow::Result<BuildResult> {
-    let mut state = trace::scope("load::read", load::read)?;
+    let mut state = trace::scope("load::read", || load::read(&options.load))?;
+    let target_names = &expand_targets(&state.graph, target_names)?;
+
+    if options.graph_stats {
+        print!("{}", state.graph.stats());
+    }
+    let no_builds = state.graph.builds().next().is_none();
 
     let mut work = work::Work::new(
         &mut state.graph,
@@ -36,44 +118,215 @@ fn build(
         &mut state.db,
         progress,
         state.pools,
-        parallelism,
+        options.parallelism,
     );
+    work.set_touch(options.touch);
+    work.set_explain_cycles(options.explain_cycles);
+    work.set_add_missing_deps(options.add_missing_deps);
+    work.set_keep_going(options.keep_going);
+    work.set_verify_targets(options.verify_targets);
+    work.set_keep_failed_outputs(options.keep_failed_outputs);
+    work.set_sandbox(options.sandbox);
+    work.set_stop_after(options.stop_after);
+    work.set_color_diagnostics(options.color_diagnostics);
+    work.set_check_content(options.check_content || state.check_content);
+    if options.normalize_content {
+        work.set_normalizers(normalize::Normalizers::builtin());
+    }
+    work.set_ignore_output_mtimes(options.ignore_output_mtimes);
+    if options.hash_relative_paths {
+        let root = std::env::current_dir()?;
+        work.set_hash_root(Some(root.to_string_lossy().into_owned()));
+    }
+    work.set_mtime_granularity(options.mtime_granularity);
+    work.set_depfile_dir(options.depfile_dir.clone());
+    work.set_cache(options.cache_dir.as_ref().map(cache::Cache::new));
+    work.set_dry_run(options.no_rebuild || options.print_dirty);
+    if let Some(path) = &options.command_log {
+        work.set_command_log(path)?;
+    }
 
+    let mut tasks_done = 0;
     if regen {
-        if let Some(target) = work.build_ninja_fileid() {
+        let manifest = options
+            .load
+            .manifest
+            .as_deref()
+            .unwrap_or(load::DEFAULT_MANIFEST);
+        if let Some(target) = work.build_ninja_fileid(manifest) {
             // Attempt to rebuild build.ninja.
+            let mtimes = manifest_mtimes(&state.manifests);
             work.want_fileid(target)?;
-            match trace::scope("work.run", || work.run())? {
-                None => return Ok(BuildResult::Failed),
-                Some(0) => {
+            let result = trace::scope("work.run", || work.run());
+            if options.stats {
+                print!("{}", work.stats());
+            }
+            match result? {
+                work::RunResult::Failed => return Ok(BuildResult::Failed),
+                work::RunResult::Interrupted => return Ok(BuildResult::Interrupted),
+                work::RunResult::Stopped(n) => return Ok(BuildResult::Stopped(n)),
+                work::RunResult::Success(0) => {
                     // build.ninja already up to date.
                 }
-                Some(_) => {
-                    // Regenerated build.ninja; start over.
-                    return Ok(BuildResult::Regen);
+                work::RunResult::Success(n) => {
+                    // If the generator ran but left the build files
+                    // untouched, as e.g. CMake does when nothing changed, the
+                    // loaded graph is still accurate and we can keep going.
+                    if manifest_mtimes(&state.manifests) != mtimes {
+                        // Regenerated the build files; start over.
+                        return Ok(BuildResult::Regen);
+                    }
+                    tasks_done = n;
                 }
             }
         }
     }
 
-    if !target_names.is_empty() {
-        for name in target_names {
-            work.want_file(name)?;
+    if options.single {
+        if target_names.is_empty() {
+            anyhow::bail!("--only requires at least one target");
+        }
+        work.want_single_files(target_names)?;
+    } else if !target_names.is_empty() {
+        work.want_files(target_names)?;
+        if let Some(transitive) = options.force {
+            for name in target_names {
+                work.force_file(name, transitive)?;
+            }
         }
     } else if !state.default.is_empty() {
-        for target in state.default {
-            work.want_fileid(target)?;
+        work.want_fileids(&state.default)?;
+        if let Some(transitive) = options.force {
+            for &target in &state.default {
+                work.force_fileid(target, transitive);
+            }
         }
+    } else if no_builds {
+        anyhow::bail!("no targets defined in build.ninja");
     } else {
         anyhow::bail!("no path specified and no default");
     }
 
-    Ok(match trace::scope("work.run", || work.run())? {
-        None => BuildResult::Failed,
-        Some(n) => BuildResult::Success(n),
+    let result = trace::scope("work.run", || work.run());
+    if options.stats {
+        print!("{}", work.stats());
+    }
+    let result = result?;
+    if options.print_dirty {
+        let dirty = work.would_run_outputs();
+        for (output, message) in &dirty {
+            println!("{}: {}", output, message);
+        }
+        return Ok(BuildResult::Dirty(dirty.len()));
+    }
+    if options.no_rebuild {
+        let would_run = work.would_run();
+        for message in &would_run {
+            println!("n2: would run: {}", message);
+        }
+        if !would_run.is_empty() {
+            return Ok(BuildResult::OutOfDate);
+        }
+    }
+    Ok(match result {
+        work::RunResult::Failed => BuildResult::Failed,
+        work::RunResult::Interrupted => BuildResult::Interrupted,
+        work::RunResult::Stopped(n) => BuildResult::Stopped(tasks_done + n),
+        work::RunResult::Success(n) => BuildResult::Success(tasks_done + n),
+    })
+}
+
+/// Expand `@rule:NAME` selectors in `names` to the explicit outputs of every
+/// build using rule NAME, in build order.  Other names are kept as they are.
+fn expand_targets(graph: &graph::Graph, names: &[String]) -> anyhow::Result<Vec<String>> {
+    let mut targets = Vec::with_capacity(names.len());
+    for name in names {
+        let rule = match name.strip_prefix("@rule:") {
+            Some(rule) => rule,
+            None => {
+                targets.push(name.clone());
+                continue;
+            }
+        };
+        let start = targets.len();
+        for (_, build) in graph.builds().filter(|(_, build)| build.rule == rule) {
+            targets.extend(
+                build
+                    .explicit_outs()
+                    .iter()
+                    .map(|&id| graph.file(id).name.clone()),
+            );
+        }
+        if targets.len() == start {
+            anyhow::bail!("unknown rule {:?} in {:?}: no builds use it", rule, name);
+        }
+    }
+    Ok(targets)
+}
+
+/// Read a list of targets, one per line, skipping blank lines and # comments.
+fn read_targets_file(path: &str) -> anyhow::Result<Vec<String>> {
+    let text = std::fs::read_to_string(path).map_err(|err| anyhow!("read {}: {}", path, err))?;
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// Prints GNU Make's "Entering directory" line when created and the matching
+/// "Leaving directory" line when dropped, which editors like Emacs use to find
+/// the files named by relative paths in error messages.
+struct PrintDirectory(std::path::PathBuf);
+
+impl PrintDirectory {
+    fn enter() -> anyhow::Result<Self> {
+        let dir = std::env::current_dir()?;
+        println!("n2: Entering directory '{}'", dir.display());
+        Ok(PrintDirectory(dir))
+    }
+}
+
+impl Drop for PrintDirectory {
+    fn drop(&mut self) {
+        println!("n2: Leaving directory '{}'", self.0.display());
+    }
+}
+
+/// Parse a duration like "100ns", "1us", "10ms" or "2s".
+fn parse_duration(text: &str) -> Option<Duration> {
+    let split = text.find(|c: char| !c.is_ascii_digit())?;
+    let n: u64 = text[..split].parse().ok()?;
+    Some(match &text[split..] {
+        "ns" => Duration::from_nanos(n),
+        "us" => Duration::from_micros(n),
+        "ms" => Duration::from_millis(n),
+        "s" => Duration::from_secs(n),
+        _ => return None,
     })
 }
 
+/// The modification times of build files, or None for any that are missing.
+fn manifest_mtimes(paths: &[String]) -> Vec<Option<std::time::SystemTime>> {
+    paths
+        .iter()
+        .map(|path| {
+            std::fs::metadata(path)
+                .and_then(|meta| meta.modified())
+                .ok()
+        })
+        .collect()
+}
+
+/// Whether `arg` is a number for `-j`, as opposed to a target following it.
+fn is_parallelism(arg: &str) -> bool {
+    arg.strip_suffix('%')
+        .unwrap_or(arg)
+        .parse::<usize>()
+        .is_ok()
+}
+
 #[cfg(unix)]
 fn use_fancy_terminal() -> bool {
     unsafe {
@@ -91,33 +344,349 @@ fn use_fancy_terminal() -> bool {
     }
 }
 
+/// Parse a `name=value` command line argument, which overrides the top-level
+/// variable `name` in the build files.
+fn parse_override(arg: &str) -> Option<(String, String)> {
+    let (key, val) = arg.split_once('=')?;
+    if key.is_empty() || !key.bytes().all(n2::parse::is_ident_char) {
+        return None;
+    }
+    Some((key.to_owned(), val.to_owned()))
+}
+
+/// A subcommand, run with -t.
+struct Tool {
+    name: &'static str,
+    /// One line description, for -t list.
+    help: &'static str,
+    run: fn(&getopts::Matches) -> anyhow::Result<i32>,
+}
+
+const TOOLS: &[Tool] = &[
+    Tool {
+        name: "browse",
+        help: "explore the build graph in a web browser, from an optional target (see --port)",
+        run: tool_browse,
+    },
+    Tool {
+        name: "compdb",
+        help: "print a compilation database for the given rules, or all rules \
+               (see --targets-file)",
+        run: tool_compdb,
+    },
+    Tool {
+        name: "format",
+        help: "print build.ninja with canonical formatting",
+        run: tool_format,
+    },
+];
+
+fn tool_browse(matches: &getopts::Matches) -> anyhow::Result<i32> {
+    let graph = load::parse_top_file(matches.opt_str("f").as_deref())?;
+    let port = match matches.opt_str("port") {
+        None => 8000,
+        Some(port) => port
+            .parse::<u16>()
+            .map_err(|err| anyhow!("invalid --port {:?}: {}", port, err))?,
+    };
+    let target = matches.free.first().map(|target| target.as_str());
+    browse::serve(&graph, port, target)?;
+    Ok(0)
+}
+
+fn tool_compdb(matches: &getopts::Matches) -> anyhow::Result<i32> {
+    let graph = load::parse_top_file(matches.opt_str("f").as_deref())?;
+    let targets = match matches.opt_str("targets-file") {
+        None => None,
+        Some(path) => Some(
+            read_targets_file(&path)?
+                .into_iter()
+                .map(|name| {
+                    graph
+                        .lookup_file_id(&name)
+                        .ok_or_else(|| anyhow!("unknown path requested: {:?}", name))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        ),
+    };
+    let dir = std::env::current_dir()?;
+    let filter = compdb::Filter {
+        rules: &matches.free,
+        targets: targets.as_deref(),
+    };
+    print!("{}", compdb::write(&graph, &dir.to_string_lossy(), &filter));
+    Ok(0)
+}
+
+fn tool_format(matches: &getopts::Matches) -> anyhow::Result<i32> {
+    let (path, bytes) = load::read_top_bytes(matches.opt_str("f").as_deref())?;
+    print!("{}", format::format(&path, &bytes)?);
+    Ok(0)
+}
+
 fn run() -> anyhow::Result<i32> {
-    let args: Vec<_> = std::env::args().collect();
+    let mut args: Vec<_> = std::env::args().collect();
     let fake_ninja_compat =
         Path::new(&args[0]).file_name().unwrap() == std::ffi::OsStr::new("ninja");
 
-    // Ninja uses available processors + a constant, but I don't think the
-    // difference matters too much.
-    let mut parallelism = usize::from(std::thread::available_parallelism()?);
+    let mut parallelism = work::default_parallelism();
 
     let mut opts = getopts::Options::new();
     opts.optopt("C", "", "chdir before running", "DIR");
+    opts.optopt(
+        "f",
+        "",
+        "specify input build file [default=build.ninja], or - to read it from stdin; \
+         either way the db is .n2_db in the current directory",
+        "FILE",
+    );
     opts.optopt("d", "debug", "debugging tools", "TOOL");
     opts.optopt("t", "tool", "subcommands", "TOOL");
-    opts.optopt(
+    opts.optflagopt(
         "j",
         "",
-        &format!("parallelism [default from system={}]", parallelism),
+        &format!(
+            "parallelism, or N% for a share of the CPUs; 0 means serial \
+             [default from system={}]",
+            parallelism
+        ),
         "NUM",
     );
+    opts.optopt(
+        "",
+        "targets-file",
+        "read additional targets from FILE, one per line",
+        "FILE",
+    );
+    opts.optmulti(
+        "",
+        "include-dir",
+        "search DIR for included and subninja files not found at their given path",
+        "DIR",
+    );
+    opts.optflag(
+        "",
+        "mmap",
+        "memory-map build files rather than reading them; n2 crashes if one is \
+         truncated while loading, e.g. by a generator rerunning concurrently",
+    );
+    opts.optopt(
+        "",
+        "mtime-granularity",
+        "treat file times within the same DURATION (e.g. 1us, 10ms, 1s) as equal",
+        "DURATION",
+    );
+    opts.optopt(
+        "",
+        "depfile-dir",
+        "resolve relative paths in depfiles against DIR",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "cache-dir",
+        "reuse outputs of identical earlier commands stored in DIR",
+        "DIR",
+    );
+    opts.optflagopt(
+        "",
+        "command-log",
+        "append each command run, with its start/end times and exit status, to FILE \
+         (default .n2_commands.log)",
+        "FILE",
+    );
+    opts.optflagopt(
+        "",
+        "color-diagnostics",
+        "set CLICOLOR_FORCE=1 for commands, so compilers color their diagnostics \
+         though n2 captures their output; WHEN is auto (if n2's output is a \
+         terminal, the default), always or never",
+        "WHEN",
+    );
+    opts.optflag(
+        "",
+        "manifest-check",
+        "check build.ninja for problems, reporting all of them, without building",
+    );
+    opts.optflag(
+        "",
+        "check-content",
+        "don't rebuild for inputs whose mtime changed but whose content didn't; \
+         costs reading inputs to compare them",
+    );
+    opts.optflag(
+        "",
+        "normalize-content",
+        "with --check-content, ignore timestamps embedded in .a and .gz files",
+    );
+    opts.optflag(
+        "",
+        "ignore-output-mtimes",
+        "judge builds up to date by their inputs and command only, not the mtimes \
+         of their outputs (e.g. for outputs set to a fixed timestamp)",
+    );
+    opts.optflag(
+        "",
+        "hash-relative-paths",
+        "hash absolute paths under the build directory in commands as relative \
+         paths, so checkouts in different places agree on what is up to date",
+    );
+    opts.optflag(
+        "",
+        "print-directory",
+        "print make-style \"Entering/Leaving directory\" lines around the build, \
+         for editors that resolve relative paths in errors with them",
+    );
+    opts.optflag(
+        "",
+        "sandbox",
+        "run each command in a directory of its own holding only its declared \
+         inputs, to catch undeclared dependencies",
+    );
+    opts.optflag(
+        "",
+        "keep-failed-outputs",
+        "don't delete outputs modified by a failed or interrupted command",
+    );
+    opts.optflag(
+        "",
+        "add-missing-deps",
+        "when a depfile lists a generated file with no dependency path to it, \
+         warn and add the dependency rather than failing",
+    );
+    opts.optopt(
+        "k",
+        "",
+        "keep going until N commands fail, building what doesn't depend on them \
+         (0 means no limit) [default=1]",
+        "N",
+    );
+    opts.optflag(
+        "",
+        "verify-targets",
+        "fail if a target is missing after its command succeeded",
+    );
+    opts.optopt(
+        "",
+        "dupbuild",
+        "whether multiple builds generating one output is an error (err, the default) \
+         or a warning (warn)",
+        "err|warn",
+    );
+    opts.optopt(
+        "",
+        "max-fail-output",
+        &format!(
+            "print at most LINES lines of output per failed command (default {}, 0 for no limit)",
+            progress::DEFAULT_MAX_FAIL_OUTPUT
+        ),
+        "LINES",
+    );
+    opts.optopt(
+        "",
+        "frontend",
+        "instead of showing progress, write build events as JSON lines to FILE \
+         (e.g. /dev/fd/3) for a frontend program to display",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "fail-output-file",
+        "append the full output of failed commands cut short by --max-fail-output to FILE",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "port",
+        "port for -t browse to listen on (default 8000)",
+        "PORT",
+    );
     opts.optflag("h", "help", "");
     opts.optflag("v", "verbose", "print executed command lines");
+    opts.optflag(
+        "B",
+        "force",
+        "rebuild the targets even if they're up to date",
+    );
+    opts.optflag(
+        "",
+        "force-all",
+        "rebuild the targets and everything they depend on",
+    );
+    opts.optflag(
+        "",
+        "no-rebuild",
+        "check that the targets are up to date without building anything; exits with status 2 if not",
+    );
+    opts.optflag(
+        "",
+        "show-oldest",
+        "show the longest-running command and its running time on the progress line",
+    );
+    opts.optmulti(
+        "",
+        "group-output",
+        "hold back the output of builds in POOL and print it together at the end",
+        "POOL",
+    );
+    opts.optopt(
+        "",
+        "stop-after",
+        "start at most N tasks, then stop once they finish, e.g. to bisect a failure",
+        "N",
+    );
+    opts.optflag(
+        "",
+        "print-dirty",
+        "list the builds that would run, by output and description, without running them",
+    );
+    opts.optflag(
+        "",
+        "only",
+        "run only the commands that generate the targets, without building their inputs",
+    );
+    opts.optflag(
+        "",
+        "touch",
+        "mark the targets up to date using their current outputs, without running commands",
+    );
+    opts.optflag(
+        "",
+        "output-sync",
+        "print each command's output as one block, between banners with the command and exit status",
+    );
+    opts.optflag(
+        "",
+        "print-graph-stats",
+        "print a summary of the size of the build graph after loading",
+    );
+    opts.optflag(
+        "",
+        "dedup-builds",
+        "run builds that repeat an earlier build's command, inputs, and outputs only once",
+    );
+    opts.optflag(
+        "",
+        "env-vars",
+        "expand variables not defined in the build file from the environment",
+    );
     if fake_ninja_compat {
         opts.optflag("", "version", "print fake ninja version");
     }
+    // A -j without a number means the default parallelism, but getopts would
+    // take an argument following it, like a target, as the number.  Such a
+    // -j requests nothing beyond the default, so just drop it.
+    if let Some(i) = args.iter().position(|arg| arg == "-j") {
+        if !matches!(args.get(i + 1), Some(next) if is_parallelism(next)) {
+            args.remove(i);
+        }
+    }
     let matches = opts.parse(&args[1..])?;
     if matches.opt_present("h") {
-        println!("{}", opts.usage("usage: n2 [target]"));
+        println!(
+            "{}",
+            opts.usage("usage: n2 [target | @rule:NAME | name=value]...")
+        );
         return Ok(1);
     }
 
@@ -128,53 +697,222 @@ fn run() -> anyhow::Result<i32> {
         }
     }
 
+    let mut stats = false;
+    let mut explain_cycles = false;
     if let Some(debug) = matches.opt_str("d") {
         match debug.as_str() {
             "list" => {
                 println!("debug tools:");
-                println!("  trace  generate json performance trace");
+                println!("  graph-cycle  list every file in cycles with a dependency cycle found");
+                println!("  stats        print internal counters after building");
+                println!("  trace        generate json performance trace");
                 return Ok(1);
             }
+            "graph-cycle" => explain_cycles = true,
+            "stats" => stats = true,
             "trace" => trace::open("trace.json")?,
             _ => anyhow::bail!("unknown -d {:?}, use -d list to list", debug),
         }
     }
 
-    if let Some(tool) = matches.opt_str("t") {
-        match tool.as_str() {
-            "list" => {
-                println!("subcommands:");
-                println!("  (none yet, but see README if you're looking here trying to get CMake to work)");
-                return Ok(1);
-            }
-            _ => {
-                if fake_ninja_compat {
-                    return Ok(0);
-                }
-                anyhow::bail!("unknown -t {:?}, use -t list to list", tool);
-            }
+    if matches.opt_present("j") {
+        let flag = matches.opt_str("j");
+        if let Some(percent) = flag.as_deref().and_then(|flag| flag.strip_suffix('%')) {
+            let percent = match percent.parse::<usize>() {
+                Ok(percent) => percent,
+                Err(e) => anyhow::bail!("invalid -j {:?}: {:?}", flag.unwrap(), e),
+            };
+            parallelism = work::parallelism_from_percent(percent, work::default_parallelism());
+        } else {
+            let n = match flag {
+                None => None,
+                Some(flag) => match flag.parse::<usize>() {
+                    Ok(n) => Some(n),
+                    Err(e) => anyhow::bail!("invalid -j {:?}: {:?}", flag, e),
+                },
+            };
+            parallelism = work::parallelism_from_flag(n);
         }
     }
 
-    if let Some(parallelism_flag) = matches.opt_str("j") {
-        match parallelism_flag.parse::<usize>() {
-            Ok(n) => parallelism = n,
-            Err(e) => anyhow::bail!("invalid -j {:?}: {:?}", parallelism, e),
+    let keep_going = match matches.opt_str("k") {
+        None => 1,
+        Some(n) => n
+            .parse::<usize>()
+            .map_err(|err| anyhow!("invalid -k {:?}: {}", n, err))?,
+    };
+
+    let color_diagnostics = if matches.opt_present("color-diagnostics") {
+        match matches.opt_str("color-diagnostics").as_deref() {
+            None | Some("auto") => use_fancy_terminal(),
+            Some("always") => true,
+            Some("never") => false,
+            Some(when) => anyhow::bail!(
+                "invalid --color-diagnostics {:?}, expected auto, always or never",
+                when
+            ),
         }
-    }
+    } else {
+        false
+    };
 
     if let Some(dir) = matches.opt_str("C") {
         let dir = Path::new(&dir);
         std::env::set_current_dir(dir).map_err(|err| anyhow!("chdir {:?}: {}", dir, err))?;
     }
+    let _print_directory = if matches.opt_present("print-directory") {
+        Some(PrintDirectory::enter()?)
+    } else {
+        None
+    };
 
-    let mut progress = ConsoleProgress::new(matches.opt_present("v"), use_fancy_terminal());
+    if let Some(tool) = matches.opt_str("t") {
+        if tool == "list" {
+            println!("subcommands:");
+            for tool in TOOLS {
+                println!("  {:<8}{}", tool.name, tool.help);
+            }
+            println!("(see README if you're looking here trying to get CMake to work)");
+            return Ok(1);
+        }
+        return match TOOLS.iter().find(|t| t.name == tool) {
+            Some(tool) => (tool.run)(&matches),
+            None if fake_ninja_compat => Ok(0),
+            None => {
+                let names: Vec<&str> = TOOLS.iter().map(|t| t.name).collect();
+                anyhow::bail!(
+                    "unknown -t {:?}, valid tools are: {}",
+                    tool,
+                    names.join(", ")
+                )
+            }
+        };
+    }
+
+    let mut console = ConsoleProgress::new(matches.opt_present("v"), use_fancy_terminal());
+    console.set_output_sync(matches.opt_present("output-sync"));
+    console.set_grouped_pools(matches.opt_strs("group-output"));
+    console.set_show_oldest(matches.opt_present("show-oldest"));
+    let max_fail_output = match matches.opt_str("max-fail-output") {
+        None => progress::DEFAULT_MAX_FAIL_OUTPUT,
+        Some(lines) => lines
+            .parse::<usize>()
+            .map_err(|err| anyhow!("invalid --max-fail-output {:?}: {}", lines, err))?,
+    };
+    console.set_max_fail_output(
+        max_fail_output,
+        matches.opt_str("fail-output-file").map(Into::into),
+    );
+    let mut progress: Box<dyn Progress> = match matches.opt_str("frontend") {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)
+                .map_err(|err| anyhow!("open {}: {}", path, err))?;
+            Box::new(frontend::JsonProgress::new(std::io::BufWriter::new(file)))
+        }
+        None => Box::new(console),
+    };
+
+    let options = BuildOptions {
+        load: load::Options {
+            os_env: matches.opt_present("env-vars"),
+            manifest: matches.opt_str("f"),
+            include_dirs: matches.opt_strs("include-dir"),
+            mmap: matches.opt_present("mmap"),
+            dedup_builds: matches.opt_present("dedup-builds"),
+            // Only set from the manifest.
+            hash_env: false,
+            dupbuild: match matches.opt_str("dupbuild").as_deref() {
+                None | Some("err") => graph::DupBuild::Err,
+                Some("warn") => graph::DupBuild::Warn,
+                Some(policy) => anyhow::bail!("invalid --dupbuild {:?}, use err or warn", policy),
+            },
+            overrides: matches
+                .free
+                .iter()
+                .filter_map(|arg| parse_override(arg))
+                .collect(),
+        },
+        parallelism,
+        stats,
+        explain_cycles,
+        add_missing_deps: matches.opt_present("add-missing-deps"),
+        keep_going,
+        verify_targets: matches.opt_present("verify-targets"),
+        keep_failed_outputs: matches.opt_present("keep-failed-outputs"),
+        sandbox: matches.opt_present("sandbox"),
+        stop_after: match matches.opt_str("stop-after") {
+            None => None,
+            Some(n) => Some(
+                n.parse::<usize>()
+                    .map_err(|err| anyhow!("invalid --stop-after {:?}: {}", n, err))?,
+            ),
+        },
+        check_content: matches.opt_present("check-content"),
+        normalize_content: matches.opt_present("normalize-content"),
+        ignore_output_mtimes: matches.opt_present("ignore-output-mtimes"),
+        hash_relative_paths: matches.opt_present("hash-relative-paths"),
+        single: matches.opt_present("only"),
+        force: if matches.opt_present("force-all") {
+            Some(true)
+        } else if matches.opt_present("B") {
+            Some(false)
+        } else {
+            None
+        },
+        touch: matches.opt_present("touch"),
+        mtime_granularity: match matches.opt_str("mtime-granularity") {
+            None => Duration::ZERO,
+            Some(flag) => parse_duration(&flag)
+                .ok_or_else(|| anyhow!("invalid --mtime-granularity {:?}", flag))?,
+        },
+        depfile_dir: matches.opt_str("depfile-dir"),
+        graph_stats: matches.opt_present("print-graph-stats"),
+        cache_dir: matches.opt_str("cache-dir"),
+        no_rebuild: matches.opt_present("no-rebuild"),
+        print_dirty: matches.opt_present("print-dirty"),
+        command_log: if matches.opt_present("command-log") {
+            Some(
+                matches
+                    .opt_str("command-log")
+                    .unwrap_or_else(|| ".n2_commands.log".to_string()),
+            )
+        } else {
+            None
+        },
+        color_diagnostics,
+    };
+
+    if matches.opt_present("manifest-check") {
+        let errors = load::check(&options.load);
+        for err in &errors {
+            println!("n2: error: {}", err);
+        }
+        if !errors.is_empty() {
+            return Ok(1);
+        }
+        println!("n2: no problems found");
+        return Ok(0);
+    }
+
+    let mut targets: Vec<String> = matches
+        .free
+        .iter()
+        .filter(|arg| parse_override(arg).is_none())
+        .cloned()
+        .collect();
+    if let Some(path) = matches.opt_str("targets-file") {
+        targets.extend(read_targets_file(&path)?);
+    }
 
     // Build once with regen=true, and if the result says we regenerated the
     // build file, reload and build everything a second time.
-    let mut result = build(&mut progress, parallelism, true, &matches.free)?;
+    let mut result = build(progress.as_mut(), &options, true, &targets)?;
     if let BuildResult::Regen = result {
-        result = build(&mut progress, parallelism, false, &matches.free)?;
+        result = build(progress.as_mut(), &options, false, &targets)?;
     }
 
     match result {
@@ -183,10 +921,32 @@ fn run() -> anyhow::Result<i32> {
             // Don't print any summary, the failing task is enough info.
             return Ok(1);
         }
+        BuildResult::Interrupted => {
+            // Like a shell reporting a process killed by SIGINT, so scripts
+            // can tell an interrupted build from a failed one.
+            println!("n2: error: interrupted");
+            return Ok(130);
+        }
+        BuildResult::OutOfDate => {
+            println!("n2: error: targets are out of date");
+            return Ok(2);
+        }
+        BuildResult::Dirty(0) => {
+            println!("n2: no work to do");
+        }
+        BuildResult::Dirty(n) => {
+            println!("n2: {} tasks would run", n);
+        }
+        BuildResult::Stopped(n) => {
+            println!("n2: stopped after {} tasks, as limited by --stop-after", n);
+        }
         BuildResult::Success(0) => {
             // Special case: don't print numbers when no work done.
             println!("n2: no work to do");
         }
+        BuildResult::Success(n) if options.touch => {
+            println!("n2: marked {} tasks up to date", n);
+        }
         BuildResult::Success(n) => {
             println!("n2: ran {} tasks, now up to date", n);
         }
@@ -0,0 +1,63 @@
+//! Read-only memory mapping of files, used to parse large manifests without
+//! copying them into memory first.
+
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+
+/// A read-only mapping of an entire file.
+pub struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mmap {
+    pub fn open(path: &str) -> std::io::Result<Mmap> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            // mmap() rejects zero-length mappings.
+            return Ok(Mmap {
+                ptr: std::ptr::null_mut(),
+                len,
+            });
+        }
+        // Safety: mapping a file we opened for reading; the mapping outlives
+        // the file descriptor, which is fine per mmap semantics.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Mmap { ptr, len })
+    }
+}
+
+impl std::ops::Deref for Mmap {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        // Safety: ptr is a live mapping of len bytes until drop.
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            // Safety: unmapping the mapping we created in open().
+            unsafe {
+                libc::munmap(self.ptr, self.len);
+            }
+        }
+    }
+}
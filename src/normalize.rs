@@ -0,0 +1,151 @@
+//! Normalizing file content before digesting it, for --check-content, so that
+//! files that embed incidental data like timestamps hash the same when
+//! nothing else about them changed.
+//!
+//! Normalizers are chosen by file extension; files with no normalizer for
+//! their extension are digested as is.
+
+use std::collections::HashMap;
+use std::hash::Hasher;
+
+/// Rewrites file content to blank out the parts that vary without the content
+/// logically changing.
+pub trait ContentNormalizer {
+    fn normalize(&self, content: &mut Vec<u8>);
+}
+
+/// Blanks the modification time, owner and group of each member of a Unix
+/// `ar` archive (`.a` files).
+pub struct ArNormalizer;
+
+impl ContentNormalizer for ArNormalizer {
+    fn normalize(&self, content: &mut Vec<u8>) {
+        const MAGIC: &[u8] = b"!<arch>\n";
+        const HEADER_LEN: usize = 60;
+        if !content.starts_with(MAGIC) {
+            return;
+        }
+        let mut pos = MAGIC.len();
+        while pos + HEADER_LEN <= content.len() {
+            let header = &mut content[pos..pos + HEADER_LEN];
+            // Fields are space-padded decimal text: mtime, uid, gid.
+            for (start, end) in [(16, 28), (28, 34), (34, 40)] {
+                header[start] = b'0';
+                header[start + 1..end].fill(b' ');
+            }
+            let size = match std::str::from_utf8(&header[48..58])
+                .ok()
+                .and_then(|size| size.trim().parse::<usize>().ok())
+            {
+                Some(size) => size,
+                // Not an archive after all; leave the rest alone.
+                None => return,
+            };
+            // Members are aligned to even offsets.
+            pos += HEADER_LEN + size + (size % 2);
+        }
+    }
+}
+
+/// Blanks the modification time in the header of a gzip file.
+pub struct GzipNormalizer;
+
+impl ContentNormalizer for GzipNormalizer {
+    fn normalize(&self, content: &mut Vec<u8>) {
+        if content.len() >= 10 && content.starts_with(&[0x1f, 0x8b]) {
+            content[4..8].fill(0);
+        }
+    }
+}
+
+/// The normalizers to use, by file extension.
+#[derive(Default)]
+pub struct Normalizers {
+    by_extension: HashMap<String, Box<dyn ContentNormalizer>>,
+}
+
+impl Normalizers {
+    /// Normalizers for the formats n2 knows about: `.a` and `.gz`.
+    pub fn builtin() -> Self {
+        let mut normalizers = Normalizers::default();
+        normalizers.add("a", Box::new(ArNormalizer));
+        normalizers.add("gz", Box::new(GzipNormalizer));
+        normalizers
+    }
+
+    /// Normalize files with the given extension (without the dot) using
+    /// `normalizer`, replacing any earlier one.
+    pub fn add(&mut self, extension: &str, normalizer: Box<dyn ContentNormalizer>) {
+        self.by_extension.insert(extension.to_owned(), normalizer);
+    }
+
+    /// Compute a digest of a file's normalized content.
+    pub fn digest_file(&self, path: &str) -> std::io::Result<u64> {
+        let mut content = std::fs::read(path)?;
+        let extension = std::path::Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str());
+        if let Some(normalizer) = extension.and_then(|ext| self.by_extension.get(ext)) {
+            normalizer.normalize(&mut content);
+        }
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        hasher.write(&content);
+        Ok(hasher.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ar_member(name: &str, mtime: &str, data: &[u8]) -> Vec<u8> {
+        let mut member = format!(
+            "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+            name,
+            mtime,
+            "1000",
+            "1000",
+            "100644",
+            data.len()
+        )
+        .into_bytes();
+        member.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            member.push(b'\n');
+        }
+        member
+    }
+
+    fn ar(mtime: &str, data: &[u8]) -> Vec<u8> {
+        let mut archive = b"!<arch>\n".to_vec();
+        archive.extend(ar_member("a.o/", mtime, data));
+        archive.extend(ar_member("b.o/", mtime, b"bb"));
+        archive
+    }
+
+    #[test]
+    fn ar_ignores_timestamps() {
+        let mut old = ar("1600000000", b"abc");
+        let mut new = ar("1700000000", b"abc");
+        let mut changed = ar("1700000000", b"abd");
+        for archive in [&mut old, &mut new, &mut changed] {
+            ArNormalizer.normalize(archive);
+        }
+        assert_eq!(old, new);
+        assert_ne!(old, changed);
+    }
+
+    #[test]
+    fn gzip_ignores_timestamp() {
+        let header = |mtime: u8| vec![0x1f, 0x8b, 8, 0, mtime, 0, 0, 0, 0, 3, b'x'];
+        let mut old = header(1);
+        let mut new = header(2);
+        GzipNormalizer.normalize(&mut old);
+        GzipNormalizer.normalize(&mut new);
+        assert_eq!(old, new);
+        // Other files are left alone.
+        let mut other = b"not gzip data".to_vec();
+        GzipNormalizer.normalize(&mut other);
+        assert_eq!(other, b"not gzip data");
+    }
+}
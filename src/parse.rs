@@ -18,7 +18,11 @@ pub struct Rule<'text> {
 pub struct Build<'text, Path> {
     pub rule: &'text str,
     pub line: usize,
+    /// Column of the rule name, for errors about it.
+    pub rule_col: usize,
     pub outs: Vec<Path>,
+    /// Column of each of `outs`, for errors about them.
+    pub out_cols: Vec<usize>,
     pub explicit_outs: usize,
     pub ins: Vec<Path>,
     pub explicit_ins: usize,
@@ -27,6 +31,14 @@ pub struct Build<'text, Path> {
     pub vars: LazyVars,
 }
 
+/// An n2-specific directive embedded in a comment, like `# n2: key = value`.
+#[derive(Debug)]
+pub struct Pragma<'text> {
+    pub line: usize,
+    pub key: &'text str,
+    pub value: &'text str,
+}
+
 #[derive(Debug)]
 pub struct Pool<'text> {
     pub name: &'text str,
@@ -41,6 +53,32 @@ pub enum Statement<'text, Path> {
     Include(Path),
     Subninja(Path),
     Pool(Pool<'text>),
+    Pragma(Pragma<'text>),
+}
+
+/// What a token recorded in spanned parsing refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    /// The name in a `rule` statement.
+    RuleName,
+    /// The rule named by a `build` statement.
+    RuleRef,
+    /// The name in a `pool` statement.
+    PoolName,
+    /// The name of a variable being defined.
+    VarKey,
+    /// A `$var` or `${var}` reference.
+    VarRef,
+    /// A path, as written in the file before any expansion.
+    Path,
+}
+
+/// A token found by spanned parsing, as a byte range of the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub start: usize,
+    pub end: usize,
 }
 
 pub struct Parser<'text> {
@@ -49,13 +87,18 @@ pub struct Parser<'text> {
     /// Reading paths is very hot when parsing, so we always read into this buffer
     /// and then immediately pass in to Loader::path() to canonicalize it in-place.
     path_buf: String,
+    /// Tokens seen so far, if recording them; see Parser::new_spanned.
+    tokens: Option<Vec<Token>>,
+    /// Variables whose values were given from outside the file, which
+    /// top-level bindings in the file don't change.
+    overrides: &'text [(String, String)],
 }
 
-fn is_ident_char(c: u8) -> bool {
+pub fn is_ident_char(c: u8) -> bool {
     matches!(c as char, 'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '.' | '/' | ',' | '+' | '@')
 }
 
-fn is_path_char(c: u8) -> bool {
+pub fn is_path_char(c: u8) -> bool {
     // Basically any character is allowed in paths, but we want to parse e.g.
     //   build foo: bar | baz
     // such that the colon is not part of the 'foo' path and such that '|' is
@@ -69,11 +112,51 @@ pub trait Loader {
 }
 
 impl<'text> Parser<'text> {
-    pub fn new(buf: &'text mut Vec<u8>) -> Parser<'text> {
+    pub fn new(buf: &'text [u8]) -> Parser<'text> {
         Parser {
             scanner: Scanner::new(buf),
             vars: Vars::new(),
             path_buf: String::with_capacity(64),
+            tokens: None,
+            overrides: &[],
+        }
+    }
+
+    /// Set top-level variables that take precedence over the file's own
+    /// bindings of them.  Bindings within a rule or build still take
+    /// precedence over these, as they do over any top-level variable.
+    pub fn set_overrides(&mut self, overrides: &'text [(String, String)]) {
+        for (key, val) in overrides {
+            self.vars.insert(key, val.clone());
+        }
+        self.overrides = overrides;
+    }
+
+    /// Create a parser that also records the location of each name, variable
+    /// and path it reads, for tools like editors that map text back to the
+    /// build graph.  Ordinary loading doesn't need this, so it's opt-in.
+    pub fn new_spanned(buf: &'text [u8]) -> Parser<'text> {
+        let mut parser = Parser::new(buf);
+        parser.tokens = Some(Vec::new());
+        parser
+    }
+
+    /// Take the tokens recorded so far by a spanned parser, in file order.
+    /// Tokens are recorded as they end, so a path is recorded after the
+    /// variable references within it; sort them by where they start.
+    pub fn take_tokens(&mut self) -> Vec<Token> {
+        let mut tokens = self.tokens.as_mut().map(std::mem::take).unwrap_or_default();
+        tokens.sort_by_key(|token| token.start);
+        tokens
+    }
+
+    fn token(&mut self, kind: TokenKind, start: usize) {
+        if let Some(tokens) = &mut self.tokens {
+            tokens.push(Token {
+                kind,
+                start,
+                end: self.scanner.ofs,
+            });
         }
     }
 
@@ -89,10 +172,16 @@ impl<'text> Parser<'text> {
             match self.scanner.peek() {
                 '\0' => return Ok(None),
                 '\n' => self.scanner.next(),
-                '#' => self.skip_comment()?,
+                '#' => {
+                    if let Some(pragma) = self.read_comment()? {
+                        return Ok(Some(Statement::Pragma(pragma)));
+                    }
+                }
                 ' ' | '\t' => return self.scanner.parse_error("unexpected whitespace"),
                 _ => {
+                    let start = self.scanner.ofs;
                     let ident = self.read_ident()?;
+                    let end = self.scanner.ofs;
                     self.scanner.skip_spaces();
                     match ident {
                         "rule" => return Ok(Some(Statement::Rule(self.read_rule()?))),
@@ -116,8 +205,14 @@ impl<'text> Parser<'text> {
                         }
                         "pool" => return Ok(Some(Statement::Pool(self.read_pool()?))),
                         ident => {
+                            if let Some(tokens) = &mut self.tokens {
+                                let kind = TokenKind::VarKey;
+                                tokens.push(Token { kind, start, end });
+                            }
                             let val = self.read_vardef()?.evaluate(&[&self.vars]);
-                            self.vars.insert(ident, val);
+                            if !self.overrides.iter().any(|(key, _)| key == ident) {
+                                self.vars.insert(ident, val);
+                            }
                         }
                     }
                 }
@@ -125,6 +220,20 @@ impl<'text> Parser<'text> {
         }
     }
 
+    /// Parse the rest of the file, passing each statement to `f` as it is
+    /// read.  For tools that only scan a manifest, e.g. to list its rules,
+    /// without loading it into a Graph.
+    pub fn for_each_statement<L: Loader>(
+        &mut self,
+        loader: &mut L,
+        mut f: impl FnMut(Statement<'text, L::Path>),
+    ) -> ParseResult<()> {
+        while let Some(stmt) = self.read(loader)? {
+            f(stmt);
+        }
+        Ok(())
+    }
+
     fn read_vardef(&mut self) -> ParseResult<EvalString<&'text str>> {
         self.scanner.skip_spaces();
         self.scanner.expect('=')?;
@@ -136,7 +245,9 @@ impl<'text> Parser<'text> {
         let mut vars = LazyVars::new();
         while self.scanner.peek() == ' ' {
             self.scanner.skip_spaces();
+            let start = self.scanner.ofs;
             let name = self.read_ident()?;
+            self.token(TokenKind::VarKey, start);
             self.scanner.skip_spaces();
             let val = self.read_vardef()?;
             vars.insert(name.to_owned(), val.into_owned());
@@ -145,14 +256,18 @@ impl<'text> Parser<'text> {
     }
 
     fn read_rule(&mut self) -> ParseResult<Rule<'text>> {
+        let start = self.scanner.ofs;
         let name = self.read_ident()?;
+        self.token(TokenKind::RuleName, start);
         self.scanner.expect('\n')?;
         let vars = self.read_scoped_vars()?;
         Ok(Rule { name, vars })
     }
 
     fn read_pool(&mut self) -> ParseResult<Pool<'text>> {
+        let start = self.scanner.ofs;
         let name = self.read_ident()?;
+        self.token(TokenKind::PoolName, start);
         self.scanner.expect('\n')?;
         let vars = self.read_scoped_vars()?;
         let mut depth = 0;
@@ -190,20 +305,44 @@ impl<'text> Parser<'text> {
         Ok(())
     }
 
+    /// Like read_paths_to, also recording the column of each path.
+    fn read_paths_with_cols_to<L: Loader>(
+        &mut self,
+        loader: &mut L,
+        v: &mut Vec<L::Path>,
+        cols: &mut Vec<usize>,
+    ) -> ParseResult<()> {
+        self.scanner.skip_spaces();
+        loop {
+            let start = self.scanner.ofs;
+            match self.read_path(loader)? {
+                Some(path) => v.push(path),
+                None => break,
+            }
+            cols.push(self.scanner.col(start));
+            self.scanner.skip_spaces();
+        }
+        Ok(())
+    }
+
     fn read_build<L: Loader>(&mut self, loader: &mut L) -> ParseResult<Build<'text, L::Path>> {
         let line = self.scanner.line;
         let mut outs = Vec::new();
-        self.read_paths_to(loader, &mut outs)?;
+        let mut out_cols = Vec::new();
+        self.read_paths_with_cols_to(loader, &mut outs, &mut out_cols)?;
         let explicit_outs = outs.len();
 
         if self.scanner.peek() == '|' {
             self.scanner.next();
-            self.read_paths_to(loader, &mut outs)?;
+            self.read_paths_with_cols_to(loader, &mut outs, &mut out_cols)?;
         }
 
         self.scanner.expect(':')?;
         self.scanner.skip_spaces();
+        let start = self.scanner.ofs;
         let rule = self.read_ident()?;
+        self.token(TokenKind::RuleRef, start);
+        let rule_col = self.scanner.col(start);
 
         let mut ins = Vec::new();
         self.read_paths_to(loader, &mut ins)?;
@@ -231,7 +370,9 @@ impl<'text> Parser<'text> {
         Ok(Build {
             rule,
             line,
+            rule_col,
             outs,
+            out_cols,
             explicit_outs,
             ins,
             explicit_ins,
@@ -254,17 +395,31 @@ impl<'text> Parser<'text> {
         Ok(defaults)
     }
 
-    fn skip_comment(&mut self) -> ParseResult<()> {
-        loop {
+    /// Skip over a comment, returning its directive if it is an n2 pragma.
+    fn read_comment(&mut self) -> ParseResult<Option<Pragma<'text>>> {
+        let line = self.scanner.line;
+        self.scanner.expect('#')?;
+        let start = self.scanner.ofs;
+        let end = loop {
             match self.scanner.read() {
                 '\0' => {
                     self.scanner.back();
-                    return Ok(());
+                    break self.scanner.ofs;
                 }
-                '\n' => return Ok(()),
+                '\n' => break self.scanner.ofs - 1,
                 _ => {}
             }
-        }
+        };
+        let text = match self.scanner.slice(start, end).trim().strip_prefix("n2:") {
+            None => return Ok(None),
+            Some(text) => text,
+        };
+        let (key, value) = text.split_once('=').unwrap_or((text, ""));
+        Ok(Some(Pragma {
+            line,
+            key: key.trim(),
+            value: value.trim(),
+        }))
     }
 
     fn read_ident(&mut self) -> ParseResult<&'text str> {
@@ -306,6 +461,7 @@ impl<'text> Parser<'text> {
 
     fn read_path<L: Loader>(&mut self, loader: &mut L) -> ParseResult<Option<L::Path>> {
         self.path_buf.clear();
+        let start = self.scanner.ofs;
         loop {
             let c = self.scanner.read();
             if is_path_char(c as u8) {
@@ -343,11 +499,18 @@ impl<'text> Parser<'text> {
         if self.path_buf.is_empty() {
             return Ok(None);
         }
+        self.token(TokenKind::Path, start);
         Ok(Some(loader.path(&mut self.path_buf)))
     }
 
     fn read_escape(&mut self) -> ParseResult<EvalPart<&'text str>> {
         Ok(match self.scanner.read() {
+            '\0' => {
+                // Point the error at the '$'.
+                self.scanner.back();
+                self.scanner.back();
+                return self.scanner.parse_error("unexpected end of file after '$'");
+            }
             '\n' => {
                 self.scanner.skip_spaces();
                 EvalPart::Literal(self.scanner.slice(0, 0))
@@ -365,18 +528,26 @@ impl<'text> Parser<'text> {
                     }
                 }
                 let end = self.scanner.ofs - 1;
+                if let Some(tokens) = &mut self.tokens {
+                    let kind = TokenKind::VarRef;
+                    tokens.push(Token { kind, start, end });
+                }
                 EvalPart::VarRef(self.scanner.slice(start, end))
             }
             _ => {
                 self.scanner.back();
+                let start = self.scanner.ofs;
                 let ident = self.read_ident()?;
+                self.token(TokenKind::VarRef, start);
                 EvalPart::VarRef(ident)
             }
         })
     }
 }
 
-struct StringLoader {}
+/// A Loader that leaves paths as the strings found in the file, for reading a
+/// manifest without a Graph to add its files to.
+pub struct StringLoader {}
 impl Loader for StringLoader {
     type Path = String;
     fn path(&mut self, path: &mut String) -> Self::Path {
@@ -390,13 +561,12 @@ mod tests {
 
     #[test]
     fn parse_defaults() {
-        let mut buf = "
+        let buf = "
 var = 3
 default a b$var c
         "
-        .as_bytes()
-        .to_vec();
-        let mut parser = Parser::new(&mut buf);
+        .as_bytes();
+        let mut parser = Parser::new(buf);
         let default = match parser.read(&mut StringLoader {}).unwrap().unwrap() {
             Statement::Default(d) => d,
             s => panic!("expected default, got {:?}", s),
@@ -404,4 +574,99 @@ default a b$var c
         assert_eq!(default, vec!["a", "b3", "c"]);
         println!("{:?}", default);
     }
+
+    #[test]
+    fn dollar_at_eof() {
+        let buf = b"cmd = foo$";
+        let mut parser = Parser::new(buf);
+        let err = parser.read(&mut StringLoader {}).unwrap_err();
+        assert_eq!(
+            parser.format_parse_error("build.ninja", err),
+            "parse error: unexpected end of file after '$'
+build.ninja:1: cmd = foo$
+                        ^
+"
+        );
+    }
+
+    #[test]
+    fn spans() {
+        let buf = "x = 1
+rule cc
+  command = cc ${in} -o $out
+build a$ b.o: cc a.c | $x
+";
+        let mut parser = Parser::new_spanned(buf.as_bytes());
+        let mut loader = StringLoader {};
+        while parser.read(&mut loader).unwrap().is_some() {}
+        let tokens: Vec<(TokenKind, &str)> = parser
+            .take_tokens()
+            .into_iter()
+            .map(|t| (t.kind, &buf[t.start..t.end]))
+            .collect();
+        use TokenKind::*;
+        assert_eq!(
+            tokens,
+            vec![
+                (VarKey, "x"),
+                (RuleName, "cc"),
+                (VarKey, "command"),
+                (VarRef, "in"),
+                (VarRef, "out"),
+                (Path, "a$ b.o"),
+                (RuleRef, "cc"),
+                (Path, "a.c"),
+                (Path, "$x"),
+                (VarRef, "x"),
+            ]
+        );
+        assert!(Parser::new(buf.as_bytes()).take_tokens().is_empty());
+    }
+
+    #[test]
+    fn overrides() {
+        let buf = b"a = file\nb = $a\ndefault $a $b\n";
+        let overrides = [("a".to_string(), "cmdline".to_string())];
+        let mut parser = Parser::new(buf);
+        parser.set_overrides(&overrides);
+        match parser.read(&mut StringLoader {}).unwrap().unwrap() {
+            Statement::Default(d) => assert_eq!(d, vec!["cmdline", "cmdline"]),
+            s => panic!("expected default, got {:?}", s),
+        }
+    }
+
+    #[test]
+    fn for_each_statement() {
+        let buf = b"rule cc\n  command = cc\nrule ld\n  command = ld\nbuild a: cc\nbuild b: ld a\n";
+        let mut parser = Parser::new(buf);
+        let (mut rules, mut builds) = (Vec::new(), 0);
+        parser
+            .for_each_statement(&mut StringLoader {}, |stmt| match stmt {
+                Statement::Rule(rule) => rules.push(rule.name),
+                Statement::Build(_) => builds += 1,
+                _ => {}
+            })
+            .unwrap();
+        assert_eq!((rules, builds), (vec!["cc", "ld"], 2));
+    }
+
+    #[test]
+    fn pragma() {
+        let buf = b"# plain comment\n#n2: ignored_key\n# n2: key = some value \n";
+        let mut parser = Parser::new(buf);
+        let mut loader = StringLoader {};
+        match parser.read(&mut loader).unwrap() {
+            Some(Statement::Pragma(p)) => {
+                assert_eq!((p.line, p.key, p.value), (2, "ignored_key", ""))
+            }
+            _ => panic!("expected pragma"),
+        }
+        match parser.read(&mut loader).unwrap() {
+            Some(Statement::Pragma(p)) => {
+                assert_eq!((p.line, p.key, p.value), (3, "key", "some value"))
+            }
+            _ => panic!("expected pragma"),
+        }
+        assert!(parser.read(&mut loader).unwrap().is_none());
+    }
 }
@@ -2,6 +2,7 @@
 //! user.
 
 use std::collections::VecDeque;
+use std::fmt::Write as _;
 use std::io::Write;
 use std::time::Duration;
 use std::time::Instant;
@@ -56,7 +57,9 @@ pub trait Progress {
     fn update(&mut self, counts: &StateCounts);
 
     /// Called when we expect to be waiting for a while before another update.
-    fn flush(&mut self);
+    /// Returns how soon flush() should be called again, if it held back
+    /// output that is due by then.
+    fn flush(&mut self) -> Option<Duration>;
 
     /// Called when a task starts.
     /// Not called for every BuildId, just the ones that start and complete.
@@ -66,13 +69,51 @@ pub trait Progress {
     /// TODO: maybe this should just be part of task_state?
     /// In particular, consider the case where builds output progress as they run,
     /// as well as the case where multiple build steps are allowed to fail.
-    fn completed(&mut self, build: &Build, success: bool, output: &[u8]);
+    /// `exit_code` is the command's exit code, if it exited normally.
+    fn completed(
+        &mut self,
+        id: BuildId,
+        build: &Build,
+        success: bool,
+        exit_code: Option<i32>,
+        output: &[u8],
+    );
+
+    /// Called to report a warning that came up during the build.
+    fn warning(&mut self, message: &str);
 
     /// Called when the overall build has completed (success or failure), to allow
     /// cleaning up the display.
     fn finish(&mut self);
 }
 
+/// Default cap on the lines of output printed for a failed command.
+pub const DEFAULT_MAX_FAIL_OUTPUT: usize = 1000;
+
+/// Split `output` after its first `max_lines` lines, returning the part to
+/// print and the number of lines left over.
+fn split_lines(output: &[u8], max_lines: usize) -> (&[u8], usize) {
+    let end = match output
+        .iter()
+        .enumerate()
+        .filter(|&(_, &b)| b == b'\n')
+        .nth(max_lines.wrapping_sub(1))
+    {
+        Some((i, _)) if max_lines > 0 => i + 1,
+        _ => return (output, 0),
+    };
+    let rest = &output[end..];
+    let mut lines = rest.iter().filter(|&&b| b == b'\n').count();
+    if !rest.is_empty() && !rest.ends_with(b"\n") {
+        lines += 1;
+    }
+    (&output[..end], lines)
+}
+
+/// Minimum time between redraws of the progress display, to avoid flooding
+/// slow terminals when builds complete quickly.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(33);
+
 /// Currently running build task, as tracked for progress updates.
 struct Task {
     id: BuildId,
@@ -90,15 +131,32 @@ struct Task {
 pub struct ConsoleProgress {
     /// Last time we updated the console, used to throttle updates.
     last_update: Instant,
+    /// Whether anything changed since we last updated the console.
+    dirty: bool,
     /// Counts of tasks in each state.  TODO: pass this as function args?
     counts: StateCounts,
     /// Build tasks that are currently executing.
     /// Pushed to as tasks are started, so it's always in order of age.
     tasks: VecDeque<Task>,
-    /// Whether to print command lines of completed programs.
+    /// Whether to print command lines of completed programs.  Each is printed
+    /// when its command completes, in the same single write as its output, so
+    /// concurrent commands never interleave their lines.
     verbose: bool,
     /// Whether to print a progress bar and currently running tasks.
     fancy_terminal: bool,
+    /// Whether to wrap the output of completed tasks in banners naming the
+    /// command and its exit status, for --output-sync.
+    output_sync: bool,
+    /// Lines of output to print for a failed command, or 0 for no limit.
+    max_fail_output: usize,
+    /// File to append the full output of truncated failures to.
+    fail_output_file: Option<std::path::PathBuf>,
+    /// Pools whose builds' output is held back and printed together when
+    /// the build is over, with the output gathered so far for each.
+    grouped_pools: Vec<(String, Vec<u8>)>,
+    /// Whether to name the longest-running task on the status line, so it's
+    /// clear what the build is waiting on near the end.
+    show_oldest: bool,
 }
 
 #[allow(clippy::new_without_default)]
@@ -109,17 +167,65 @@ impl ConsoleProgress {
             // before our first print.  This reduces flicker in the case where
             // the work immediately completes.
             last_update: Instant::now(),
+            dirty: false,
             counts: StateCounts::new(),
             tasks: VecDeque::new(),
             verbose,
             fancy_terminal,
+            output_sync: false,
+            max_fail_output: DEFAULT_MAX_FAIL_OUTPUT,
+            fail_output_file: None,
+            grouped_pools: Vec::new(),
+            show_oldest: false,
+        }
+    }
+
+    pub fn set_show_oldest(&mut self, show_oldest: bool) {
+        self.show_oldest = show_oldest;
+    }
+
+    pub fn set_output_sync(&mut self, output_sync: bool) {
+        self.output_sync = output_sync;
+    }
+
+    /// Limit the output printed for a failed command to `max_lines` lines
+    /// (0 for no limit), optionally saving the full output to `file`.
+    pub fn set_max_fail_output(&mut self, max_lines: usize, file: Option<std::path::PathBuf>) {
+        self.max_fail_output = max_lines;
+        self.fail_output_file = file;
+    }
+
+    /// Hold back the output of builds in `pools` until the build is over,
+    /// then print it grouped by pool, so that e.g. test logs aren't mixed in
+    /// with compiler output.
+    pub fn set_grouped_pools(&mut self, pools: Vec<String>) {
+        self.grouped_pools = pools.into_iter().map(|pool| (pool, Vec::new())).collect();
+    }
+
+    /// Append a failed command's full output to the fail output file.
+    fn save_fail_output(&self, build: &Build, output: &[u8]) -> std::io::Result<()> {
+        let path = match &self.fail_output_file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let mut buf = Vec::new();
+        writeln!(buf, "---- {}", build.cmdline.as_ref().unwrap())?;
+        buf.extend_from_slice(output);
+        if !output.ends_with(b"\n") {
+            buf.push(b'\n');
         }
+        file.write_all(&buf)
     }
 }
 
 impl Progress for ConsoleProgress {
     fn update(&mut self, counts: &StateCounts) {
         self.counts = counts.clone();
+        self.dirty = true;
         self.maybe_print_progress();
     }
 
@@ -133,20 +239,38 @@ impl Progress for ConsoleProgress {
                     message: message.to_string(),
                 });
             }
-            BuildState::Done => {
+            BuildState::Done | BuildState::Failed => {
                 self.tasks
                     .remove(self.tasks.iter().position(|t| t.id == id).unwrap());
             }
             _ => {}
         }
+        self.dirty = true;
         self.maybe_print_progress();
     }
 
-    fn flush(&mut self) {
-        self.print_progress();
+    fn flush(&mut self) -> Option<Duration> {
+        // Redraw even if nothing changed once in a while, to keep the running
+        // times of tasks current.
+        let elapsed = self.last_update.elapsed();
+        if elapsed >= Duration::from_secs(1) || (self.dirty && elapsed >= REDRAW_INTERVAL) {
+            self.redraw();
+        } else if self.dirty {
+            // Redrawn too recently; have the caller come back when the
+            // pending redraw is allowed.
+            return Some(REDRAW_INTERVAL - elapsed);
+        }
+        None
     }
 
-    fn completed(&mut self, build: &Build, success: bool, output: &[u8]) {
+    fn completed(
+        &mut self,
+        _id: BuildId,
+        build: &Build,
+        success: bool,
+        exit_code: Option<i32>,
+        output: &[u8],
+    ) {
         // By default we don't want to print anything when a task completes,
         // but we do want to print the completed task when:
         // - failed tasks
@@ -159,21 +283,93 @@ impl Progress for ConsoleProgress {
         } else {
             build_message(build)
         };
-        if !success {
-            self.clear_progress();
-            println!("failed: {}", message);
-        } else if self.verbose || !self.fancy_terminal || !output.is_empty() {
-            self.clear_progress();
-            println!("{}", message);
+        // Cap the output of failures, which can be huge, e.g. for template
+        // errors; the full output can be kept in a file instead.
+        let mut truncated = None;
+        let output = if success {
+            output
+        } else {
+            let (head, more) = split_lines(output, self.max_fail_output);
+            if more > 0 {
+                let mut marker = format!("... (truncated, {} more lines", more);
+                if let Some(path) = &self.fail_output_file {
+                    match self.save_fail_output(build, output) {
+                        Ok(()) => write!(marker, "; full output in {}", path.display()).unwrap(),
+                        Err(err) => write!(marker, "; write {}: {}", path.display(), err).unwrap(),
+                    }
+                }
+                marker.push_str(")\n");
+                truncated = Some(marker);
+            }
+            head
+        };
+
+        // Gather everything to print into one buffer so it is written in one
+        // go, rather than interleaving with anything else printing.
+        let mut buf = Vec::new();
+        if self.output_sync && (!success || !output.is_empty()) {
+            writeln!(buf, "---- begin: {}", build.cmdline.as_ref().unwrap()).unwrap();
+            buf.extend_from_slice(output);
+            if !output.is_empty() && !output.ends_with(b"\n") {
+                buf.push(b'\n');
+            }
+            if let Some(marker) = &truncated {
+                buf.extend_from_slice(marker.as_bytes());
+            }
+            match exit_code {
+                Some(code) => writeln!(buf, "---- end: exit status {}", code).unwrap(),
+                None if success => writeln!(buf, "---- end: ok").unwrap(),
+                None => writeln!(buf, "---- end: failed").unwrap(),
+            }
+        } else {
+            if !success {
+                writeln!(buf, "failed: {}", message).unwrap();
+            } else if self.verbose || !self.fancy_terminal || !output.is_empty() {
+                writeln!(buf, "{}", message).unwrap();
+            }
+            buf.extend_from_slice(output);
+            if let Some(marker) = &truncated {
+                buf.extend_from_slice(marker.as_bytes());
+            }
         }
 
-        if !output.is_empty() {
-            std::io::stdout().write_all(output).unwrap();
+        if let Some((_, group)) = self
+            .grouped_pools
+            .iter_mut()
+            .find(|(pool, _)| build.pool.as_deref() == Some(pool.as_str()))
+        {
+            group.append(&mut buf);
+            return;
         }
+
+        if !buf.is_empty() {
+            self.clear_progress();
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(&buf).unwrap();
+            stdout.flush().unwrap();
+        }
+    }
+
+    fn warning(&mut self, message: &str) {
+        self.clear_progress();
+        let mut stdout = std::io::stdout().lock();
+        writeln!(stdout, "n2: warning: {}", message).unwrap();
+        stdout.flush().unwrap();
+        self.dirty = true;
     }
 
     fn finish(&mut self) {
         self.clear_progress();
+        let mut stdout = std::io::stdout().lock();
+        for (pool, group) in &mut self.grouped_pools {
+            if group.is_empty() {
+                continue;
+            }
+            writeln!(stdout, "---- output of pool {}", pool).unwrap();
+            stdout.write_all(group).unwrap();
+            group.clear();
+        }
+        stdout.flush().unwrap();
     }
 }
 
@@ -184,7 +380,7 @@ impl ConsoleProgress {
         let mut sum: usize = 0;
         let total = self.counts.total();
         for (count, ch) in [
-            (self.counts.get(BuildState::Done), '='),
+            (self.counts.finished(), '='),
             (
                 self.counts.get(BuildState::Queued)
                     + self.counts.get(BuildState::Running)
@@ -201,6 +397,43 @@ impl ConsoleProgress {
         bar
     }
 
+    /// The counts part of the status line.
+    fn status(&self) -> String {
+        let mut status = format!(
+            "{}/{} done, {}/{} running",
+            self.counts.get(BuildState::Done),
+            self.counts.total(),
+            self.tasks.len(),
+            self.counts.get(BuildState::Queued) + self.tasks.len(),
+        );
+        let failed = self.counts.get(BuildState::Failed);
+        if failed > 0 {
+            write!(
+                status,
+                ", {} failed, {} skipped",
+                failed,
+                self.counts.get(BuildState::Skipped)
+            )
+            .unwrap();
+        }
+        let unchecked = self.counts.unchecked();
+        if unchecked > 0 {
+            write!(status, ", checking {} edges", unchecked).unwrap();
+        }
+        if self.show_oldest {
+            if let Some(task) = self.tasks.front() {
+                write!(
+                    status,
+                    ", oldest: {}s {}",
+                    task.start.elapsed().as_secs(),
+                    task.message
+                )
+                .unwrap();
+            }
+        }
+        status
+    }
+
     fn clear_progress(&self) {
         if !self.fancy_terminal {
             return;
@@ -214,46 +447,104 @@ impl ConsoleProgress {
         if !self.fancy_terminal {
             return;
         }
-        self.clear_progress();
-        println!(
-            "[{}] {}/{} done, {}/{} running",
-            self.progress_bar(),
-            self.counts.get(BuildState::Done),
-            self.counts.total(),
-            self.tasks.len(),
-            self.counts.get(BuildState::Queued) + self.tasks.len(),
-        );
-
+        // Build up the whole display and write it at once, rather than line
+        // by line.
+        let mut out = String::new();
+        // If the user hit ctl-c, it may have printed something on the line.
+        // So \r to go to first column first, then clear anything below.
+        out.push_str("\r\x1b[J");
         let max_cols = get_terminal_cols().unwrap_or(80);
+        let status = format!("[{}] {}", self.progress_bar(), self.status());
+        // A long command named on the status line would wrap, throwing off
+        // the cursor movement below.
+        match status.char_indices().nth(max_cols.saturating_sub(4)) {
+            Some((end, _)) => writeln!(out, "{}...", &status[..end]).unwrap(),
+            None => writeln!(out, "{}", status).unwrap(),
+        }
+
         let mut lines = 1;
         let max_lines = 8;
         let now = Instant::now();
         for task in self.tasks.iter().take(max_lines) {
             if lines == max_lines && self.tasks.len() > max_lines {
-                println!("...and {} more", self.tasks.len() - max_lines + 1);
+                writeln!(out, "...and {} more", self.tasks.len() - max_lines + 1).unwrap();
             } else {
                 let delta = now.duration_since(task.start).as_secs();
                 let line = format!("{}s {}", delta, task.message);
                 if line.len() >= max_cols {
-                    println!("{}...", &line[0..max_cols - 4]);
+                    writeln!(out, "{}...", &line[0..max_cols - 4]).unwrap();
                 } else {
-                    println!("{}", line);
+                    writeln!(out, "{}", line).unwrap();
                 }
             }
             lines += 1;
         }
 
         // Move cursor up to the first printed line, for overprinting.
-        print!("\x1b[{}A", lines);
+        write!(out, "\x1b[{}A", lines).unwrap();
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(out.as_bytes()).unwrap();
+        stdout.flush().unwrap();
     }
 
+    /// Redraw the progress display, unless it was redrawn very recently.
+    /// Skipped updates are picked up by a later call.
     fn maybe_print_progress(&mut self) {
-        let now = Instant::now();
-        let delta = now.duration_since(self.last_update);
-        if delta < Duration::from_millis(50) {
+        if self.last_update.elapsed() < REDRAW_INTERVAL {
             return;
         }
+        self.redraw();
+    }
+
+    /// Redraw the progress display now.
+    fn redraw(&mut self) {
         self.print_progress();
-        self.last_update = now;
+        self.last_update = Instant::now();
+        self.dirty = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_output_lines() {
+        let output = b"a\nb\nc\nd";
+        assert_eq!(split_lines(output, 0), (&output[..], 0));
+        assert_eq!(split_lines(output, 2), (&b"a\nb\n"[..], 2));
+        assert_eq!(split_lines(output, 3), (&b"a\nb\nc\n"[..], 1));
+        assert_eq!(split_lines(output, 4), (&output[..], 0));
+        assert_eq!(split_lines(b"a\nb\n", 1), (&b"a\n"[..], 1));
+        assert_eq!(split_lines(b"a\nb\n", 2), (&b"a\nb\n"[..], 0));
+    }
+
+    #[test]
+    fn status_names_oldest_task() {
+        let mut progress = ConsoleProgress::new(false, true);
+        let now = Instant::now();
+        for (id, age, message) in [(0, 90, "link app"), (1, 2, "cc foo.c")] {
+            progress.tasks.push_back(Task {
+                id: BuildId::from(id),
+                start: now - Duration::from_secs(age),
+                message: message.to_string(),
+            });
+        }
+        assert!(!progress.status().contains("oldest"));
+        progress.set_show_oldest(true);
+        assert!(progress.status().ends_with(", oldest: 90s link app"));
+    }
+
+    #[test]
+    fn flush_is_rate_limited() {
+        let mut progress = ConsoleProgress::new(false, false);
+        progress.dirty = true;
+        let wait = progress.flush().unwrap();
+        assert!(wait <= REDRAW_INTERVAL);
+        assert!(progress.dirty);
+
+        progress.last_update -= REDRAW_INTERVAL;
+        assert_eq!(progress.flush(), None);
+        assert!(!progress.dirty);
     }
 }
@@ -7,33 +7,62 @@ pub struct ParseError {
 }
 pub type ParseResult<T> = Result<T, ParseError>;
 
+/// Scanner over a byte buffer.  The buffer need not be NUL-terminated (and
+/// is never modified); reading at the end of the buffer yields '\0'.
 pub struct Scanner<'a> {
     buf: &'a [u8],
     pub ofs: usize,
     pub line: usize,
+    /// Offset of the start of the current line, for col().
+    line_start: usize,
 }
 
 impl<'a> Scanner<'a> {
-    pub fn new(buf: &'a mut Vec<u8>) -> Self {
-        buf.push(0);
+    pub fn new(buf: &'a [u8]) -> Self {
         Scanner {
             buf,
             ofs: 0,
             line: 1,
+            line_start: 0,
         }
     }
 
+    /// The offset of the start of the line containing offset `ofs`.
+    fn find_line_start(&self, ofs: usize) -> usize {
+        match self.buf[..ofs].iter().rposition(|&c| c == b'\n') {
+            Some(newline) => newline + 1,
+            None => 0,
+        }
+    }
+
+    /// The 1-based column, in bytes, of offset `ofs` within its line.  This
+    /// is cheap for offsets already scanned on the current line.
+    pub fn col(&self, ofs: usize) -> usize {
+        let line_start = if (self.line_start..=self.ofs).contains(&ofs) {
+            self.line_start
+        } else {
+            self.find_line_start(ofs)
+        };
+        ofs - line_start + 1
+    }
+
     pub fn slice(&self, start: usize, end: usize) -> &'a str {
         unsafe { std::str::from_utf8_unchecked(self.buf.get_unchecked(start..end)) }
     }
+    /// Returns the character at the current offset.  The end of the buffer
+    /// reads as a '\0', without the buffer needing to contain one.
     pub fn peek(&self) -> char {
+        if self.ofs >= self.buf.len() {
+            return '\0';
+        }
         unsafe { *self.buf.get_unchecked(self.ofs) as char }
     }
     pub fn next(&mut self) {
         if self.peek() == '\n' {
             self.line += 1;
+            self.line_start = self.ofs + 1;
         }
-        if self.ofs == self.buf.len() {
+        if self.ofs > self.buf.len() {
             panic!("scanned past end")
         }
         self.ofs += 1;
@@ -45,6 +74,7 @@ impl<'a> Scanner<'a> {
         self.ofs -= 1;
         if self.peek() == '\n' {
             self.line -= 1;
+            self.line_start = self.find_line_start(self.ofs);
         }
     }
     pub fn read(&mut self) -> char {
@@ -92,20 +122,23 @@ impl<'a> Scanner<'a> {
                 let prefix = format!("{}:{}: ", filename, line_number + 1);
                 msg.push_str(&prefix);
 
-                let mut context = unsafe { std::str::from_utf8_unchecked(line) };
-                let mut col = err.ofs - ofs;
+                // Window the line in chars rather than bytes, so trimming
+                // never splits a multibyte character.
+                let mut context: Vec<char> = String::from_utf8_lossy(line).chars().collect();
+                let mut col = String::from_utf8_lossy(&line[..err.ofs - ofs])
+                    .chars()
+                    .count();
                 if col > 40 {
                     // Trim beginning of line to fit it on screen.
                     msg.push_str("...");
-                    context = &context[col - 20..];
+                    context.drain(..col - 20);
                     col = 3 + 20;
                 }
                 if context.len() > 40 {
-                    context = &context[0..40];
-                    msg.push_str(context);
+                    msg.extend(&context[0..40]);
                     msg.push_str("...");
                 } else {
-                    msg.push_str(context);
+                    msg.extend(&context);
                 }
                 msg.push('\n');
 
@@ -118,3 +151,82 @@ impl<'a> Scanner<'a> {
         panic!("invalid offset when formatting error")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn col() {
+        let scanner = Scanner::new(b"ab\ncd");
+        assert_eq!(scanner.col(0), 1);
+        assert_eq!(scanner.col(1), 2);
+        assert_eq!(scanner.col(3), 1);
+        assert_eq!(scanner.col(4), 2);
+
+        let mut scanner = Scanner::new(b"ab\ncd");
+        while scanner.read() != 'd' {}
+        assert_eq!(scanner.col(4), 2);
+        assert_eq!(scanner.col(1), 2);
+        scanner.back();
+        scanner.back();
+        scanner.back();
+        assert_eq!(scanner.col(2), 3);
+    }
+
+    #[test]
+    fn eof_without_nul() {
+        let buf = b"ab";
+        let mut scanner = Scanner::new(buf);
+        assert_eq!(scanner.read(), 'a');
+        assert_eq!(scanner.read(), 'b');
+        assert_eq!(scanner.peek(), '\0');
+        assert_eq!(scanner.read(), '\0');
+        scanner.back();
+        assert_eq!(scanner.read(), '\0');
+    }
+
+    #[test]
+    fn empty() {
+        let mut scanner = Scanner::new(b"");
+        assert_eq!(scanner.read(), '\0');
+    }
+
+    #[test]
+    #[should_panic(expected = "scanned past end")]
+    fn past_end() {
+        let mut scanner = Scanner::new(b"");
+        scanner.read();
+        scanner.read();
+    }
+
+    #[test]
+    fn error_at_eof() {
+        let mut scanner = Scanner::new(b"foo\nbar");
+        while scanner.read() != '\0' {}
+        scanner.back();
+        let err = scanner.parse_error::<(), _>("eof").unwrap_err();
+        assert_eq!(
+            scanner.format_parse_error("test", err),
+            "parse error: eof\ntest:2: bar\n           ^\n"
+        );
+    }
+
+    #[test]
+    fn error_in_long_multibyte_line() {
+        let line = "é".repeat(3000) + "x" + &"ü".repeat(3000);
+        let mut scanner = Scanner::new(line.as_bytes());
+        while scanner.read() != 'x' {}
+        scanner.back();
+        let err = scanner.parse_error::<(), _>("bad").unwrap_err();
+        assert_eq!(
+            scanner.format_parse_error("test", err),
+            format!(
+                "parse error: bad\ntest:1: ...{}x{}...\n{}^\n",
+                "é".repeat(20),
+                "ü".repeat(19),
+                " ".repeat(8 + 23)
+            )
+        );
+    }
+}
@@ -4,9 +4,20 @@
 //! and let the parent properly print that progress.  This also lets us still
 //! write out pending debug traces, too.
 
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set once a SIGINT has been received.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
 #[cfg(unix)]
 extern "C" fn sigint_handler(_sig: libc::c_int) {
-    // Do nothing; SA_RESETHAND should clear the handler.
+    // Just note the interrupt; SA_RESETHAND should clear the handler.
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Whether the build has been interrupted, e.g. by Ctrl-C.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
 }
 
 #[cfg(unix)]
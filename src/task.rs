@@ -9,6 +9,8 @@ use crate::depfile;
 use crate::graph::{BuildId, RspFile};
 use crate::scanner::Scanner;
 use anyhow::{anyhow, bail};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::time::{Duration, Instant};
 
@@ -36,18 +38,127 @@ pub struct FinishedTask {
 /// The result of executing a build step.
 pub struct TaskResult {
     pub success: bool,
+    /// Exit code of the command, if it ran and exited normally.
+    pub exit_code: Option<i32>,
     /// Console output.
     pub output: Vec<u8>,
     pub discovered_deps: Option<Vec<String>>,
+    /// Resources used by the command, where the platform reports them.
+    pub usage: Option<ResourceUsage>,
+}
+
+/// Resources used by a finished command.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ResourceUsage {
+    /// Peak resident set size, in bytes.
+    pub max_rss: u64,
+    /// User plus system CPU time.
+    pub cpu_time: Duration,
+}
+
+#[cfg(unix)]
+impl ResourceUsage {
+    fn from_rusage(usage: &libc::rusage) -> Self {
+        let time = |t: libc::timeval| {
+            Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+        };
+        // ru_maxrss is in bytes on macOS, and in kilobytes elsewhere.
+        let max_rss = usage.ru_maxrss as u64;
+        let max_rss = if cfg!(target_os = "macos") {
+            max_rss
+        } else {
+            max_rss * 1024
+        };
+        ResourceUsage {
+            max_rss,
+            cpu_time: time(usage.ru_utime) + time(usage.ru_stime),
+        }
+    }
+}
+
+/// A directory holding only a build's declared inputs, to run its command in
+/// so that reading an undeclared input fails.  Inputs are linked (or on
+/// Windows, copied) in before the command runs, and declared outputs are moved
+/// out after it succeeds.
+pub struct Sandbox {
+    pub dir: PathBuf,
+    pub ins: Vec<String>,
+    pub outs: Vec<String>,
+}
+
+impl Sandbox {
+    /// The path of a build file within the sandbox, or None for an absolute
+    /// path, which the command can use as is.
+    fn path(&self, name: &str) -> anyhow::Result<Option<PathBuf>> {
+        let path = Path::new(name);
+        if path.is_absolute() {
+            return Ok(None);
+        }
+        if path
+            .components()
+            .any(|c| c == std::path::Component::ParentDir)
+        {
+            bail!("sandbox: can't stage {}, outside the build directory", name);
+        }
+        Ok(Some(self.dir.join(path)))
+    }
+
+    fn stage(&self) -> anyhow::Result<()> {
+        // Remove any sandbox left behind by an earlier interrupted run.
+        let _ = std::fs::remove_dir_all(&self.dir);
+        std::fs::create_dir_all(&self.dir)?;
+        let top = std::env::current_dir()?;
+        for name in &self.ins {
+            let path = match self.path(name)? {
+                Some(path) => path,
+                None => continue,
+            };
+            let src = top.join(name);
+            // Phony inputs name no file.
+            if !src.exists() {
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            #[cfg(unix)]
+            let staged = std::os::unix::fs::symlink(&src, &path);
+            #[cfg(windows)]
+            let staged = std::fs::copy(&src, &path).map(|_| ());
+            staged.map_err(|err| anyhow!("sandbox: stage {}: {}", name, err))?;
+        }
+        for name in &self.outs {
+            if let Some(parent) = self.path(name)?.as_deref().and_then(Path::parent) {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn collect(&self) -> anyhow::Result<()> {
+        for name in &self.outs {
+            let path = match self.path(name)? {
+                Some(path) => path,
+                None => continue,
+            };
+            match std::fs::rename(&path, name) {
+                Ok(()) => {}
+                // Not every command writes all its outputs.
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => bail!("sandbox: collect {}: {}", name, err),
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Reads dependencies from a .d file path.
 fn read_depfile(path: &str) -> anyhow::Result<Vec<String>> {
-    let mut bytes = match std::fs::read(path) {
+    let bytes = match std::fs::read(path) {
         Ok(b) => b,
         Err(e) => bail!("read {}: {}", path, e),
     };
-    let mut scanner = Scanner::new(&mut bytes);
+    let mut scanner = Scanner::new(&bytes);
     let parsed_deps = depfile::parse(&mut scanner)
         .map_err(|err| anyhow!(scanner.format_parse_error(path, err)))?;
     // TODO verify deps refers to correct output
@@ -59,28 +170,95 @@ fn read_depfile(path: &str) -> anyhow::Result<Vec<String>> {
     Ok(deps)
 }
 
-fn write_rspfile(rspfile: &RspFile) -> anyhow::Result<()> {
-    if let Some(parent) = rspfile.path.parent() {
+fn write_rspfile(path: &Path, rspfile: &RspFile) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
         std::fs::create_dir_all(parent)?;
     }
-    std::fs::write(&rspfile.path, &rspfile.content)?;
+    retry_out_of_fds(|| std::fs::write(path, &rspfile.content))?;
     Ok(())
 }
 
+/// Number of tasks waiting for file descriptors to free up before they can
+/// start; no more tasks are started while this is nonzero.
+static WAITING_FOR_FDS: AtomicUsize = AtomicUsize::new(0);
+
+/// Longest a task waits for file descriptors before giving up.
+const MAX_FD_WAIT: Duration = Duration::from_secs(10);
+
+/// Whether an error means the process or system ran out of file descriptors.
+fn is_out_of_fds(err: &std::io::Error) -> bool {
+    #[cfg(unix)]
+    let codes = [libc::EMFILE, libc::ENFILE];
+    // ERROR_TOO_MANY_OPEN_FILES.
+    #[cfg(windows)]
+    let codes = [4];
+    matches!(err.raw_os_error(), Some(code) if codes.contains(&code))
+}
+
+/// Run `f`, and if it fails for lack of file descriptors, wait with
+/// increasing delays for running tasks to finish and release theirs, then
+/// try again.  Meanwhile no new tasks start, so a build at a -j too high
+/// for the descriptor limit slows down rather than failing.
+fn retry_out_of_fds<T>(mut f: impl FnMut() -> std::io::Result<T>) -> anyhow::Result<T> {
+    let mut delay = Duration::from_millis(10);
+    let mut waited = Duration::ZERO;
+    let result = loop {
+        match f() {
+            Err(err) if is_out_of_fds(&err) && waited < MAX_FD_WAIT => {
+                if waited.is_zero() {
+                    WAITING_FOR_FDS.fetch_add(1, Ordering::SeqCst);
+                }
+                std::thread::sleep(delay);
+                waited += delay;
+                delay = (delay * 2).min(Duration::from_secs(1));
+            }
+            result => break result,
+        }
+    };
+    if !waited.is_zero() {
+        WAITING_FOR_FDS.fetch_sub(1, Ordering::SeqCst);
+    }
+    result.map_err(|err| {
+        if is_out_of_fds(&err) {
+            anyhow!(
+                "{}; raise the open file limit (e.g. with `ulimit -n`) or lower -j",
+                err
+            )
+        } else {
+            err.into()
+        }
+    })
+}
+
 /// Executes a build task as a subprocess.
 /// Returns an Err() if we failed outside of the process itself.
 fn run_task(
     cmdline: &str,
+    env: &[(String, String)],
     depfile: Option<&str>,
     rspfile: Option<&RspFile>,
+    sandbox: Option<&Sandbox>,
 ) -> anyhow::Result<TaskResult> {
+    let dir = sandbox.map(|sandbox| sandbox.dir.as_path());
+    // Paths the command reads or writes are relative to where it runs.
+    let in_dir = |path: &Path| match dir {
+        Some(dir) => dir.join(path),
+        None => path.to_path_buf(),
+    };
+    if let Some(sandbox) = sandbox {
+        sandbox.stage()?;
+    }
     if let Some(rspfile) = rspfile {
-        write_rspfile(rspfile)?;
+        write_rspfile(&in_dir(&rspfile.path), rspfile)?;
     }
-    let mut result = run_command(cmdline)?;
+    let mut result = run_command(cmdline, env, dir)?;
     if result.success {
         if let Some(depfile) = depfile {
-            result.discovered_deps = Some(read_depfile(depfile)?);
+            let path = in_dir(Path::new(depfile));
+            result.discovered_deps = Some(read_depfile(&path.to_string_lossy())?);
+        }
+        if let Some(sandbox) = sandbox {
+            sandbox.collect()?;
         }
     }
     Ok(result)
@@ -92,25 +270,43 @@ lazy_static! {
 }
 
 #[cfg(unix)]
-fn run_command(cmdline: &str) -> anyhow::Result<TaskResult> {
-    // Command::spawn() can leak FSs when run concurrently, see #14.
-    let just_one = TASK_MUTEX.lock().unwrap();
-    let p = std::process::Command::new("/bin/sh")
-        .arg("-c")
+fn run_command(
+    cmdline: &str,
+    env: &[(String, String)],
+    dir: Option<&Path>,
+) -> anyhow::Result<TaskResult> {
+    let mut cmd = std::process::Command::new("/bin/sh");
+    cmd.arg("-c")
         .arg(cmdline)
+        .envs(env.iter().map(|(key, val)| (key, val)))
         .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()?;
-    drop(just_one);
-
-    let mut cmd = p.wait_with_output()?;
+        .stderr(std::process::Stdio::piped());
+    if let Some(dir) = dir {
+        cmd.current_dir(dir);
+    }
+    let mut p = retry_out_of_fds(|| {
+        // Command::spawn() can leak FSs when run concurrently, see #14.
+        let _just_one = TASK_MUTEX.lock().unwrap();
+        cmd.spawn()
+    })?;
+
+    // Read the output ourselves rather than with wait_with_output(), so the
+    // child can be reaped with wait4() to get its resource usage.
+    let mut stderr = p.stderr.take().unwrap();
+    let stderr = std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        std::io::Read::read_to_end(&mut stderr, &mut buf).map(|_| buf)
+    });
     let mut output = Vec::new();
-    output.append(&mut cmd.stdout);
-    output.append(&mut cmd.stderr);
-    let success = cmd.status.success();
+    std::io::Read::read_to_end(p.stdout.as_mut().unwrap(), &mut output)?;
+    output.append(&mut stderr.join().unwrap()?);
+
+    let (status, usage) = wait_with_usage(&mut p)?;
+    let success = status.success();
+    let exit_code = status.code();
 
     if !success {
-        if let Some(sig) = cmd.status.signal() {
+        if let Some(sig) = status.signal() {
             match sig {
                 libc::SIGINT => write!(output, "interrupted").unwrap(),
                 _ => write!(output, "signal {}", sig).unwrap(),
@@ -120,11 +316,34 @@ fn run_command(cmdline: &str) -> anyhow::Result<TaskResult> {
 
     Ok(TaskResult {
         success,
+        exit_code,
         output,
         discovered_deps: None,
+        usage,
     })
 }
 
+/// Reap a child, along with its resource usage if available.
+#[cfg(unix)]
+fn wait_with_usage(
+    child: &mut std::process::Child,
+) -> std::io::Result<(std::process::ExitStatus, Option<ResourceUsage>)> {
+    let mut status = 0;
+    // Safety: rusage is plain old data, filled in by wait4().
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        let pid = unsafe { libc::wait4(child.id() as libc::pid_t, &mut status, 0, &mut usage) };
+        if pid >= 0 {
+            let status = std::process::ExitStatus::from_raw(status);
+            return Ok((status, Some(ResourceUsage::from_rusage(&usage))));
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
 #[cfg(windows)]
 fn zeroed_startupinfo() -> winapi::um::processthreadsapi::STARTUPINFOA {
     winapi::um::processthreadsapi::STARTUPINFOA {
@@ -159,8 +378,26 @@ fn zeroed_process_information() -> winapi::um::processthreadsapi::PROCESS_INFORM
     }
 }
 
+/// Build a CreateProcess environment block: the current environment with
+/// `env` applied, as NUL-terminated "KEY=VALUE" strings ending in an extra NUL.
+#[cfg(windows)]
+fn environment_block(env: &[(String, String)]) -> Vec<u8> {
+    let mut vars: std::collections::BTreeMap<String, String> = std::env::vars().collect();
+    vars.extend(env.iter().cloned());
+    let mut block = Vec::new();
+    for (key, val) in vars {
+        block.extend_from_slice(format!("{}={}\0", key, val).as_bytes());
+    }
+    block.push(0);
+    block
+}
+
 #[cfg(windows)]
-fn run_command(cmdline: &str) -> anyhow::Result<TaskResult> {
+fn run_command(
+    cmdline: &str,
+    env: &[(String, String)],
+    dir: Option<&Path>,
+) -> anyhow::Result<TaskResult> {
     // Don't want to run `cmd /c` since that limits cmd line length to 8192 bytes.
     // std::process::Command can't take a string and pass it through to CreateProcess unchanged,
     // so call that ourselves.
@@ -175,6 +412,21 @@ fn run_command(cmdline: &str) -> anyhow::Result<TaskResult> {
     let mut process_info = zeroed_process_information();
 
     let mut mut_cmdline = cmdline.to_string() + "\0";
+    // A null environment means the child inherits ours.
+    let mut env_block = if env.is_empty() {
+        None
+    } else {
+        Some(environment_block(env))
+    };
+    let env_ptr = match &mut env_block {
+        Some(block) => block.as_mut_ptr() as *mut winapi::ctypes::c_void,
+        None => std::ptr::null_mut(),
+    };
+    let dir = dir.map(|dir| dir.to_string_lossy().into_owned() + "\0");
+    let dir_ptr = match &dir {
+        Some(dir) => dir.as_ptr() as *const i8,
+        None => std::ptr::null(),
+    };
 
     let create_process_success = unsafe {
         winapi::um::processthreadsapi::CreateProcessA(
@@ -184,8 +436,8 @@ fn run_command(cmdline: &str) -> anyhow::Result<TaskResult> {
             std::ptr::null_mut(),
             /*inherit handles = */ winapi::shared::ntdef::TRUE.into(),
             process_flags,
-            std::ptr::null_mut(),
-            std::ptr::null_mut(),
+            env_ptr,
+            dir_ptr,
             &mut startup_info,
             &mut process_info,
         )
@@ -224,8 +476,11 @@ fn run_command(cmdline: &str) -> anyhow::Result<TaskResult> {
 
     Ok(TaskResult {
         success,
+        exit_code: Some(exit_code as i32),
         output,
         discovered_deps: None,
+        // TODO: resource usage via job object accounting.
+        usage: None,
     })
 }
 
@@ -280,7 +535,7 @@ impl Runner {
     }
 
     pub fn can_start_more(&self) -> bool {
-        self.running < self.parallelism
+        self.running < self.parallelism && WAITING_FOR_FDS.load(Ordering::SeqCst) == 0
     }
 
     pub fn is_running(&self) -> bool {
@@ -291,21 +546,32 @@ impl Runner {
         &mut self,
         id: BuildId,
         cmdline: String,
+        env: Vec<(String, String)>,
         depfile: Option<String>,
         rspfile: Option<RspFile>,
+        sandbox: Option<Sandbox>,
     ) {
         let tid = self.tids.claim();
         let tx = self.finished_send.clone();
         std::thread::spawn(move || {
             let start = Instant::now();
-            let result =
-                run_task(&cmdline, depfile.as_deref(), rspfile.as_ref()).unwrap_or_else(|err| {
-                    TaskResult {
-                        success: false,
-                        output: err.to_string().into_bytes(),
-                        discovered_deps: None,
-                    }
-                });
+            let result = run_task(
+                &cmdline,
+                &env,
+                depfile.as_deref(),
+                rspfile.as_ref(),
+                sandbox.as_ref(),
+            );
+            if let Some(sandbox) = &sandbox {
+                let _ = std::fs::remove_dir_all(&sandbox.dir);
+            }
+            let result = result.unwrap_or_else(|err| TaskResult {
+                success: false,
+                exit_code: None,
+                output: err.to_string().into_bytes(),
+                discovered_deps: None,
+                usage: None,
+            });
             let finish = Instant::now();
 
             let task = FinishedTask {
@@ -1,19 +1,35 @@
 //! Build runner, choosing and executing tasks as determined by out of date inputs.
 
+use crate::cache::Cache;
 use crate::db;
-use crate::densemap::DenseMap;
+use crate::densemap::{DenseMap, Index};
 use crate::graph::*;
+use crate::normalize::Normalizers;
 use crate::progress;
 use crate::progress::Progress;
 use crate::task;
 use crate::trace;
-use std::collections::HashSet;
 use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet};
 use std::time::Duration;
+use std::time::Instant;
 
-#[cfg(unix)]
 use crate::signal;
 
+/// How a Work::run() ended.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RunResult {
+    /// The wanted files are up to date, after running this many tasks.
+    Success(usize),
+    /// A build failed.
+    Failed,
+    /// The build was interrupted, e.g. by Ctrl-C, failing the running tasks.
+    Interrupted,
+    /// The build stopped short after running this many tasks, as limited by
+    /// set_stop_after().
+    Stopped(usize),
+}
+
 /// Build steps go through this sequence of states.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum BuildState {
@@ -38,16 +54,20 @@ pub enum BuildState {
     Running,
     /// Finished executing.
     Done,
+    /// Executed and failed, with -k letting the build go on.
+    Failed,
+    /// Can't run, because something it depends on failed.
+    Skipped,
 }
 
 // Counters that track number of builds in each state.
 // Only covers builds not in the "unknown" state, which means it's only builds
 // that are considered part of the current build.
 #[derive(Clone, Debug)]
-pub struct StateCounts([usize; 5]);
+pub struct StateCounts([usize; 7]);
 impl StateCounts {
     pub fn new() -> Self {
-        StateCounts([0; 5])
+        StateCounts([0; 7])
     }
     fn idx(state: BuildState) -> usize {
         match state {
@@ -57,6 +77,8 @@ impl StateCounts {
             BuildState::Queued => 2,
             BuildState::Running => 3,
             BuildState::Done => 4,
+            BuildState::Failed => 5,
+            BuildState::Skipped => 6,
         }
     }
     fn add(&mut self, state: BuildState, delta: isize) {
@@ -67,7 +89,16 @@ impl StateCounts {
         self.0[StateCounts::idx(state)]
     }
     pub fn total(&self) -> usize {
-        self.0[0] + self.0[1] + self.0[2] + self.0[3] + self.0[4]
+        self.0.iter().sum()
+    }
+    /// Builds that are over, whether or not they succeeded.
+    pub fn finished(&self) -> usize {
+        self.get(BuildState::Done) + self.get(BuildState::Failed) + self.get(BuildState::Skipped)
+    }
+    /// Builds whose inputs are done but that haven't yet been checked for
+    /// dirtiness.  Nonzero while stat()ing through a large up to date graph.
+    pub fn unchecked(&self) -> usize {
+        self.get(BuildState::Ready)
     }
 }
 
@@ -75,12 +106,17 @@ impl StateCounts {
 /// Each running build is running "in" a pool; there's a default unbounded
 /// pool for builds that don't specify one.
 struct PoolState {
-    /// A queue of builds that are ready to be executed in this pool.
-    queued: VecDeque<BuildId>,
-    /// The number of builds currently running in this pool.
+    /// A queue of builds that are ready to be executed in this pool, along
+    /// with their weights.
+    queued: VecDeque<(BuildId, usize)>,
+    /// The total weight of the builds currently running in this pool.
     running: usize,
     /// The total depth of the pool.  0 means unbounded.
     depth: usize,
+    /// When the pool last became saturated, if it currently is.
+    saturated_since: Option<Instant>,
+    /// Total time the pool was saturated, excluding any current saturation.
+    saturated: Duration,
 }
 
 impl PoolState {
@@ -89,6 +125,40 @@ impl PoolState {
             queued: VecDeque::new(),
             running: 0,
             depth,
+            saturated_since: None,
+            saturated: Duration::ZERO,
+        }
+    }
+
+    /// Whether builds are queued waiting on this pool because it is full.
+    fn is_saturated(&self) -> bool {
+        match self.queued.front() {
+            Some(&(_, weight)) => self.depth != 0 && self.running + weight > self.depth,
+            None => false,
+        }
+    }
+
+    /// Note whether the pool is currently saturated, accumulating the time it
+    /// has been so.  `done` closes out any current saturation.
+    fn sample(&mut self, now: Instant, done: bool) {
+        let saturated = !done && self.is_saturated();
+        match (saturated, self.saturated_since) {
+            (true, None) => self.saturated_since = Some(now),
+            (false, Some(since)) => {
+                self.saturated += now - since;
+                self.saturated_since = None;
+            }
+            _ => {}
+        }
+    }
+
+    /// The number of slots a build consumes while running in this pool.
+    /// A build heavier than the whole pool runs by itself.
+    fn weight(&self, build: &Build) -> usize {
+        if self.depth == 0 {
+            build.pool_weight
+        } else {
+            build.pool_weight.min(self.depth)
         }
     }
 }
@@ -108,6 +178,20 @@ struct BuildStates {
     /// We expect a relatively small number of pools, such that a Vec is more
     /// efficient than a HashMap.
     pools: Vec<(String, PoolState)>,
+
+    /// Whether to list the whole strongly-connected component in dependency
+    /// cycle errors, for -d graph-cycle.
+    explain_cycles: bool,
+
+    /// Files written by running builds, with the build writing each.
+    running_outs: HashMap<FileId, BuildId>,
+
+    /// Builds already reported as waiting for a running build that writes
+    /// the same file.
+    reported_conflicts: HashSet<BuildId>,
+
+    /// Warnings not yet passed on to the progress display.
+    warnings: Vec<String>,
 }
 
 impl BuildStates {
@@ -115,19 +199,29 @@ impl BuildStates {
         let mut pools: Vec<(String, PoolState)> = vec![
             // The implied default pool.
             (String::from(""), PoolState::new(0)),
-            // TODO: the console pool is just a depth-1 pool for now.
+            // The console pool defaults to depth 1, like Ninja's.  n2 doesn't
+            // hand the terminal to console builds; their output is captured
+            // and printed when they complete like any other build's.  So a
+            // manifest declaring a deeper console pool (which Ninja forbids)
+            // just lets that many run at once, each still printed whole.
             (String::from("console"), PoolState::new(1)),
         ];
-        pools.extend(
-            depths
-                .into_iter()
-                .map(|(name, depth)| (name, PoolState::new(depth))),
-        );
+        for (name, depth) in depths {
+            // Depths given for the builtin pools override their defaults.
+            match pools.iter_mut().find(|(key, _)| *key == name) {
+                Some((_, pool)) => *pool = PoolState::new(depth),
+                None => pools.push((name, PoolState::new(depth))),
+            }
+        }
         BuildStates {
             states: DenseMap::new_sized(size, BuildState::Unknown),
             counts: StateCounts::new(),
             ready: HashSet::new(),
             pools,
+            explain_cycles: false,
+            running_outs: HashMap::new(),
+            reported_conflicts: HashSet::new(),
+            warnings: Vec::new(),
         }
     }
 
@@ -145,7 +239,13 @@ impl BuildStates {
                 self.ready.remove(&id);
             }
             BuildState::Running => {
-                self.get_pool(build).unwrap().running -= 1;
+                let pool = self.get_pool(build).unwrap();
+                pool.running -= pool.weight(build);
+                for out in build.outs().iter().chain(&build.dup_outs) {
+                    if self.running_outs.get(out) == Some(&id) {
+                        self.running_outs.remove(out);
+                    }
+                }
             }
             _ => {}
         };
@@ -162,7 +262,11 @@ impl BuildStates {
                 // if self.counts.get(BuildState::Running) == 0 {
                 //     trace::if_enabled(|t| t.write_instant("first build"));
                 // }
-                self.get_pool(build).unwrap().running += 1;
+                let pool = self.get_pool(build).unwrap();
+                pool.running += pool.weight(build);
+                for &out in build.outs().iter().chain(&build.dup_outs) {
+                    self.running_outs.insert(out, id);
+                }
             }
             _ => {}
         };
@@ -184,6 +288,29 @@ impl BuildStates {
         });*/
     }
 
+    /// Mark everything waiting on the outputs of the failed build `id` as
+    /// skipped, transitively, taking queued builds out of their pools.
+    fn skip_dependents(&mut self, graph: &Graph, id: BuildId) {
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            for &out in graph.build(id).outs() {
+                for &dep in &graph.file(out).dependents {
+                    let build = graph.build(dep);
+                    match self.get(dep) {
+                        BuildState::Want | BuildState::Ready => {}
+                        BuildState::Queued => {
+                            let pool = self.get_pool(build).unwrap();
+                            pool.queued.retain(|&(queued, _)| queued != dep);
+                        }
+                        _ => continue,
+                    }
+                    self.set(dep, build, BuildState::Skipped);
+                    stack.push(dep);
+                }
+            }
+        }
+    }
+
     fn unfinished(&self) -> bool {
         self.counts.get(BuildState::Want) > 0
             || self.counts.get(BuildState::Ready) > 0
@@ -191,58 +318,102 @@ impl BuildStates {
             || self.counts.get(BuildState::Queued) > 0
     }
 
-    /// Visits a BuildId that is an input to the desired output.
-    /// Will recursively visit its own inputs.
-    fn want_build(
+    /// Visits a FileId that is an input to the desired output, along with
+    /// everything it transitively depends on.
+    /// The traversal keeps its own stack rather than recursing, as generated
+    /// graphs can have dependency chains deep enough to overflow the native
+    /// stack.  `stack` holds the path of files from the wanted file to the one
+    /// being visited, for detecting cycles.
+    pub fn want_file(
         &mut self,
         graph: &Graph,
         stack: &mut Vec<FileId>,
-        id: BuildId,
+        id: FileId,
     ) -> anyhow::Result<()> {
-        if self.get(id) != BuildState::Unknown {
-            return Ok(()); // Already visited.
-        }
-
-        let build = graph.build(id);
-        self.set(id, build, BuildState::Want);
+        // Files in `stack`, for quick cycle checks.
+        let mut on_stack: HashSet<FileId> = stack.iter().copied().collect();
+        // Builds being visited, each with the index of its next input to
+        // visit.  The nth build generates the nth file pushed onto `stack`
+        // by this call.
+        let mut builds: Vec<(BuildId, usize)> = Vec::new();
+        let base = stack.len();
+
+        let mut next = Some(id);
+        loop {
+            if let Some(id) = next.take() {
+                // Check for a dependency cycle.
+                if on_stack.contains(&id) {
+                    let cycle = stack.iter().position(|&sid| sid == id).unwrap();
+                    let mut err = "dependency cycle: ".to_string();
+                    for &id in stack[cycle..].iter() {
+                        err.push_str(&format!("{} -> ", graph.file(id).name));
+                    }
+                    err.push_str(&graph.file(id).name);
+                    if self.explain_cycles {
+                        let component = graph.cycle_component(id);
+                        err.push_str(&format!(
+                            "\nall {} files in cycles with {}:",
+                            component.len(),
+                            graph.file(id).name
+                        ));
+                        for id in component {
+                            err.push_str(&format!("\n  {}", graph.file(id).name));
+                        }
+                    }
+                    stack.truncate(base);
+                    anyhow::bail!(err);
+                }
+                match graph.file(id).input {
+                    Some(bid) if self.get(bid) == BuildState::Unknown => {
+                        stack.push(id);
+                        on_stack.insert(id);
+                        self.set(bid, graph.build(bid), BuildState::Want);
+                        builds.push((bid, 0));
+                    }
+                    // Source file, or a build that was already visited.
+                    _ => {}
+                }
+            }
 
-        // Any Build that doesn't depend on an output of another Build is ready.
-        let mut ready = true;
-        for &id in build.ordering_ins() {
-            self.want_file(graph, stack, id)?;
-            ready = ready && graph.file(id).input.is_none();
-        }
+            let (bid, index) = match builds.last_mut() {
+                Some(top) => top,
+                None => break,
+            };
+            let build = graph.build(*bid);
+            if let Some(&id) = build.ordering_ins().get(*index) {
+                *index += 1;
+                next = Some(id);
+                continue;
+            }
 
-        if ready {
-            self.set(id, build, BuildState::Ready);
+            // All inputs visited.  Any Build that doesn't depend on an output
+            // of another Build is ready, as is one whose inputs were all
+            // already built by a previous run().
+            let bid = *bid;
+            let ready = build
+                .ordering_ins()
+                .iter()
+                .all(|&id| match graph.file(id).input {
+                    None => true,
+                    Some(bid) => self.get(bid) == BuildState::Done,
+                });
+            if ready {
+                self.set(bid, build, BuildState::Ready);
+            }
+            builds.pop();
+            on_stack.remove(&stack.pop().unwrap());
         }
         Ok(())
     }
 
-    /// Visits a FileId that is an input to the desired output.
-    /// Will recursively visit its own inputs.
-    pub fn want_file(
-        &mut self,
-        graph: &Graph,
-        stack: &mut Vec<FileId>,
-        id: FileId,
-    ) -> anyhow::Result<()> {
-        // Check for a dependency cycle.
-        if let Some(cycle) = stack.iter().position(|&sid| sid == id) {
-            let mut err = "dependency cycle: ".to_string();
-            for &id in stack[cycle..].iter() {
-                err.push_str(&format!("{} -> ", graph.file(id).name));
-            }
-            err.push_str(&graph.file(id).name);
-            anyhow::bail!(err);
-        }
-
-        stack.push(id);
-        if let Some(bid) = graph.file(id).input {
-            self.want_build(graph, stack, bid)?;
+    /// Visits a BuildId without visiting its inputs, making it immediately
+    /// ready.
+    fn want_build_alone(&mut self, build: &Build, id: BuildId) {
+        if self.get(id) != BuildState::Unknown {
+            return; // Already visited.
         }
-        stack.pop();
-        Ok(())
+        self.set(id, build, BuildState::Want);
+        self.set(id, build, BuildState::Ready);
     }
 
     pub fn pop_ready(&mut self) -> Option<BuildId> {
@@ -279,21 +450,167 @@ impl BuildStates {
                 build.pool.as_ref().unwrap()
             )
         })?;
-        pool.queued.push_back(id);
+        let weight = pool.weight(build);
+        pool.queued.push_back((id, weight));
         Ok(())
     }
 
+    /// Track how long each pool spends saturated; see PoolState::sample.
+    fn sample_pools(&mut self, done: bool) {
+        let now = Instant::now();
+        for (_, pool) in self.pools.iter_mut() {
+            pool.sample(now, done);
+        }
+    }
+
+    /// Pools that were saturated at some point, and for how long.
+    fn saturated_pools(&self) -> Vec<(String, Duration)> {
+        self.pools
+            .iter()
+            .filter(|(_, pool)| !pool.saturated.is_zero())
+            .map(|(name, pool)| (name.clone(), pool.saturated))
+            .collect()
+    }
+
     /// Pop a ready to run queued build.
-    pub fn pop_queued(&mut self) -> Option<BuildId> {
+    /// A build writing a file that a running build also writes, as builds
+    /// sharing outputs under --dupbuild=warn do, waits for that build to
+    /// finish rather than racing it, letting the builds queued behind it in
+    /// its pool go first.
+    pub fn pop_queued(&mut self, graph: &Graph) -> Option<BuildId> {
+        let running_outs = &self.running_outs;
         for (_, pool) in self.pools.iter_mut() {
-            if pool.depth == 0 || pool.running < pool.depth {
-                if let Some(id) = pool.queued.pop_front() {
-                    return Some(id);
+            let mut found = None;
+            for (index, &(id, weight)) in pool.queued.iter().enumerate() {
+                // Builds are started in order within the pool's depth, so
+                // that a heavy build isn't starved by lighter ones after it.
+                if pool.depth != 0 && pool.running + weight > pool.depth {
+                    break;
                 }
+                let build = graph.build(id);
+                let conflict = build
+                    .outs()
+                    .iter()
+                    .chain(&build.dup_outs)
+                    .find_map(|out| running_outs.get(out).map(|&other| (*out, other)));
+                if let Some((out, other)) = conflict {
+                    if self.reported_conflicts.insert(id) {
+                        self.warnings.push(format!(
+                            "{}: {} is also written by running build {}; \
+                             waiting for it to finish",
+                            build.location,
+                            graph.file(out).name,
+                            graph.build(other).location
+                        ));
+                    }
+                    continue;
+                }
+                found = Some((index, id));
+                break;
+            }
+            if let Some((index, id)) = found {
+                pool.queued.remove(index);
+                return Some(id);
             }
         }
         None
     }
+
+    /// Take the warnings collected since the last call.
+    fn take_warnings(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.warnings)
+    }
+}
+
+/// The default parallelism for a build: the number of logical CPUs, or 1 if
+/// that can't be determined.
+/// Ninja uses available processors + a constant, but I don't think the
+/// difference matters too much.
+pub fn default_parallelism() -> usize {
+    std::thread::available_parallelism()
+        .map(usize::from)
+        .unwrap_or(1)
+}
+
+/// Interpret a user-requested parallelism, e.g. from the `-j` flag.
+/// None means no particular number was requested, which uses the default;
+/// 0 means a serial build.
+pub fn parallelism_from_flag(n: Option<usize>) -> usize {
+    match n {
+        None => default_parallelism(),
+        Some(0) => 1,
+        Some(n) => n,
+    }
+}
+
+/// Interpret a parallelism given as a percentage of `cpus`, e.g. from
+/// `-j 50%`, rounding down but running at least one task.  Over 100%
+/// oversubscribes the CPUs.
+pub fn parallelism_from_percent(percent: usize, cpus: usize) -> usize {
+    (cpus.saturating_mul(percent) / 100).max(1)
+}
+
+/// Counters of internal operations during a build, for `-d stats`.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    /// stat() calls on files.
+    pub stats: usize,
+    /// Builds whose hash was computed.
+    pub hashes: usize,
+    /// Builds recorded in the db.
+    pub db_writes: usize,
+    /// Builds found to be up to date, which didn't need to run.
+    pub clean: usize,
+    /// Builds whose outputs were restored from the cache.
+    pub cache_hits: usize,
+    /// Files whose content was digested, for --check-content.
+    pub digests: usize,
+    /// Pools that were full while builds waited on them, and for how long.
+    pub saturated_pools: Vec<(String, Duration)>,
+    /// Total CPU time of the commands run, where reported.
+    pub cpu_time: Duration,
+    /// The largest peak memory use of any command, in bytes, along with the
+    /// first output of its build.
+    pub max_rss: Option<(u64, String)>,
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:<12} {:>10}", "metric", "count")?;
+        for (name, count) in [
+            ("stat", self.stats),
+            ("hash", self.hashes),
+            ("db write", self.db_writes),
+            ("clean", self.clean),
+            ("cache hit", self.cache_hits),
+            ("digest", self.digests),
+        ] {
+            writeln!(f, "{:<12} {:>10}", name, count)?;
+        }
+        for (name, time) in &self.saturated_pools {
+            let name = if name.is_empty() { "(default)" } else { name };
+            writeln!(f, "pool {} was full for {:.1}s", name, time.as_secs_f64())?;
+        }
+        if !self.cpu_time.is_zero() {
+            writeln!(f, "command cpu time {:.1}s", self.cpu_time.as_secs_f64())?;
+        }
+        if let Some((rss, name)) = &self.max_rss {
+            writeln!(f, "max rss {:.1}MB, building {}", *rss as f64 / 1e6, name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Receives notifications as builds move through their lifecycle, for
+/// instrumentation that doesn't care about presenting progress.
+/// All methods default to doing nothing.
+pub trait BuildObserver {
+    /// Called when a dirty build is queued to run.
+    fn queued(&mut self, _id: BuildId, _build: &Build) {}
+    /// Called when a build's command is started.
+    fn started(&mut self, _id: BuildId, _build: &Build) {}
+    /// Called when a build's command completes.
+    fn finished(&mut self, _id: BuildId, _build: &Build, _success: bool, _duration: Duration) {}
 }
 
 pub struct Work<'a> {
@@ -305,6 +622,65 @@ pub struct Work<'a> {
     last_hashes: &'a Hashes,
     build_states: BuildStates,
     runner: task::Runner,
+    stats: Stats,
+    /// Builds wanted via want_single_file(), which run without checking
+    /// whether they're dirty.
+    single: HashSet<BuildId>,
+    /// Builds that are considered dirty regardless of their hash.
+    forced: HashSet<BuildId>,
+    /// If true, record dirty builds as up to date instead of running them.
+    touch: bool,
+    /// Output directories already created during this run.
+    created_dirs: HashSet<std::path::PathBuf>,
+    observer: Option<&'a mut dyn BuildObserver>,
+    /// Directory that relative paths in depfiles are relative to, if not the
+    /// current directory.
+    depfile_dir: Option<String>,
+    cache: Option<Cache>,
+    /// Log of executed commands, as the path and the open file.
+    command_log: Option<(String, std::fs::File)>,
+    /// If true, leave output mtimes out of build hashes.
+    ignore_output_mtimes: bool,
+    /// Directory under which absolute paths in commands are hashed as
+    /// relative ones, if any.
+    hash_root: Option<String>,
+    /// If true, inputs whose mtime changed but whose content didn't are
+    /// treated as unchanged.
+    check_content: bool,
+    /// If true, leave the outputs of failed commands in place rather than
+    /// deleting them.
+    keep_failed_outputs: bool,
+    /// If true, run each command in a directory holding only its declared
+    /// inputs.
+    sandbox: bool,
+    /// If true, ask commands for colored diagnostics via CLICOLOR_FORCE.
+    color_diagnostics: bool,
+    /// Number of tasks to run before stopping, if limited.
+    stop_after: Option<usize>,
+    /// If true, a depfile listing a generated file the build has no
+    /// dependency path to adds the missing dependency rather than failing.
+    add_missing_deps: bool,
+    /// Number of failed commands to stop the build at, or 0 for no limit.
+    keep_going: usize,
+    /// Commands that failed so far.
+    failures: usize,
+    /// If true, check after a successful build that the wanted files exist.
+    verify_targets: bool,
+    /// Files wanted by the caller, for verify_targets.
+    targets: Vec<FileId>,
+    /// Builds found dirty because they're marked always_dirty or depend on
+    /// one that is, which in turn dirties the builds depending on them.
+    always_dirty: HashSet<BuildId>,
+    /// If true, find the builds that would run but don't run them.
+    dry_run: bool,
+    /// In a dry run, builds that would run, or phony builds depending on them;
+    /// anything depending on these is presumed dirty as well.
+    dry_dirty: HashSet<BuildId>,
+    /// In a dry run, the builds that would run, in order.
+    would_run: Vec<BuildId>,
+    /// Builds listing each file among their discovered inputs, built on the
+    /// first notify_changed() and kept current as depfiles are read.
+    discovered_dependents: Option<HashMap<FileId, Vec<BuildId>>>,
 }
 
 impl<'a> Work<'a> {
@@ -326,13 +702,268 @@ impl<'a> Work<'a> {
             last_hashes,
             build_states: BuildStates::new(builds, pools),
             runner: task::Runner::new(parallelism),
+            stats: Stats::default(),
+            single: HashSet::new(),
+            forced: HashSet::new(),
+            touch: false,
+            created_dirs: HashSet::new(),
+            observer: None,
+            depfile_dir: None,
+            cache: None,
+            command_log: None,
+            ignore_output_mtimes: false,
+            hash_root: None,
+            check_content: false,
+            keep_failed_outputs: false,
+            sandbox: false,
+            color_diagnostics: false,
+            stop_after: None,
+            add_missing_deps: false,
+            keep_going: 1,
+            failures: 0,
+            verify_targets: false,
+            targets: Vec::new(),
+            always_dirty: HashSet::new(),
+            dry_run: false,
+            dry_dirty: HashSet::new(),
+            would_run: Vec::new(),
+            discovered_dependents: None,
+        }
+    }
+
+    /// Determine which builds are dirty, but rather than running them just
+    /// collect them, for would_run().
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Descriptions of the builds that a dry run found would run.
+    pub fn would_run(&self) -> Vec<&str> {
+        self.would_run
+            .iter()
+            .map(|&id| progress::build_message(self.graph.build(id)))
+            .collect()
+    }
+
+    /// Like would_run(), pairing each description with the build's first
+    /// output.
+    pub fn would_run_outputs(&self) -> Vec<(&str, &str)> {
+        self.would_run
+            .iter()
+            .map(|&id| {
+                let build = self.graph.build(id);
+                let output = self.graph.file(build.outs()[0]).name.as_str();
+                (output, progress::build_message(build))
+            })
+            .collect()
+    }
+
+    /// In a dry run, whether a build depends on one that would run, in which
+    /// case it is presumed dirty too; its inputs may not even exist yet.
+    fn depends_on_dry_dirty(&self, id: BuildId) -> bool {
+        self.graph.build(id).ordering_ins().iter().any(|&in_id| {
+            match self.graph.file(in_id).input {
+                Some(bid) => self.dry_dirty.contains(&bid),
+                None => false,
+            }
+        })
+    }
+
+    /// Whether a build has a dirtying input generated by an always-dirty
+    /// build, or one depending on one.
+    fn depends_on_always_dirty(&self, id: BuildId) -> bool {
+        !self.always_dirty.is_empty()
+            && self.graph.build(id).dirtying_ins().iter().any(|&in_id| {
+                match self.graph.file(in_id).input {
+                    Some(bid) => self.always_dirty.contains(&bid),
+                    None => false,
+                }
+            })
+    }
+
+    /// Restore the outputs of dirty builds from `cache` when possible, and
+    /// store the outputs of builds that run in it.
+    pub fn set_cache(&mut self, cache: Option<Cache>) {
+        self.cache = cache;
+    }
+
+    /// Append a line to the file at `path` for each command run, with its
+    /// start and end times and its exit status.
+    pub fn set_command_log(&mut self, path: &str) -> anyhow::Result<()> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| anyhow::anyhow!("open {}: {}", path, err))?;
+        self.command_log = Some((path.to_owned(), file));
+        Ok(())
+    }
+
+    /// Accumulate a finished command's resource usage into the stats.
+    fn record_usage(&mut self, id: BuildId, usage: task::ResourceUsage) {
+        self.stats.cpu_time += usage.cpu_time;
+        if self
+            .stats
+            .max_rss
+            .as_ref()
+            .is_none_or(|(max, _)| usage.max_rss > *max)
+        {
+            let name = &self.graph.file(self.graph.build(id).outs()[0]).name;
+            self.stats.max_rss = Some((usage.max_rss, name.clone()));
         }
     }
 
-    /// If there's a build rule that generates build.ninja, return the FileId
-    /// to pass to want_fileid that will rebuild it.
-    pub fn build_ninja_fileid(&mut self) -> Option<FileId> {
-        if let Some(id) = self.graph.lookup_file_id("build.ninja") {
+    /// Record a finished command in the command log, if enabled.
+    /// Each entry is one tab-separated line:
+    ///   start  end  status  command
+    /// where times are seconds since the epoch, and written with a single
+    /// write so the log can be followed while the build runs.
+    fn log_command(&mut self, task: &task::FinishedTask) -> anyhow::Result<()> {
+        let (path, file) = match &mut self.command_log {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        // Task spans are Instants; place them in wall clock time relative to now.
+        let (now, wall_now) = (Instant::now(), std::time::SystemTime::now());
+        let wall = |at: Instant| {
+            (wall_now - now.duration_since(at))
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64()
+        };
+        let status = match (task.result.exit_code, task.result.success) {
+            (Some(code), _) => code.to_string(),
+            (None, true) => "ok".to_string(),
+            (None, false) => "failed".to_string(),
+        };
+        let cmdline = self
+            .graph
+            .build(task.buildid)
+            .cmdline
+            .as_deref()
+            .unwrap_or("");
+        let line = format!(
+            "{:.3}\t{:.3}\t{}\t{}\n",
+            wall(task.span.0),
+            wall(task.span.1),
+            status,
+            cmdline.replace('\n', "\\n")
+        );
+        std::io::Write::write_all(file, line.as_bytes())
+            .map_err(|err| anyhow::anyhow!("write {}: {}", path, err))
+    }
+
+    /// Resolve relative paths found in depfiles against `dir`, for commands
+    /// that run in a different directory than n2.
+    pub fn set_depfile_dir(&mut self, dir: Option<String>) {
+        self.depfile_dir = dir;
+    }
+
+    /// See FileState::set_mtime_granularity.
+    pub fn set_mtime_granularity(&mut self, granularity: Duration) {
+        self.file_state.set_mtime_granularity(granularity);
+    }
+
+    /// See FileState::set_normalizers.
+    pub fn set_normalizers(&mut self, normalizers: Normalizers) {
+        self.file_state.set_normalizers(normalizers);
+    }
+
+    /// On a dependency cycle, report every file in a cycle with the one
+    /// found, not just the one cycle.
+    pub fn set_explain_cycles(&mut self, explain: bool) {
+        self.build_states.explain_cycles = explain;
+    }
+
+    /// Consider a build up to date based only on its inputs and command,
+    /// regardless of its outputs' mtimes, as long as they exist.  For
+    /// reproducible builds that set outputs to a fixed timestamp, or
+    /// otherwise touch them after building.
+    pub fn set_ignore_output_mtimes(&mut self, ignore: bool) {
+        self.ignore_output_mtimes = ignore;
+    }
+
+    /// Hash absolute paths under `root` in commands as relative ones, so that
+    /// checkouts of the same tree in different places agree on which builds
+    /// are up to date, e.g. to share a build cache.
+    pub fn set_hash_root(&mut self, root: Option<String>) {
+        self.hash_root = root;
+    }
+
+    /// Record content digests of inputs in the db, and treat an input whose
+    /// mtime changed but whose content matches its last digest as unchanged,
+    /// as after e.g. a `git checkout` that touched it.  This costs reading
+    /// each input once when first seen and whenever its mtime changes.
+    pub fn set_check_content(&mut self, check: bool) {
+        self.check_content = check;
+    }
+
+    /// Keep the outputs of failed commands rather than deleting them, for
+    /// tools that resume from partial outputs.
+    pub fn set_keep_failed_outputs(&mut self, keep: bool) {
+        self.keep_failed_outputs = keep;
+    }
+
+    /// Run each command in its own directory under `.n2_sandbox`, holding
+    /// only the build's declared inputs, so that a command reading an
+    /// undeclared input fails rather than silently depending on it.
+    pub fn set_sandbox(&mut self, sandbox: bool) {
+        self.sandbox = sandbox;
+    }
+
+    /// Set `CLICOLOR_FORCE=1` in the environment of commands that don't set
+    /// it themselves.  Tools that honor it color their diagnostics even
+    /// though their output goes to a pipe rather than a terminal.
+    pub fn set_color_diagnostics(&mut self, color: bool) {
+        self.color_diagnostics = color;
+    }
+
+    /// Start no more than `n` tasks, letting those finish and then stopping
+    /// even if other builds are left to run, e.g. to bisect a failure that
+    /// only shows up after some part of the build.
+    pub fn set_stop_after(&mut self, n: Option<usize>) {
+        self.stop_after = n;
+    }
+
+    /// Rather than failing when a build's discovered deps include a generated
+    /// file it has no dependency path to, warn and add the file as an
+    /// order-only input.  This eases migrating from build systems that
+    /// tolerate such missing dependencies.
+    pub fn set_add_missing_deps(&mut self, add: bool) {
+        self.add_missing_deps = add;
+    }
+
+    /// Keep building what doesn't depend on a failed command until `n`
+    /// commands have failed, or indefinitely if `n` is 0.  The default of 1
+    /// stops at the first failure.
+    pub fn set_keep_going(&mut self, n: usize) {
+        self.keep_going = n;
+    }
+
+    /// After a successful build, stat the wanted files and fail if any is
+    /// missing, catching commands that exit successfully without writing
+    /// their outputs.  Otherwise such a build just reruns next time.
+    pub fn set_verify_targets(&mut self, verify: bool) {
+        self.verify_targets = verify;
+    }
+
+    /// Register an observer to be notified of build lifecycle events.
+    pub fn set_observer(&mut self, observer: &'a mut dyn BuildObserver) {
+        self.observer = Some(observer);
+    }
+
+    /// Rather than running dirty builds, record their current outputs in the
+    /// db as if they had just run.  Used to mark outputs produced outside of
+    /// n2 as up to date.
+    pub fn set_touch(&mut self, touch: bool) {
+        self.touch = touch;
+    }
+
+    /// If there's a build rule that generates the top-level build file (e.g.
+    /// build.ninja), return the FileId to pass to want_fileid that will
+    /// rebuild it.
+    pub fn build_ninja_fileid(&mut self, manifest: &str) -> Option<FileId> {
+        if let Some(id) = self.graph.lookup_file_id(manifest) {
             if self.graph.file(id).input.is_some() {
                 return Some(id);
             }
@@ -340,17 +971,193 @@ impl<'a> Work<'a> {
         None
     }
 
+    /// Add a file to the set of files to bring up to date.
+    /// This may be called again after run(), in which case only the newly
+    /// wanted work is done by the next run().
     pub fn want_fileid(&mut self, id: FileId) -> anyhow::Result<()> {
+        self.want_fileids(&[id])
+    }
+
+    /// Want several files, sharing one cycle-detection stack between them.
+    pub fn want_fileids(&mut self, ids: &[FileId]) -> anyhow::Result<()> {
         let mut stack = Vec::new();
-        self.build_states.want_file(self.graph, &mut stack, id)
+        for &id in ids {
+            self.build_states.want_file(self.graph, &mut stack, id)?;
+        }
+        self.targets.extend_from_slice(ids);
+        Ok(())
     }
 
-    pub fn want_file(&mut self, name: &str) -> anyhow::Result<()> {
-        let target = match self.graph.lookup_file_id(name) {
+    fn lookup_file(&self, name: &str) -> anyhow::Result<FileId> {
+        match self.graph.lookup_file_id(name) {
             None => anyhow::bail!("unknown path requested: {:?}", name),
+            Some(id) => Ok(id),
+        }
+    }
+
+    pub fn want_file(&mut self, name: &str) -> anyhow::Result<()> {
+        let target = self.lookup_file(name)?;
+        self.want_fileid(target)
+    }
+
+    /// Look up several files by name, reporting the unknown ones together.
+    fn lookup_files<S: AsRef<str>>(&self, names: &[S]) -> anyhow::Result<Vec<FileId>> {
+        let mut ids = Vec::with_capacity(names.len());
+        let mut unknown = Vec::new();
+        for name in names {
+            match self.graph.lookup_file_id(name.as_ref()) {
+                Some(id) => ids.push(id),
+                None => unknown.push(name.as_ref()),
+            }
+        }
+        match unknown.as_slice() {
+            [] => Ok(ids),
+            [name] => anyhow::bail!("unknown path requested: {:?}", name),
+            names => anyhow::bail!("unknown paths requested: {:?}", names),
+        }
+    }
+
+    /// Want several files by name.  All the names are looked up before any
+    /// is wanted, so that unknown ones are reported together.
+    pub fn want_files<S: AsRef<str>>(&mut self, names: &[S]) -> anyhow::Result<()> {
+        let ids = self.lookup_files(names)?;
+        self.want_fileids(&ids)
+    }
+
+    /// Tell the build that the file at `path` may have changed on disk, as a
+    /// file watcher would.  If its mtime did change, the builds using or
+    /// generating it that were already checked (or ran) are marked to be
+    /// checked again, along with everything depending on them, so that the
+    /// next run() brings them up to date.  Returns whether any were.
+    pub fn notify_changed(&mut self, path: &str) -> anyhow::Result<bool> {
+        let id = match self.graph.lookup_file_id(path) {
             Some(id) => id,
+            None => return Ok(false),
         };
-        self.want_fileid(target)
+        // A file never stat()ed hasn't informed any decision yet.
+        let before = match self.file_state.get(id) {
+            Some(mtime) => mtime,
+            None => return Ok(false),
+        };
+        if self.file_state.restat(id, &self.graph.file(id).name)? == before {
+            return Ok(false);
+        }
+
+        let graph = &*self.graph;
+        let discovered_dependents = self.discovered_dependents.get_or_insert_with(|| {
+            let mut index: HashMap<FileId, Vec<BuildId>> = HashMap::new();
+            for (bid, build) in graph.builds() {
+                for &dep in build.discovered_ins() {
+                    index.entry(dep).or_default().push(bid);
+                }
+            }
+            index
+        });
+        let file = graph.file(id);
+        let mut stack: Vec<BuildId> = file.dependents.clone();
+        stack.extend(file.input);
+        stack.extend(discovered_dependents.get(&id).into_iter().flatten());
+        let mut invalidated = Vec::new();
+        while let Some(bid) = stack.pop() {
+            if self.build_states.get(bid) != BuildState::Done {
+                continue;
+            }
+            let build = self.graph.build(bid);
+            self.build_states.set(bid, build, BuildState::Want);
+            invalidated.push(bid);
+            for &out in build.outs() {
+                stack.extend_from_slice(&self.graph.file(out).dependents);
+            }
+        }
+        for &bid in &invalidated {
+            if self.recheck_ready(bid) {
+                self.build_states
+                    .set(bid, self.graph.build(bid), BuildState::Ready);
+            }
+        }
+        Ok(!invalidated.is_empty())
+    }
+
+    /// Mark the build that generates a file as dirty regardless of its hash,
+    /// so it runs when wanted.  If transitive, also mark all the builds it
+    /// depends on.
+    pub fn force_fileid(&mut self, id: FileId, transitive: bool) {
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            let bid = match self.graph.file(id).input {
+                None => continue,
+                Some(bid) => bid,
+            };
+            if !self.forced.insert(bid) || !transitive {
+                continue;
+            }
+            stack.extend_from_slice(self.graph.build(bid).ordering_ins());
+        }
+    }
+
+    pub fn force_file(&mut self, name: &str, transitive: bool) -> anyhow::Result<()> {
+        let target = self.lookup_file(name)?;
+        self.force_fileid(target, transitive);
+        Ok(())
+    }
+
+    /// Want only the build that generates the named file, and run it even if
+    /// it's up to date.  Its inputs are used as they are, without bringing
+    /// them up to date.
+    ///
+    /// Because the inputs aren't built, the outputs of this build can end up
+    /// out of sync with the rest of the graph; this is meant for things like
+    /// rerunning a failed link without first checking everything it links.
+    pub fn want_single_file(&mut self, name: &str) -> anyhow::Result<()> {
+        let id = self.lookup_file(name)?;
+        self.want_single_fileid(id)
+    }
+
+    /// Like want_single_file(), for several files by name.  As with
+    /// want_files(), unknown names are reported together.
+    pub fn want_single_files<S: AsRef<str>>(&mut self, names: &[S]) -> anyhow::Result<()> {
+        for id in self.lookup_files(names)? {
+            self.want_single_fileid(id)?;
+        }
+        Ok(())
+    }
+
+    fn want_single_fileid(&mut self, id: FileId) -> anyhow::Result<()> {
+        let name = &self.graph.file(id).name;
+        let bid = match self.graph.file(id).input {
+            None => anyhow::bail!("{:?} isn't generated by any build", name),
+            Some(bid) => bid,
+        };
+        let build = self.graph.build(bid);
+        if build.cmdline.is_none() {
+            anyhow::bail!("{:?} is generated by a phony build", name);
+        }
+        self.build_states.want_build_alone(build, bid);
+        self.single.insert(bid);
+        self.targets.push(id);
+        Ok(())
+    }
+
+    /// Stat all the inputs of a build wanted by want_single_file(), failing
+    /// if any are missing.  Generated inputs may not have been stat()ed,
+    /// because the builds that generate them weren't visited.  The outputs
+    /// are stat()ed too, so that remove_failed_outputs() can tell which ones
+    /// a failing command touched.
+    fn stat_single_build_ins(&mut self, id: BuildId) -> anyhow::Result<()> {
+        let build = self.graph.build(id);
+        for &id in build.ordering_ins() {
+            let file = self.graph.file(id);
+            if self.file_state.get_or_stat(id, &file.name)? == MTime::Missing {
+                anyhow::bail!("{}: input {} missing", build.location, file.name);
+            }
+        }
+        for &id in build.discovered_ins() {
+            self.file_state.get_or_stat(id, &self.graph.file(id).name)?;
+        }
+        for &id in build.outs() {
+            self.file_state.restat(id, &self.graph.file(id).name)?;
+        }
+        Ok(())
     }
 
     /// Check whether a given build is ready, generally after one of its inputs
@@ -377,11 +1184,63 @@ impl<'a> Work<'a> {
         true
     }
 
+    /// Add generated `dep`, discovered as an input of build `id` with no
+    /// dependency path to it, as an order-only input of the build.
+    fn add_missing_dep(&mut self, id: BuildId, dep: FileId) {
+        println!(
+            "n2: warning: {} used generated file {}, but has no dependency path to it; \
+             adding it as an order-only input",
+            self.graph.build(id).location,
+            self.graph.file(dep).name
+        );
+        self.graph.add_order_only_in(id, dep);
+    }
+
+    /// With add_missing_deps, add any generated discovered inputs of a ready
+    /// build that it has no dependency path to, and bring them up to date
+    /// first.  Returns true if the build must now wait for them, having been
+    /// moved back to the Want state.
+    fn wait_for_missing_deps(&mut self, id: BuildId) -> anyhow::Result<bool> {
+        if !self.add_missing_deps {
+            return Ok(false);
+        }
+        let missing: Vec<FileId> = self
+            .graph
+            .build(id)
+            .discovered_ins()
+            .iter()
+            .copied()
+            .filter(|&dep| {
+                self.file_state.get(dep).is_none() && self.graph.file(dep).input.is_some()
+            })
+            .collect();
+        if missing.is_empty() {
+            return Ok(false);
+        }
+        for &dep in &missing {
+            self.add_missing_dep(id, dep);
+            // Start from this build's outputs, so that a new edge that
+            // closes a cycle is reported as one.
+            let mut stack = self.graph.build(id).outs().to_vec();
+            self.build_states.want_file(self.graph, &mut stack, dep)?;
+        }
+        if self.recheck_ready(id) {
+            return Ok(false);
+        }
+        let build = self.graph.build(id);
+        self.build_states.set(id, build, BuildState::Want);
+        Ok(true)
+    }
+
     /// Ensure all the discovered_ins for a build exist, as expected just before
     /// or after building it.  Returns the missing FileId if found.
-    fn ensure_discovered_stats(&mut self, id: BuildId) -> anyhow::Result<Option<FileId>> {
-        let build = self.graph.build(id);
-        for &id in build.discovered_ins() {
+    fn ensure_discovered_stats(&mut self, bid: BuildId) -> anyhow::Result<Option<FileId>> {
+        for index in 0.. {
+            let build = self.graph.build(bid);
+            let id = match build.discovered_ins().get(index) {
+                Some(&id) => id,
+                None => break,
+            };
             let mtime = match self.file_state.get(id) {
                 Some(mtime) => mtime,
                 None => {
@@ -399,12 +1258,18 @@ impl<'a> Work<'a> {
                         // either direct or indirect (like the stamp).  If that
                         // were present, then we'd already have file_state for this
                         // file and wouldn't get here.
-                        anyhow::bail!(
-                            "{} used generated file {}, but has no dependency path to it",
-                            build.location,
-                            file.name
-                        );
+                        if !self.add_missing_deps {
+                            anyhow::bail!(
+                                "{} used generated file {}, but has no dependency path to it",
+                                build.location,
+                                file.name
+                            );
+                        }
+                        // Only reached after the build ran; before it runs,
+                        // wait_for_missing_deps adds the dependency.
+                        self.add_missing_dep(bid, id);
                     }
+                    let file = self.graph.file(id);
                     self.file_state.restat(id, &file.name)?
                 }
             };
@@ -417,16 +1282,54 @@ impl<'a> Work<'a> {
     }
 
     /// Given a task that just finished, record any discovered deps and hash.
+    /// Returns false if an output was missing, as record_outputs() does.
     /// Postcondition: all outputs have been stat()ed.
-    fn record_finished(&mut self, id: BuildId, result: task::TaskResult) -> anyhow::Result<()> {
+    fn record_finished(&mut self, id: BuildId, result: task::TaskResult) -> anyhow::Result<bool> {
         let deps = match result.discovered_deps {
             None => Vec::new(),
             Some(names) => names
                 .into_iter()
-                .map(|mut name| self.graph.file_id(&mut name))
+                .map(|name| {
+                    let mut name = match &self.depfile_dir {
+                        Some(dir) if std::path::Path::new(&name).is_relative() => {
+                            format!("{}/{}", dir, name)
+                        }
+                        _ => name,
+                    };
+                    self.graph.file_id(&mut name)
+                })
                 .collect(),
         };
+        let build = self.graph.build(id);
+        let own_outs: Vec<&str> = deps
+            .iter()
+            .filter(|dep| build.outs().contains(dep))
+            .map(|&dep| self.graph.file(dep).name.as_str())
+            .collect();
+        if !own_outs.is_empty() {
+            println!(
+                "n2: warning: {}: depfile lists the build's own output {}; ignoring it",
+                build.location,
+                own_outs.join(", ")
+            );
+        }
+        let old_deps = match self.discovered_dependents {
+            Some(_) => self.graph.build(id).discovered_ins().to_vec(),
+            None => Vec::new(),
+        };
         let deps_changed = self.graph.build_mut(id).update_discovered(deps);
+        if deps_changed {
+            if let Some(index) = &mut self.discovered_dependents {
+                for dep in old_deps {
+                    if let Some(bids) = index.get_mut(&dep) {
+                        bids.retain(|&bid| bid != id);
+                    }
+                }
+                for &dep in self.graph.build(id).discovered_ins() {
+                    index.entry(dep).or_default().push(id);
+                }
+            }
+        }
 
         // We may have discovered new deps, so ensure we have mtimes for those.
         if deps_changed {
@@ -439,6 +1342,73 @@ impl<'a> Work<'a> {
             }
         }
 
+        self.record_outputs(id)
+    }
+
+    /// Delete the outputs a failed (or interrupted) command modified, so that
+    /// a partially written output is never mistaken for a valid one.
+    /// Outputs the command left untouched are kept, as are outputs whose
+    /// state before the command ran is unknown.
+    fn remove_failed_outputs(&mut self, id: BuildId) -> anyhow::Result<()> {
+        for &id in self.graph.build(id).outs() {
+            let file = self.graph.file(id);
+            let before = match self.file_state.get(id) {
+                Some(before) => before,
+                None => continue,
+            };
+            let mtime = self.file_state.restat(id, &file.name)?;
+            if mtime == MTime::Missing || before == mtime {
+                continue;
+            }
+            match std::fs::remove_file(&file.name) {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => anyhow::bail!("remove {}: {}", file.name, err),
+            }
+            self.file_state.restat(id, &file.name)?;
+        }
+        Ok(())
+    }
+
+    /// With check_content, compare the content of a build's inputs against
+    /// their recorded digests.  An input whose mtime changed but whose content
+    /// didn't is given its recorded mtime, so it doesn't change the build's
+    /// hash; otherwise its new digest is recorded.
+    /// Prereq: the inputs have been stat()ed.
+    fn apply_content_digests(&mut self, id: BuildId) -> anyhow::Result<()> {
+        if !self.check_content {
+            return Ok(());
+        }
+        let build = self.graph.build(id);
+        for &id in build.dirtying_ins().iter().chain(build.discovered_ins()) {
+            let mtime = match self.file_state.get(id) {
+                Some(MTime::Stamp(mtime)) => mtime,
+                _ => continue,
+            };
+            let last = self.db.digest(id);
+            if matches!(last, Some((last_mtime, _)) if last_mtime == mtime) {
+                continue;
+            }
+            let name = &self.graph.file(id).name;
+            let digest = self
+                .file_state
+                .digest_file(name)
+                .map_err(|err| anyhow::anyhow!("read {}: {}", name, err))?;
+            self.stats.digests += 1;
+            match last {
+                Some((last_mtime, last_digest)) if last_digest == digest => {
+                    self.file_state.set(id, MTime::Stamp(last_mtime));
+                }
+                _ => self.db.write_digest(self.graph, id, mtime, digest)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Stat the outputs of a build and write its hash to the db, marking it
+    /// up to date.  Returns false if an output was missing and so nothing
+    /// was recorded.
+    fn record_outputs(&mut self, id: BuildId) -> anyhow::Result<bool> {
         // Stat all the outputs.  This step just finished, so we need to update
         // any cached state of the output files to reflect their new state.
         let build = self.graph.build(id);
@@ -446,7 +1416,7 @@ impl<'a> Work<'a> {
         for &id in build.outs() {
             let file = self.graph.file(id);
             let mtime = self.file_state.restat(id, &file.name)?;
-            if mtime == MTime::Missing {
+            if mtime == MTime::Missing && !build.optional_outs.contains(&id) {
                 output_missing = true;
             }
         }
@@ -455,13 +1425,62 @@ impl<'a> Work<'a> {
             // If a declared output is missing, don't record the build in
             // in the db.  It will be considered dirty next time anyway due
             // to the missing output.
-            return Ok(());
+            return Ok(false);
         }
 
-        let hash = hash_build(self.graph, &mut self.file_state, build)?;
+        self.apply_content_digests(id)?;
+        let build = self.graph.build(id);
+        let hash = hash_build(
+            self.graph,
+            &mut self.file_state,
+            build,
+            !self.ignore_output_mtimes,
+            self.hash_root.as_deref(),
+        )?;
+        self.stats.hashes += 1;
         self.db.write_build(self.graph, id, hash)?;
+        self.stats.db_writes += 1;
 
-        Ok(())
+        Ok(true)
+    }
+
+    /// Try to satisfy a build from the cache, recording it as finished on a
+    /// hit.  Cache failures aren't fatal; the build just runs as usual.
+    fn restore_from_cache(&mut self, id: BuildId) -> anyhow::Result<bool> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return Ok(false),
+        };
+        let build = self.graph.build(id);
+        let deps = match cache.restore(self.graph, build, self.hash_root.as_deref()) {
+            Ok(Some(deps)) => deps,
+            Ok(None) => return Ok(false),
+            Err(err) => {
+                println!("n2: warning: cache: {}", err);
+                return Ok(false);
+            }
+        };
+        self.stats.cache_hits += 1;
+        self.record_finished(
+            id,
+            task::TaskResult {
+                success: true,
+                exit_code: Some(0),
+                output: Vec::new(),
+                discovered_deps: Some(deps),
+                usage: None,
+            },
+        )?;
+        Ok(true)
+    }
+
+    fn store_in_cache(&self, id: BuildId) {
+        if let Some(cache) = &self.cache {
+            let build = self.graph.build(id);
+            if let Err(err) = cache.store(self.graph, build, self.hash_root.as_deref()) {
+                println!("n2: warning: cache: {}", err);
+            }
+        }
     }
 
     /// Given a build that just finished, check whether its dependent builds are now ready.
@@ -534,6 +1553,13 @@ impl<'a> Work<'a> {
                     if workaround_missing_phony_deps {
                         continue;
                     }
+                    // The output of a phony build needn't exist; it's an alias
+                    // for the phony build's inputs.
+                    if let Some(bid) = file.input {
+                        if self.graph.build(bid).cmdline.is_none() {
+                            continue;
+                        }
+                    }
                     anyhow::bail!("{}: input {} missing", build.location, file.name);
                 }
             }
@@ -546,11 +1572,7 @@ impl<'a> Work<'a> {
                     // exists or not, we only used it for ordering.
                     continue;
                 }
-                let mtime = match self.file_state.get(id) {
-                    Some(mtime) => mtime,
-                    None => self.file_state.restat(id, &file.name)?,
-                };
-                if mtime == MTime::Missing {
+                if self.file_state.get_or_stat(id, &file.name)? == MTime::Missing {
                     if workaround_missing_phony_deps {
                         continue;
                     }
@@ -568,16 +1590,19 @@ impl<'a> Work<'a> {
 
         // Stat all the outputs.
         // We know this build is solely responsible for updating these outputs,
-        // and if we're checking if it's dirty we are visiting it the first
-        // time, so we stat unconditionally.
+        // so we stat unconditionally.  Any previous file state for an output
+        // (e.g. from an earlier run() against the same FileState) may be stale
+        // if something outside the build touched it, so refresh it rather than
+        // trusting it.
         // This is looking at if the outputs are already present.
-        for &id in self.graph.build(id).outs() {
+        // There's no need to skip outputs some other pending build will
+        // generate: an output has exactly one generating build (see
+        // "Missing files" in doc/design_notes.md).
+        let build = self.graph.build(id);
+        for &id in build.outs() {
             let file = self.graph.file(id);
-            if self.file_state.get(id).is_some() {
-                panic!("expected no file state for {}", file.name);
-            }
             let mtime = self.file_state.restat(id, &file.name)?;
-            if mtime == MTime::Missing {
+            if mtime == MTime::Missing && !build.optional_outs.contains(&id) {
                 return Ok(true);
             }
         }
@@ -592,43 +1617,116 @@ impl<'a> Work<'a> {
         let file_missing = self.check_build_files_missing(id)?;
 
         let build = self.graph.build(id);
-
-        // A phony build can never be dirty.
         let phony = build.cmdline.is_none();
+
+        if build.always_dirty || self.depends_on_always_dirty(id) {
+            self.always_dirty.insert(id);
+            // A phony build has nothing to run, but passes the dirtiness on.
+            return Ok(!phony);
+        }
+
+        // Otherwise, a phony build can never be dirty.
         if phony {
             return Ok(false);
         }
 
+        if self.forced.contains(&id) {
+            return Ok(true);
+        }
+
         // If any files are missing, the build is dirty without needing
         // to consider hashes.
         if file_missing {
             return Ok(true);
         }
 
+        // A build that never ran is dirty; don't bother hashing it.
+        if !self.last_hashes.has(id) {
+            return Ok(true);
+        }
+
         // If we get here, all the relevant files are present and stat()ed,
         // so compare the hash against the last hash.
-        // TODO: skip this whole function if no previous hash is present.
-        let hash = hash_build(self.graph, &mut self.file_state, build)?;
-        Ok(self.last_hashes.changed(id, hash))
+        self.apply_content_digests(id)?;
+        let build = self.graph.build(id);
+        let hash = hash_build(
+            self.graph,
+            &mut self.file_state,
+            build,
+            !self.ignore_output_mtimes,
+            self.hash_root.as_deref(),
+        )?;
+        self.stats.hashes += 1;
+        Ok(self.last_hashes.changed(id, hash) != HashChange::Unchanged)
     }
 
-    /// Create the parent directories of a given list of fileids.
-    /// Used to create directories used for outputs.
+    fn enqueue(&mut self, id: BuildId) -> anyhow::Result<()> {
+        let build = self.graph.build(id);
+        self.build_states.enqueue(id, build)?;
+        if let Some(observer) = &mut self.observer {
+            observer.queued(id, build);
+        }
+        Ok(())
+    }
+
+    /// Create the parent directories of a build's outputs.
+    /// Directories are remembered once created, so builds sharing an output
+    /// directory only create it once per run.
     /// TODO: do this within the thread executing the subtask?
-    fn create_parent_dirs(&self, ids: &[FileId]) -> anyhow::Result<()> {
-        let mut dirs: Vec<&std::path::Path> = Vec::new();
-        for &out in ids {
-            if let Some(parent) = std::path::Path::new(&self.graph.file(out).name).parent() {
-                if dirs.iter().any(|&p| p == parent) {
+    fn create_parent_dirs(&mut self, id: BuildId) -> anyhow::Result<()> {
+        let build = self.graph.build(id);
+        for &out in build.outs() {
+            let name = &self.graph.file(out).name;
+            if let Some(parent) = std::path::Path::new(name).parent() {
+                if self.created_dirs.contains(parent) {
                     continue;
                 }
-                std::fs::create_dir_all(parent)?;
-                dirs.push(parent);
+                if let Err(err) = std::fs::create_dir_all(parent) {
+                    return Err(self
+                        .explain_dir_conflict(build, name, parent)
+                        .unwrap_or_else(|| {
+                            anyhow::anyhow!("create {}: {}", parent.display(), err)
+                        }));
+                }
+                self.created_dirs.insert(parent.to_path_buf());
             }
         }
         Ok(())
     }
 
+    /// Diagnose a failure to create the directory `dir` for output `out`,
+    /// in the common case where a component of it exists as a file, which is
+    /// typically the output of another build.
+    fn explain_dir_conflict(
+        &self,
+        build: &Build,
+        out: &str,
+        dir: &std::path::Path,
+    ) -> Option<anyhow::Error> {
+        let file = dir
+            .ancestors()
+            .find(|p| std::fs::metadata(p).is_ok_and(|m| !m.is_dir()))?;
+        let name = file.to_string_lossy();
+        let origin = match self
+            .graph
+            .lookup_file_id(&name)
+            .and_then(|id| self.graph.file(id).input)
+        {
+            Some(bid) => format!(
+                ", generated by the build at {}",
+                self.graph.build(bid).location
+            ),
+            None => String::new(),
+        };
+        Some(anyhow::anyhow!(
+            "{}: output {} needs {} to be a directory, but it is a file{}",
+            build.location,
+            out,
+            name,
+            origin
+        ))
+    }
+
     // Runs the build.
     // Returns a Result for failures, but we must clean up the progress before
     // returning the result to the caller.
@@ -637,6 +1735,7 @@ impl<'a> Work<'a> {
         signal::register_sigint();
         let mut tasks_done = 0;
         while self.build_states.unfinished() {
+            self.report_warnings();
             self.progress.update(&self.build_states.counts);
 
             // Approach:
@@ -651,31 +1750,106 @@ impl<'a> Work<'a> {
 
             let mut made_progress = false;
             while self.runner.can_start_more() {
-                let id = match self.build_states.pop_queued() {
+                // Once enough tasks have started, let them finish but start
+                // no more.
+                if matches!(self.stop_after, Some(n) if tasks_done + self.runner.running >= n) {
+                    break;
+                }
+                let id = match self.build_states.pop_queued(self.graph) {
                     Some(id) => id,
                     None => break,
                 };
+                if self.dry_run {
+                    self.dry_dirty.insert(id);
+                    self.would_run.push(id);
+                    tasks_done += 1;
+                    self.ready_dependents(id);
+                    made_progress = true;
+                    continue;
+                }
+                if self.touch {
+                    if !self.record_outputs(id)? {
+                        println!(
+                            "n2: warning: {}: output missing, not marked up to date",
+                            self.graph.build(id).location
+                        );
+                    }
+                    tasks_done += 1;
+                    self.ready_dependents(id);
+                    made_progress = true;
+                    continue;
+                }
+                if self.restore_from_cache(id)? {
+                    tasks_done += 1;
+                    self.ready_dependents(id);
+                    made_progress = true;
+                    continue;
+                }
+                // After Ctrl-C, start nothing more, even with -k letting the
+                // build go on past the interrupted commands.
+                if signal::interrupted() {
+                    return Ok(None);
+                }
+                self.create_parent_dirs(id)?;
                 let build = self.graph.build(id);
                 self.build_states.set(id, build, BuildState::Running);
-                self.create_parent_dirs(build.outs())?;
+                let names = |ids: &[FileId]| -> Vec<String> {
+                    ids.iter()
+                        .map(|&id| self.graph.file(id).name.clone())
+                        .collect()
+                };
+                let sandbox = if self.sandbox {
+                    Some(task::Sandbox {
+                        dir: std::path::Path::new(".n2_sandbox").join(id.index().to_string()),
+                        ins: names(build.ordering_ins()),
+                        outs: names(build.outs()),
+                    })
+                } else {
+                    None
+                };
+                let mut env = build.env.clone();
+                if self.color_diagnostics && !env.iter().any(|(key, _)| key == "CLICOLOR_FORCE") {
+                    env.push(("CLICOLOR_FORCE".to_string(), "1".to_string()));
+                }
                 self.runner.start(
                     id,
                     build.cmdline.clone().unwrap(),
+                    env,
                     build.depfile.clone(),
                     build.rspfile.clone(),
+                    sandbox,
                 );
                 self.progress.task_state(id, build, BuildState::Running);
+                if let Some(observer) = &mut self.observer {
+                    observer.started(id, build);
+                }
                 made_progress = true;
             }
 
             while let Some(id) = self.build_states.pop_ready() {
-                if !self.check_build_dirty(id)? {
+                if self.single.contains(&id) {
+                    self.stat_single_build_ins(id)?;
+                    self.enqueue(id)?;
+                } else if self.dry_run && self.depends_on_dry_dirty(id) {
+                    if self.graph.build(id).cmdline.is_none() {
+                        self.dry_dirty.insert(id);
+                        self.ready_dependents(id);
+                    } else {
+                        self.enqueue(id)?;
+                    }
+                } else if self.wait_for_missing_deps(id)? {
+                    // Queued again once the newly added inputs are built.
+                } else if !self.check_build_dirty(id)? {
                     // Not dirty; go directly to the Done state.
+                    self.stats.clean += 1;
                     self.ready_dependents(id);
                 } else {
-                    self.build_states.enqueue(id, self.graph.build(id))?;
+                    self.enqueue(id)?;
                 }
                 made_progress = true;
+                // Checking a large graph can take a while before any command
+                // runs; keep the progress display moving meanwhile.
+                self.progress.update(&self.build_states.counts);
             }
 
             if made_progress {
@@ -683,31 +1857,70 @@ impl<'a> Work<'a> {
             }
 
             if !self.runner.is_running() {
+                if matches!(self.stop_after, Some(n) if tasks_done >= n) {
+                    break;
+                }
                 panic!("no work to do and runner not running?");
             }
 
+            self.build_states.sample_pools(false);
+
             // Flush progress here, to ensure that the progress is the most up
             // to date before we wait.  Otherwise the progress might seem like
             // we're doing nothing while we wait.
-            self.progress.flush();
-            let task = match self.runner.wait(Duration::from_millis(500)) {
+            // Wake up early if it has a redraw pending.
+            let mut timeout = Duration::from_millis(500);
+            if let Some(redraw) = self.progress.flush() {
+                timeout = timeout.min(redraw);
+            }
+            let task = match self.runner.wait(timeout) {
                 None => continue, // timeout
                 Some(task) => task,
             };
+            self.log_command(&task)?;
+            if let Some(usage) = task.result.usage {
+                self.record_usage(task.buildid, usage);
+            }
             let build = self.graph.build(task.buildid);
             trace::if_enabled(|t| {
                 let desc = progress::build_message(build);
                 t.write_complete(desc, task.tid + 1, task.span.0, task.span.1);
             });
 
-            self.progress
-                .completed(build, task.result.success, &task.result.output);
+            self.progress.completed(
+                task.buildid,
+                build,
+                task.result.success,
+                task.result.exit_code,
+                &task.result.output,
+            );
+            if let Some(observer) = &mut self.observer {
+                let duration = task.span.1.duration_since(task.span.0);
+                observer.finished(task.buildid, build, task.result.success, duration);
+            }
             if !task.result.success {
-                return Ok(None);
+                if !self.keep_failed_outputs {
+                    self.remove_failed_outputs(task.buildid)?;
+                }
+                self.failures += 1;
+                if self.failures == self.keep_going || signal::interrupted() {
+                    return Ok(None);
+                }
+                // Builds depending on this one can never run; take them out
+                // now rather than leaving them waiting.
+                let build = self.graph.build(task.buildid);
+                self.build_states
+                    .set(task.buildid, build, BuildState::Failed);
+                self.progress
+                    .task_state(task.buildid, build, BuildState::Failed);
+                self.build_states.skip_dependents(self.graph, task.buildid);
+                continue;
             }
 
             tasks_done += 1;
-            self.record_finished(task.buildid, task.result)?;
+            if self.record_finished(task.buildid, task.result)? {
+                self.store_in_cache(task.buildid);
+            }
             self.progress.task_state(
                 task.buildid,
                 self.graph.build(task.buildid),
@@ -716,20 +1929,486 @@ impl<'a> Work<'a> {
             self.ready_dependents(task.buildid);
         }
 
+        if self.failures > 0 {
+            return Ok(None);
+        }
         Ok(Some(tasks_done))
     }
 
-    pub fn run(&mut self) -> anyhow::Result<Option<usize>> {
-        let result = self.run_without_cleanup();
+    /// Counters of the work done so far.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            stats: self.file_state.stat_count(),
+            saturated_pools: self.build_states.saturated_pools(),
+            ..self.stats.clone()
+        }
+    }
+
+    /// Pass warnings collected while building on to the progress display.
+    fn report_warnings(&mut self) {
+        let warnings = self.file_state.take_warnings();
+        for warning in warnings.iter().chain(&self.build_states.take_warnings()) {
+            self.progress.warning(warning);
+        }
+    }
+
+    pub fn run(&mut self) -> anyhow::Result<RunResult> {
+        let result = self.run_without_cleanup().map(|tasks| match tasks {
+            Some(tasks) if self.build_states.unfinished() => RunResult::Stopped(tasks),
+            Some(tasks) => RunResult::Success(tasks),
+            None if signal::interrupted() => RunResult::Interrupted,
+            None => RunResult::Failed,
+        });
+        self.build_states.sample_pools(true);
         // Clean up progress before returning.
+        self.report_warnings();
         self.progress.update(&self.build_states.counts);
         self.progress.finish();
-        result
+        if self.keep_going != 1 && self.failures > 0 {
+            println!(
+                "n2: error: {} failed, {} skipped because something they depend on failed",
+                self.failures,
+                self.build_states.counts.get(BuildState::Skipped)
+            );
+        }
+        let result = result?;
+        if let RunResult::Success(_) = result {
+            if self.verify_targets && !self.dry_run && !self.targets_present()? {
+                return Ok(RunResult::Failed);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Stat the wanted files, reporting any generated one that's missing.
+    /// A phony target is checked through its inputs.  Returns false if any
+    /// was missing.
+    fn targets_present(&mut self) -> anyhow::Result<bool> {
+        let mut present = true;
+        let mut visited: HashSet<FileId> = self.targets.iter().copied().collect();
+        let mut stack = self.targets.clone();
+        while let Some(id) = stack.pop() {
+            let file = self.graph.file(id);
+            let build = match file.input {
+                Some(bid) => self.graph.build(bid),
+                // Missing source files already failed the build.
+                None => continue,
+            };
+            // The output of a phony build needn't exist, but the outputs
+            // it stands for must.
+            if build.cmdline.is_none() {
+                for &input in build.dirtying_ins() {
+                    if visited.insert(input) {
+                        stack.push(input);
+                    }
+                }
+                continue;
+            }
+            if self.file_state.restat(id, &file.name)? == MTime::Missing {
+                println!(
+                    "n2: error: {}: {} is missing though its command succeeded",
+                    build.location, file.name
+                );
+                present = false;
+            }
+        }
+        Ok(present)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    struct NoProgress;
+    impl Progress for NoProgress {
+        fn update(&mut self, _counts: &StateCounts) {}
+        fn flush(&mut self) -> Option<Duration> {
+            None
+        }
+        fn task_state(&mut self, _id: BuildId, _build: &Build, _state: BuildState) {}
+        fn completed(&mut self, _: BuildId, _: &Build, _: bool, _: Option<i32>, _: &[u8]) {}
+        fn warning(&mut self, _message: &str) {}
+        fn finish(&mut self) {}
+    }
+
+    /// What a Work borrows, for a manifest whose database lives in `dir`.
+    struct Setup {
+        graph: Graph,
+        hashes: Hashes,
+        db: db::Writer,
+        progress: NoProgress,
+    }
+
+    impl Setup {
+        fn new(dir: &std::path::Path, manifest: &str) -> anyhow::Result<Self> {
+            let mut graph = crate::load::parse("build.ninja", manifest.as_bytes())?;
+            let mut hashes = Hashes::new();
+            let db_path = dir.join(".n2_db");
+            let db = db::open(db_path.to_str().unwrap(), &mut graph, &mut hashes)?;
+            Ok(Setup {
+                graph,
+                hashes,
+                db,
+                progress: NoProgress,
+            })
+        }
+
+        fn work(&mut self) -> Work {
+            Work::new(
+                &mut self.graph,
+                &self.hashes,
+                &mut self.db,
+                &mut self.progress,
+                vec![],
+                1,
+            )
+        }
+    }
+
+    #[test]
+    fn parallelism_percent() {
+        assert_eq!(parallelism_from_percent(0, 8), 1);
+        assert_eq!(parallelism_from_percent(50, 8), 4);
+        assert_eq!(parallelism_from_percent(50, 3), 1);
+        assert_eq!(parallelism_from_percent(100, 8), 8);
+        assert_eq!(parallelism_from_percent(200, 8), 16);
+        assert_eq!(parallelism_from_percent(10, 1), 1);
+        assert_eq!(parallelism_from_percent(usize::MAX, 8), usize::MAX / 100);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn want_incrementally() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let (a, b) = (path("a"), path("b"));
+        let file = format!(
+            "
+rule touch
+  command = touch $out
+build {a}: touch
+build {b}: touch {a}
+",
+            a = a,
+            b = b
+        );
+        let mut setup = Setup::new(dir.path(), &file)?;
+        let mut work = setup.work();
+
+        work.want_file(&a)?;
+        assert_eq!(work.run()?, RunResult::Success(1));
+        // b depends on the already-built a, so only b runs.
+        work.want_file(&b)?;
+        assert_eq!(work.run()?, RunResult::Success(1));
+        // Everything wanted is already done.
+        work.want_file(&a)?;
+        work.want_file(&b)?;
+        assert_eq!(work.run()?, RunResult::Success(0));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn observer() -> anyhow::Result<()> {
+        #[derive(Default)]
+        struct Events(Vec<String>);
+        impl BuildObserver for Events {
+            fn queued(&mut self, _id: BuildId, build: &Build) {
+                self.0.push(format!("queued {}", build.location));
+            }
+            fn started(&mut self, _id: BuildId, build: &Build) {
+                self.0.push(format!("started {}", build.location));
+            }
+            fn finished(&mut self, _id: BuildId, build: &Build, success: bool, _: Duration) {
+                self.0
+                    .push(format!("finished {} {}", build.location, success));
+            }
+        }
+
+        let dir = tempfile::tempdir()?;
+        let out = dir.path().join("out").to_str().unwrap().to_string();
+        let file = format!(
+            "
+rule touch
+  command = touch $out
+build {}: touch
+",
+            out
+        );
+        let mut setup = Setup::new(dir.path(), &file)?;
+        let mut events = Events::default();
+        let mut work = setup.work();
+        work.set_observer(&mut events);
+        work.want_file(&out)?;
+        assert_eq!(work.run()?, RunResult::Success(1));
+        drop(work);
+        assert_eq!(
+            events.0,
+            vec![
+                "queued build.ninja:4",
+                "started build.ninja:4",
+                "finished build.ninja:4 true"
+            ]
+        );
+        Ok(())
+    }
+
+    /// In a diamond whose one branch fails, keep going builds the other
+    /// branch and skips the join.
+    #[cfg(unix)]
+    #[test]
+    fn keep_going_skips_dependents() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let (base, left, right, top) = (path("base"), path("left"), path("right"), path("top"));
+        let file = format!(
+            "
+rule touch
+  command = touch $out
+rule fail
+  command = false
+build {base}: touch
+build {left}: fail {base}
+build {right}: touch {base}
+build {top}: touch {left} {right}
+",
+            base = base,
+            left = left,
+            right = right,
+            top = top
+        );
+        let mut setup = Setup::new(dir.path(), &file)?;
+        let mut work = setup.work();
+        work.set_keep_going(0);
+        work.want_file(&top)?;
+        assert_eq!(work.run()?, RunResult::Failed);
+        let counts = &work.build_states.counts;
+        assert_eq!(
+            (
+                counts.get(BuildState::Done),
+                counts.get(BuildState::Failed),
+                counts.get(BuildState::Skipped)
+            ),
+            (2, 1, 1)
+        );
+        drop(work);
+        assert!(std::path::Path::new(&right).exists());
+        assert!(!std::path::Path::new(&top).exists());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn notify_changed() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let (input, mid, out) = (path("in"), path("mid"), path("out"));
+        let (header, obj) = (path("header"), path("obj"));
+        let file = format!(
+            "
+rule cp
+  command = cp $in $out
+rule cat
+  command = cat $src > $out && echo \"$out: $src\" > ${{out}}.d
+  depfile = ${{out}}.d
+build {mid}: cp {input}
+build {out}: cp {mid}
+build {obj}: cat
+  src = {header}
+",
+            input = input,
+            mid = mid,
+            out = out,
+            header = header,
+            obj = obj
+        );
+        std::fs::write(&input, "1")?;
+        std::fs::write(&header, "1")?;
+        let mut setup = Setup::new(dir.path(), &file)?;
+        let mut work = setup.work();
+        work.want_file(&out)?;
+        assert_eq!(work.run()?, RunResult::Success(2));
+
+        // Nothing changed yet; unknown files are ignored.
+        assert!(!work.notify_changed(&input)?);
+        assert!(!work.notify_changed("nonexistent")?);
+
+        std::fs::write(&input, "2")?;
+        std::fs::File::options()
+            .write(true)
+            .open(&input)?
+            .set_modified(std::time::SystemTime::now() + Duration::from_secs(10))?;
+        assert!(work.notify_changed(&input)?);
+        assert_eq!(work.run()?, RunResult::Success(2));
+
+        // A discovered input dirties the builds whose depfiles listed it.
+        work.want_file(&obj)?;
+        assert_eq!(work.run()?, RunResult::Success(1));
+        std::fs::write(&header, "2")?;
+        std::fs::File::options()
+            .write(true)
+            .open(&header)?
+            .set_modified(std::time::SystemTime::now() + Duration::from_secs(10))?;
+        assert!(work.notify_changed(&header)?);
+        assert_eq!(work.run()?, RunResult::Success(1));
+        drop(work);
+        assert_eq!(std::fs::read(&out)?, b"2");
+        assert_eq!(std::fs::read(&obj)?, b"2");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stat_once() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let (missing, out1, out2) = (path("missing"), path("out1"), path("out2"));
+        // Both builds share an input that doesn't exist, the output of a
+        // phony build; its missing state is cached rather than stat()ed again.
+        let file = format!(
+            "
+rule touch
+  command = touch $out
+build {missing}: phony
+build {out1}: touch || {missing}
+build {out2}: touch || {missing}
+",
+            missing = missing,
+            out1 = out1,
+            out2 = out2
+        );
+        let mut setup = Setup::new(dir.path(), &file)?;
+        let mut work = setup.work();
+        work.want_file(&out1)?;
+        work.want_file(&out2)?;
+        assert_eq!(work.run()?, RunResult::Success(2));
+        // The phony output once, then each output before and after running.
+        assert_eq!(work.stats().stats, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn pool_weight() -> anyhow::Result<()> {
+        let file = "
+pool p
+  depth = 4
+rule r
+  command = r
+  pool = p
+build light1: r
+build heavy: r
+  pool_weight = 4
+build light2: r
+";
+        let graph = crate::load::parse("build.ninja", file.as_bytes())?;
+        let mut states = BuildStates::new(graph.builds.next_id(), vec![("p".to_string(), 4)]);
+        let id = |name: &str| {
+            graph
+                .file(graph.lookup_file_id(name).unwrap())
+                .input
+                .unwrap()
+        };
+        let (light1, heavy, light2) = (id("light1"), id("heavy"), id("light2"));
+        for id in [light1, heavy, light2] {
+            states.enqueue(id, graph.build(id))?;
+        }
+
+        assert_eq!(states.pop_queued(&graph), Some(light1));
+        states.set(light1, graph.build(light1), BuildState::Running);
+        // heavy needs the whole pool, so must wait for light1.
+        assert_eq!(states.pop_queued(&graph), None);
+        states.set(light1, graph.build(light1), BuildState::Done);
+        assert_eq!(states.pop_queued(&graph), Some(heavy));
+        states.set(heavy, graph.build(heavy), BuildState::Running);
+        assert_eq!(states.pop_queued(&graph), None);
+        states.set(heavy, graph.build(heavy), BuildState::Done);
+        assert_eq!(states.pop_queued(&graph), Some(light2));
+        Ok(())
+    }
+
+    #[test]
+    fn conflict_doesnt_block_pool() -> anyhow::Result<()> {
+        let file = "
+rule r
+  command = r
+build a: r
+build b: r
+build c: r
+";
+        let graph = crate::load::parse("build.ninja", file.as_bytes())?;
+        let mut states = BuildStates::new(graph.builds.next_id(), vec![]);
+        let file_id = |name: &str| graph.lookup_file_id(name).unwrap();
+        let id = |name: &str| graph.file(file_id(name)).input.unwrap();
+        let (a, b, c) = (id("a"), id("b"), id("c"));
+        for id in [a, b] {
+            states.enqueue(id, graph.build(id))?;
+        }
+
+        // As if c were running and also writing a.
+        states.running_outs.insert(file_id("a"), c);
+        assert_eq!(states.pop_queued(&graph), Some(b));
+        assert_eq!(states.take_warnings().len(), 1);
+        assert_eq!(states.pop_queued(&graph), None);
+        assert!(states.take_warnings().is_empty());
+
+        states.running_outs.clear();
+        assert_eq!(states.pop_queued(&graph), Some(a));
+        Ok(())
+    }
+
+    #[test]
+    fn console_pool_depth() -> anyhow::Result<()> {
+        let file = "
+rule r
+  command = r
+  pool = console
+build a: r
+build b: r
+build c: r
+";
+        let graph = crate::load::parse("build.ninja", file.as_bytes())?;
+        let ids: Vec<BuildId> = graph.builds().map(|(id, _)| id).collect();
+        let running = |depths: Vec<(String, usize)>| -> anyhow::Result<usize> {
+            let mut states = BuildStates::new(graph.builds.next_id(), depths);
+            for &id in &ids {
+                states.enqueue(id, graph.build(id))?;
+            }
+            let mut running = 0;
+            while let Some(id) = states.pop_queued(&graph) {
+                states.set(id, graph.build(id), BuildState::Running);
+                running += 1;
+            }
+            Ok(running)
+        };
+        // One at a time by default, or as many as a declared depth allows.
+        assert_eq!(running(vec![])?, 1);
+        assert_eq!(running(vec![("console".to_string(), 2)])?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn pool_saturation() {
+        let mut pool = PoolState::new(1);
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        pool.running += 1;
+        pool.sample(at(10), false);
+        // A build waits while the pool is full.
+        pool.queued.push_back((BuildId::from(1), 1));
+        pool.sample(at(20), false);
+        pool.sample(at(50), false);
+        pool.running -= 1;
+        pool.sample(at(60), false);
+        assert_eq!(pool.saturated, Duration::from_millis(40));
+        // An unbounded pool is never saturated.
+        let mut pool = PoolState::new(0);
+        pool.queued.push_back((BuildId::from(0), 1));
+        pool.sample(at(0), false);
+        pool.sample(at(100), true);
+        assert!(pool.saturated.is_zero());
+    }
+
     #[test]
     fn build_cycle() -> Result<(), anyhow::Error> {
         let file = "
@@ -737,9 +2416,9 @@ build a: phony b
 build b: phony c
 build c: phony a
 ";
-        let mut graph = crate::load::parse("build.ninja".to_string(), file.as_bytes().to_vec())?;
+        let mut graph = crate::load::parse("build.ninja", file.as_bytes())?;
         let a_id = graph.file_id(&mut "a".to_string());
-        let mut states = crate::work::BuildStates::new(graph.builds.next_id(), vec![]);
+        let mut states = BuildStates::new(graph.builds.next_id(), vec![]);
         let mut stack = Vec::new();
         match states.want_file(&graph, &mut stack, a_id) {
             Ok(_) => panic!("expected build cycle error"),
@@ -747,4 +2426,46 @@ build c: phony a
         }
         Ok(())
     }
+
+    #[test]
+    fn deep_chain() -> anyhow::Result<()> {
+        // Deep enough to overflow the stack of a recursive traversal.
+        let depth = 100_000;
+        let mut file = String::from("rule r\n  command = r\nbuild f0: r src\n");
+        for i in 1..depth {
+            file.push_str(&format!("build f{}: r f{}\n", i, i - 1));
+        }
+        let graph = crate::load::parse("build.ninja", file.as_bytes())?;
+        let mut states = BuildStates::new(graph.builds.next_id(), vec![]);
+        let mut stack = Vec::new();
+        let top = graph.lookup_file_id(&format!("f{}", depth - 1)).unwrap();
+        states.want_file(&graph, &mut stack, top)?;
+        assert!(stack.is_empty());
+        assert_eq!(states.counts.get(BuildState::Want), depth - 1);
+        let first = graph
+            .file(graph.lookup_file_id("f0").unwrap())
+            .input
+            .unwrap();
+        assert_eq!(states.pop_ready(), Some(first));
+        Ok(())
+    }
+
+    #[test]
+    fn unchecked_count() -> anyhow::Result<()> {
+        let file = "
+rule r
+  command = r
+build a: r
+build b: r
+build c: r a b
+";
+        let graph = crate::load::parse("build.ninja", file.as_bytes())?;
+        let mut states = BuildStates::new(graph.builds.next_id(), vec![]);
+        let mut stack = Vec::new();
+        states.want_file(&graph, &mut stack, graph.lookup_file_id("c").unwrap())?;
+        // a and b are ready to be checked; c waits on them.
+        assert_eq!(states.counts.unchecked(), 2);
+        assert_eq!(states.counts.get(BuildState::Want), 1);
+        Ok(())
+    }
 }
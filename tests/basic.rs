@@ -85,11 +85,20 @@ fn empty_file() -> anyhow::Result<()> {
     let out = space.run(&mut n2_command(vec![]))?;
     assert_eq!(
         std::str::from_utf8(&out.stdout)?,
-        "n2: error: no path specified and no default\n"
+        "n2: error: no targets defined in build.ninja\n"
     );
     Ok(())
 }
 
+#[test]
+fn comments_only_file() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write("build.ninja", "# Nothing here.\n\n")?;
+    let out = space.run(&mut n2_command(vec![]))?;
+    assert_output_contains(&out, "no targets defined in build.ninja");
+    Ok(())
+}
+
 #[cfg(unix)]
 const TOUCH_RULE: &str = "
 rule touch
@@ -115,6 +124,40 @@ fn basic_build() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn chained_outputs() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build mid: touch in",
+            "build out: touch mid",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+
+    // A missing leaf output reruns just its build.
+    std::fs::remove_file(space.dir.path().join("out"))?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    // A missing intermediate output is regenerated, dirtying what follows.
+    std::fs::remove_file(space.dir.path().join("mid"))?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work");
+
+    Ok(())
+}
+
 #[test]
 fn create_subdir() -> anyhow::Result<()> {
     // Run a build rule that needs a subdir to be automatically created.
@@ -130,6 +173,202 @@ fn create_subdir() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+fn output_dir_is_file() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build foo: touch in",
+            "build foo/bar: touch in",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+    space.run_expect(&mut n2_command(vec!["foo"]))?;
+    let out = space.run(&mut n2_command(vec!["foo/bar"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(
+        &out,
+        "build.ninja:6: output foo/bar needs foo to be a directory, \
+         but it is a file, generated by the build at build.ninja:5",
+    );
+
+    Ok(())
+}
+
+#[test]
+fn include_dir() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    std::fs::create_dir_all(space.dir.path().join("a"))?;
+    std::fs::create_dir_all(space.dir.path().join("b"))?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "include rules.ninja", "build out: touch in", ""].join("\n"),
+    )?;
+    space.write("b/rules.ninja", "rule unused\n  command = false\n")?;
+    space.write("in", "")?;
+
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "read rules.ninja");
+
+    let out = space.run(&mut n2_command(vec!["--include-dir", "a", "out"]))?;
+    assert_output_contains(
+        &out,
+        "read rules.ninja: not found; tried rules.ninja, a/rules.ninja",
+    );
+
+    let out = space.run_expect(&mut n2_command(vec![
+        "--include-dir",
+        "a",
+        "--include-dir",
+        "b",
+        "out",
+    ]))?;
+    assert!(out.status.success());
+    assert!(space.read("out").is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn dedup_builds() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch in", "build out: touch in", ""].join("\n"),
+    )?;
+    space.write("in", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["--dedup-builds", "out"]))?;
+    assert_output_contains(
+        &out,
+        "n2: warning: build.ninja:6: duplicates the build at build.ninja:5; running it once",
+    );
+    assert_output_contains(&out, "ran 1 task");
+
+    // Builds differing only in their environment aren't duplicates.
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build out: touch in",
+            "build out: touch in\n  env.FOO = 1",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run(&mut n2_command(vec!["--dedup-builds", "out"]))?;
+    assert_output_contains(&out, "multiple rules generate out");
+
+    Ok(())
+}
+
+#[test]
+fn tool_list() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    let out = space.run(&mut n2_command(vec!["-t", "list"]))?;
+    assert_output_contains(
+        &out,
+        "\n  format  print build.ninja with canonical formatting\n",
+    );
+    let out = space.run(&mut n2_command(vec!["-t", "nope"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(
+        &out,
+        "unknown -t \"nope\", valid tools are: browse, compdb, format",
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn variable_override() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+cflags = -O0
+rule cc
+  command = echo $cflags > $out
+build out: cc
+",
+    )?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_eq!(space.read("out")?, b"-O0\n");
+
+    // The changed command line makes the build dirty.
+    let out = space.run_expect(&mut n2_command(vec!["cflags=-O2 -g", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    assert_eq!(space.read("out")?, b"-O2 -g\n");
+    Ok(())
+}
+
+#[test]
+fn empty_phony() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build marker: phony",
+            "build out: touch marker",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["marker"]))?;
+    assert!(out.status.success());
+    assert_output_contains(&out, "no work to do");
+    // Nothing ran, so nothing was recorded.
+    assert_eq!(space.metadata(".n2_db")?.len(), 0);
+
+    // Builds depending on it aren't dirtied by it.
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+
+    Ok(())
+}
+
+#[test]
+fn dupbuild() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build out: touch in",
+            "build out other: touch in2",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+    space.write("in2", "")?;
+
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(
+        &out,
+        "n2: error: build.ninja:6:7: multiple rules generate out",
+    );
+
+    // With warn, the first build keeps the output, and the second still
+    // generates its other output.
+    let out = space.run_expect(&mut n2_command(vec!["--dupbuild=warn", "out", "other"]))?;
+    assert_output_contains(
+        &out,
+        "n2: warning: build.ninja:6:7: multiple rules generate out;",
+    );
+    assert_output_contains(&out, "ran 2 tasks");
+    assert!(space.read("other").is_ok());
+
+    Ok(())
+}
+
 #[cfg(unix)]
 #[test]
 fn generate_build_file() -> anyhow::Result<()> {
@@ -164,6 +403,59 @@ EOT
     Ok(())
 }
 
+#[cfg(unix)]
+#[test]
+fn generate_build_file_unchanged() -> anyhow::Result<()> {
+    // A generator that runs but leaves build.ninja as is shouldn't cause a
+    // reload.
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule regen
+  command = echo regen >> regen.log
+  generator = 1
+build build.ninja: regen
+rule touch
+  command = touch $out
+build out: touch
+",
+    )?;
+
+    // Both tasks count towards a single build rather than starting over.
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    assert_eq!(space.read("regen.log")?, b"regen\n");
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work");
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn generate_included_file() -> anyhow::Result<()> {
+    // A generator that leaves build.ninja as is but rewrites a file it
+    // includes still causes a reload.
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule regen
+  command = printf 'rule write\\n  command = echo new > $$out\\n' > inc.ninja
+  generator = 1
+build build.ninja: regen
+include inc.ninja
+build out: write
+",
+    )?;
+    space.write("inc.ninja", "rule write\n  command = echo old > $out\n")?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_eq!(space.read("out")?, b"new\n");
+    Ok(())
+}
+
 #[cfg(unix)]
 #[test]
 fn generate_rsp_file() -> anyhow::Result<()> {
@@ -243,3 +535,1796 @@ quiet c
     );
     Ok(())
 }
+
+/// Builds declared in subninja files, which are parsed on worker threads.
+#[cfg(unix)]
+#[test]
+fn subninja() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule write
+  command = echo $msg > $out
+subninja a.ninja
+subninja b.ninja
+build out: write a b
+  msg = top
+",
+    )?;
+    space.write(
+        "a.ninja",
+        "
+msg = from a
+build a: write
+",
+    )?;
+    space.write(
+        "b.ninja",
+        "
+msg = from b
+build b: write
+msg = unused
+",
+    )?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_eq!(space.read("a")?, b"from a\n");
+    assert_eq!(space.read("b")?, b"from b\n");
+    assert_eq!(space.read("out")?, b"top\n");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn subninja_rule_scope() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule write
+  command = echo top > $out
+subninja a.ninja
+subninja b.ninja
+build out: write a b
+",
+    )?;
+    space.write(
+        "a.ninja",
+        "
+rule write
+  command = echo a > $out
+build a: write
+",
+    )?;
+    space.write(
+        "b.ninja",
+        "
+build b: write
+",
+    )?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_eq!(space.read("a")?, b"a\n");
+    // a.ninja's rule doesn't leak into its sibling or parent.
+    assert_eq!(space.read("b")?, b"top\n");
+    assert_eq!(space.read("out")?, b"top\n");
+    Ok(())
+}
+
+/// Subninjas are loaded as if in place of their statements, even though
+/// they're parsed in parallel.
+#[cfg(unix)]
+#[test]
+fn subninja_declaration_order() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule write
+  command = echo x${N2_TEST_VAR}$msg > $out
+subninja a.ninja
+# n2: env_vars = 1
+build out: write
+  msg = top
+build env: write
+",
+    )?;
+    space.write(
+        "a.ninja",
+        "
+subninja b.ninja
+build out: write
+  msg = a
+build noenv: write
+",
+    )?;
+    space.write(
+        "b.ninja",
+        "
+build out: write
+  msg = b
+",
+    )?;
+    let mut cmd = n2_command(vec!["--dupbuild=warn", "out", "env", "noenv"]);
+    cmd.env("N2_TEST_VAR", "env");
+    let out = space.run_expect(&mut cmd)?;
+    // The most deeply nested build comes first, so it wins.
+    assert_output_contains(&out, "a.ninja:3:7: multiple rules generate out");
+    assert_output_contains(&out, "build.ninja:6:7: multiple rules generate out");
+    assert_eq!(space.read("out")?, b"xb\n");
+    // The pragma only applies to what follows it.
+    assert_eq!(space.read("noenv")?, b"x\n");
+    assert_eq!(space.read("env")?, b"xenv\n");
+
+    // A subninja can't use a rule its parent defines after it.
+    space.write(
+        "build.ninja",
+        "
+subninja c.ninja
+rule late
+  command = touch $out
+",
+    )?;
+    space.write("c.ninja", "build c: late\n")?;
+    let out = space.run(&mut n2_command(vec!["c"]))?;
+    assert_output_contains(&out, "c.ninja:1:10: unknown rule \"late\"");
+    Ok(())
+}
+
+/// Variables can be resolved from the environment, but only when requested.
+#[cfg(unix)]
+#[test]
+fn env_vars() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule write
+  command = echo x${N2_TEST_VAR}x > $out
+build out: write
+",
+    )?;
+    let mut cmd = n2_command(vec!["out"]);
+    cmd.env("N2_TEST_VAR", "env");
+    space.run_expect(&mut cmd)?;
+    assert_eq!(space.read("out")?, b"xx\n");
+
+    let mut cmd = n2_command(vec!["--env-vars", "out"]);
+    cmd.env("N2_TEST_VAR", "env");
+    space.run_expect(&mut cmd)?;
+    assert_eq!(space.read("out")?, b"xenvx\n");
+    Ok(())
+}
+
+#[test]
+fn env_vars_pragma() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+# n2: env_vars = 1
+# n2: no_such_thing
+rule write
+  command = echo x${N2_TEST_VAR}x > $out
+build out: write
+",
+    )?;
+    let mut cmd = n2_command(vec!["out"]);
+    cmd.env("N2_TEST_VAR", "env");
+    let out = space.run_expect(&mut cmd)?;
+    assert_output_contains(&out, "ignoring unknown directive \"no_such_thing\"");
+    assert_eq!(space.read("out")?, b"xenvx\n");
+
+    // A directive in an included file stops applying at its end.
+    space.write(
+        "build.ninja",
+        "
+rule write
+  command = echo x${N2_TEST_VAR}x > $out
+include inc.ninja
+build out: write
+",
+    )?;
+    space.write("inc.ninja", "# n2: env_vars = 1\nbuild inc: write\n")?;
+    let mut cmd = n2_command(vec!["out", "inc"]);
+    cmd.env("N2_TEST_VAR", "env");
+    space.run_expect(&mut cmd)?;
+    assert_eq!(space.read("inc")?, b"xenvx\n");
+    assert_eq!(space.read("out")?, b"xx\n");
+    Ok(())
+}
+
+/// A file with an mtime in the future produces a clock skew warning.
+#[test]
+fn future_mtime_warning() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch in", ""].join("\n"),
+    )?;
+    space.write("in", "")?;
+    let future = std::time::SystemTime::now() + std::time::Duration::from_secs(3600);
+    std::fs::File::options()
+        .write(true)
+        .open(space.dir.path().join("in"))?
+        .set_modified(future)?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "warning: in has modification time");
+
+    // A frontend gets the warning as an event rather than on stdout.
+    let out = space.run_expect(&mut n2_command(vec!["--frontend", "events.json", "out"]))?;
+    assert!(!String::from_utf8_lossy(&out.stdout).contains("warning"));
+    let events = String::from_utf8(space.read("events.json")?)?;
+    assert!(events.contains("{\"event\":\"warning\",\"message\":\"in has modification time"));
+    Ok(())
+}
+
+/// A phony alias for a real file, used as an input, doesn't cause repeated
+/// work but does propagate changes to the file it aliases.
+#[cfg(unix)]
+#[test]
+fn phony_alias() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule copy
+  command = cp $in $out
+rule touch
+  command = touch $out
+build real: copy src
+build alias: phony real
+build down: touch | alias
+",
+    )?;
+    space.write("src", "1")?;
+    let out = space.run_expect(&mut n2_command(vec!["down"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+
+    let out = space.run_expect(&mut n2_command(vec!["down"]))?;
+    assert_output_contains(&out, "no work");
+
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    space.write("src", "2")?;
+    let out = space.run_expect(&mut n2_command(vec!["down"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    Ok(())
+}
+
+/// --only runs just the build for the target, even if it's up to date and
+/// its inputs are not.
+#[cfg(unix)]
+#[test]
+fn only() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule copy
+  command = cp $in $out
+build mid: copy src
+build out: copy mid
+",
+    )?;
+    space.write("src", "1")?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+
+    space.write("src", "2")?;
+    let out = space.run_expect(&mut n2_command(vec!["--only", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    assert_eq!(space.read("mid")?, b"1");
+
+    // Up to date, but runs anyway.
+    let out = space.run_expect(&mut n2_command(vec!["--only", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    let out = space.run(&mut n2_command(vec!["--only", "x", "out", "y"]))?;
+    assert_output_contains(&out, "unknown paths requested: [\"x\", \"y\"]");
+    Ok(())
+}
+
+#[test]
+fn force() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule copy
+  command = cp $in $out
+build mid: copy src
+build out: copy mid
+",
+    )?;
+    space.write("src", "1")?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+
+    let out = space.run_expect(&mut n2_command(vec!["-B", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    let out = space.run_expect(&mut n2_command(vec!["--force-all", "out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+
+    // The forced builds were recorded as up to date.
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+#[test]
+fn touch() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule fail
+  command = exit 1
+build out: fail in
+",
+    )?;
+    space.write("in", "")?;
+    space.write("out", "made elsewhere")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["--touch", "out"]))?;
+    assert_output_contains(&out, "marked 1 tasks up to date");
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+    assert_eq!(space.read("out")?, b"made elsewhere");
+    Ok(())
+}
+
+#[test]
+fn targets_file() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule touch
+  command = touch $out
+build a: touch
+build b: touch
+build c: touch
+",
+    )?;
+    space.write("targets", "# Some targets.\na\n\n  b\n")?;
+    let out = space.run_expect(&mut n2_command(vec!["--targets-file", "targets"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    assert!(space.read("c").is_err());
+
+    space.write("targets", "a\nx\ny\n")?;
+    let out = space.run(&mut n2_command(vec!["--targets-file", "targets"]))?;
+    assert_output_contains(&out, "unknown paths requested: [\"x\", \"y\"]");
+    Ok(())
+}
+
+#[test]
+fn missing_manifest() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    let out = space.run(&mut n2_command(vec![]))?;
+    assert_output_contains(&out, "no build.ninja in the current directory");
+
+    space.write("build.ninja", "include missing.ninja\n")?;
+    let out = space.run(&mut n2_command(vec![]))?;
+    assert_output_contains(&out, "read missing.ninja: ");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn output_sync() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule fail
+  command = echo oops; exit 3
+build out: fail
+",
+    )?;
+    let out = space.run(&mut n2_command(vec!["--output-sync", "out"]))?;
+    assert_output_contains(
+        &out,
+        "---- begin: echo oops; exit 3\noops\n---- end: exit status 3\n",
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn group_output() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+pool test
+  depth = 1
+rule say
+  command = echo said $out && touch $out
+  description = say $out
+build t1: say
+  pool = test
+build t2: say | t1
+  pool = test
+build c1: say | t1
+build c2: say | t2
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["--group-output", "test", "c1", "c2"]))?;
+    let stdout = std::str::from_utf8(&out.stdout)?;
+    // The test pool's output comes after everything else, together.
+    let group = stdout.find("---- output of pool test\n").unwrap();
+    assert!(stdout.find("said c1").unwrap() < group, "{}", stdout);
+    assert!(stdout.find("said c2").unwrap() < group, "{}", stdout);
+    assert_output_contains(
+        &out,
+        "---- output of pool test\nsay t1\nsaid t1\nsay t2\nsaid t2\n",
+    );
+    Ok(())
+}
+
+#[test]
+fn manifest_from_stdin() -> anyhow::Result<()> {
+    use std::io::Write;
+    let space = TestSpace::new()?;
+    let run_args = |args: Vec<&str>, manifest: &str| -> std::io::Result<std::process::Output> {
+        let mut child = n2_command([vec!["-f", "-"], args].concat())
+            .current_dir(space.dir.path())
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()?;
+        child.stdin.take().unwrap().write_all(manifest.as_bytes())?;
+        child.wait_with_output()
+    };
+    let run = |manifest: &str| run_args(vec!["out"], manifest);
+    let manifest = [TOUCH_RULE, "build out: touch", ""].join("\n");
+    let out = run(&manifest)?;
+    assert_output_contains(&out, "ran 1 task");
+    let out = run(&manifest)?;
+    assert_output_contains(&out, "no work to do");
+
+    let out = run("build out: nope\n")?;
+    assert_output_contains(&out, "<stdin>:1:12: unknown rule");
+
+    // Tools read the manifest from stdin too.
+    let out = run_args(vec!["-t", "format"], "build  out :  phony\n")?;
+    assert_eq!(std::str::from_utf8(&out.stdout)?, "build out: phony\n");
+    let out = run_args(vec!["-t", "compdb"], &manifest)?;
+    assert_output_contains(&out, "\"output\": \"out\"");
+    Ok(())
+}
+
+#[test]
+fn manifest_flag() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "other.ninja",
+        &[TOUCH_RULE, "build out: touch", ""].join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["-f", "other.ninja", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+/// Build files are loaded the same whether mapped or read, and files that
+/// can't be mapped, like pipes, are read even with --mmap.
+#[cfg(unix)]
+#[test]
+fn mmap_manifest() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch", ""].join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["--mmap", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    space.write(
+        "pipe.ninja",
+        &[TOUCH_RULE, "build piped: touch", ""].join("\n"),
+    )?;
+    for flags in ["", "--mmap"] {
+        let out =
+            space.run_expect(std::process::Command::new("/bin/sh").arg("-c").arg(format!(
+                "cat pipe.ninja | {} {} -f /dev/stdin piped",
+                n2_binary().display(),
+                flags
+            )))?;
+        assert_output_contains(&out, "ran 1 task");
+        std::fs::remove_file(space.dir.path().join("piped"))?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn max_fail_output() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule fail
+  command = printf '1\\n2\\n3\\n4\\n'; exit 1
+build out: fail
+",
+    )?;
+    let out = space.run(&mut n2_command(vec![
+        "--max-fail-output",
+        "1",
+        "--fail-output-file",
+        "fail.log",
+        "out",
+    ]))?;
+    assert_output_contains(
+        &out,
+        "1\n... (truncated, 3 more lines; full output in fail.log)\n",
+    );
+    let log = String::from_utf8(space.read("fail.log")?)?;
+    assert!(log.ends_with("\n1\n2\n3\n4\n"));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn command_log() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule fail
+  command = exit 3
+build out: fail
+",
+    )?;
+    space.run(&mut n2_command(vec!["--command-log", "out"]))?;
+    space.run(&mut n2_command(vec!["--command-log", "out"]))?;
+    let log = String::from_utf8(space.read(".n2_commands.log")?)?;
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 2);
+    let fields: Vec<&str> = lines[0].split('\t').collect();
+    assert_eq!(&fields[2..], ["3", "exit 3"]);
+    let start: f64 = fields[0].parse()?;
+    let end: f64 = fields[1].parse()?;
+    assert!(start > 0.0 && start <= end);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn rule_env() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule greet
+  command = echo $$GREETING $$NAME > $out
+  env.GREETING = hello
+  env.NAME = rule
+build out: greet
+  env.NAME = build
+",
+    )?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_eq!(space.read("out")?, b"hello build\n");
+
+    // Without opting in, env doesn't affect whether the build is up to date.
+    space.write(
+        "build.ninja",
+        "
+rule greet
+  command = echo $$GREETING $$NAME > $out
+  env.GREETING = bye
+build out: greet
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work");
+
+    // With hash_env, it does.
+    space.write(
+        "build.ninja",
+        "
+# n2: hash_env = 1
+rule greet
+  command = echo $$GREETING $$NAME > $out
+  env.GREETING = bye
+build out: greet
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    assert_eq!(space.read("out")?, b"bye\n");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn resource_usage_stats() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch in", ""].join("\n"),
+    )?;
+    space.write("in", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["-d", "stats", "out"]))?;
+    assert_output_contains(&out, "MB, building out\n");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn depfile_dir() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    // The command runs in sub/ and writes depfile paths relative to it.
+    space.write(
+        "build.ninja",
+        "
+rule cc
+  command = cd sub && touch ../out && echo 'out: bar.h' > ../out.d
+  depfile = out.d
+build out: cc
+",
+    )?;
+    std::fs::create_dir(space.dir.path().join("sub"))?;
+    space.write("sub/bar.h", "")?;
+
+    // Without --depfile-dir, bar.h is looked for in the wrong place.
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "depfile references nonexistent bar.h");
+
+    space.run_expect(&mut n2_command(vec!["--depfile-dir", "sub", "out"]))?;
+    let out = space.run_expect(&mut n2_command(vec!["--depfile-dir", "sub", "out"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+/// A depfile path spelled non-canonically in a subninja is still read after
+/// the build, and the dependencies it lists are tracked.
+#[cfg(unix)]
+#[test]
+fn subninja_relative_depfile() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cc
+  command = touch out && echo 'out: foo.h' > sub/../out.d
+subninja sub/build.ninja
+",
+    )?;
+    std::fs::create_dir(space.dir.path().join("sub"))?;
+    space.write(
+        "sub/build.ninja",
+        "
+build out: cc
+  depfile = ./sub/../out.d
+",
+    )?;
+    space.write("foo.h", "")?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+
+    // The header the depfile listed is a dependency.
+    space.write("foo.h", "changed")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn depfile_lists_own_output() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cc
+  command = touch out && echo 'out: in out' > out.d
+  depfile = out.d
+build out: cc in
+",
+    )?;
+    space.write("in", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(
+        &out,
+        "n2: warning: build.ninja:5: depfile lists the build's own output out; ignoring it",
+    );
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn color_diagnostics() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule show
+  command = echo \"[$$CLICOLOR_FORCE]\"
+build a: show
+build b: show
+  env.CLICOLOR_FORCE = 0
+",
+    )?;
+    let out = space.run_expect(
+        n2_command(vec!["--color-diagnostics=always", "a"]).env_remove("CLICOLOR_FORCE"),
+    )?;
+    assert_output_contains(&out, "[1]");
+    // A build's own setting wins.
+    let out = space.run_expect(&mut n2_command(vec!["--color-diagnostics=always", "b"]))?;
+    assert_output_contains(&out, "[0]");
+    // By default it's only set when n2's output is a terminal, which here it
+    // isn't.
+    let out = space
+        .run_expect(n2_command(vec!["--color-diagnostics", "a"]).env_remove("CLICOLOR_FORCE"))?;
+    assert_output_contains(&out, "[]");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn cache() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule gen
+  command = echo run >> log && cp $in $out
+build out: gen in
+",
+    )?;
+    space.write("in", "1")?;
+    let cache_args = |target| vec!["--cache-dir", "cache", target];
+    space.run_expect(&mut n2_command(cache_args("out")))?;
+
+    // With the output and db gone, the output is restored from the cache.
+    std::fs::remove_file(space.dir.path().join("out"))?;
+    std::fs::remove_file(space.dir.path().join(".n2_db"))?;
+    space.run_expect(&mut n2_command(cache_args("out")))?;
+    assert_eq!(space.read("out")?, b"1");
+    assert_eq!(space.read("log")?, b"run\n");
+
+    // A changed input misses the cache.
+    space.write("in", "2")?;
+    space.run_expect(&mut n2_command(cache_args("out")))?;
+    assert_eq!(space.read("out")?, b"2");
+    assert_eq!(space.read("log")?, b"run\nrun\n");
+    Ok(())
+}
+
+/// An input that is the output of a phony build is keyed by the content of
+/// the files behind it.
+#[cfg(unix)]
+#[test]
+fn cache_phony_input() -> anyhow::Result<()> {
+    let first = TestSpace::new()?;
+    let second = TestSpace::new()?;
+    let cache = first.dir.path().join("cache");
+    for (space, content) in [(&first, "v1"), (&second, "v2")] {
+        space.write(
+            "build.ninja",
+            "
+rule cat
+  command = cat a.c > $out
+build srcs: phony a.c
+build out: cat srcs
+",
+        )?;
+        space.write("a.c", content)?;
+        let out = space.run_expect(&mut n2_command(vec![
+            "--cache-dir",
+            cache.to_str().unwrap(),
+            "out",
+        ]))?;
+        assert!(!String::from_utf8_lossy(&out.stdout).contains("cache hit"));
+        assert_eq!(space.read("out")?, content.as_bytes());
+    }
+    Ok(())
+}
+
+/// Only builds recorded as up to date are cached, and optional outputs they
+/// didn't produce don't stop them from being cached.
+#[cfg(unix)]
+#[test]
+fn cache_missing_outputs() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule gen
+  command = echo run >> log && touch out
+  optional_outputs = .map
+build out | out.map: gen
+rule broken
+  command = true
+build never: broken
+",
+    )?;
+    let cache_args = |target| vec!["--cache-dir", "cache", target];
+    let out = space.run_expect(&mut n2_command(cache_args("out")))?;
+    assert!(!String::from_utf8_lossy(&out.stdout).contains("cache:"));
+    std::fs::remove_file(space.dir.path().join("out"))?;
+    std::fs::remove_file(space.dir.path().join(".n2_db"))?;
+    space.run_expect(&mut n2_command(cache_args("out")))?;
+    assert_eq!(space.read("log")?, b"run\n");
+    assert!(space.read("out.map").is_err());
+
+    // A build that didn't write its output isn't stored.
+    let entries = std::fs::read_dir(space.dir.path().join("cache"))?.count();
+    let out = space.run_expect(&mut n2_command(cache_args("never")))?;
+    assert!(!String::from_utf8_lossy(&out.stdout).contains("cache:"));
+    assert_eq!(
+        std::fs::read_dir(space.dir.path().join("cache"))?.count(),
+        entries
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn no_rebuild() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build mid: touch in",
+            "build alias: phony mid",
+            "build out: touch alias",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+
+    // Nothing exists yet, so everything would run, but nothing does.
+    let out = space.run(&mut n2_command(vec!["--no-rebuild", "out"]))?;
+    assert_eq!(out.status.code(), Some(2));
+    assert_output_contains(&out, "would run: touch mid\nn2: would run: touch out\n");
+    assert!(space.read("mid").is_err());
+
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    let out = space.run_expect(&mut n2_command(vec!["--no-rebuild", "out"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+#[test]
+fn print_dirty() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build mid: touch in",
+            "build out: touch mid",
+            "build other: touch",
+            "  description = making other",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+    space.run_expect(&mut n2_command(vec!["other"]))?;
+
+    let out = space.run_expect(&mut n2_command(vec!["--print-dirty", "out", "other"]))?;
+    assert_output_contains(
+        &out,
+        "mid: touch mid\nout: touch out\nn2: 2 tasks would run\n",
+    );
+    assert!(space.read("mid").is_err());
+
+    std::fs::remove_file(space.dir.path().join("other"))?;
+    let out = space.run_expect(&mut n2_command(vec!["--print-dirty", "other"]))?;
+    assert_output_contains(&out, "other: making other\nn2: 1 tasks would run\n");
+
+    space.run_expect(&mut n2_command(vec!["out", "other"]))?;
+    let out = space.run_expect(&mut n2_command(vec!["--print-dirty", "out", "other"]))?;
+    assert_output_contains(&out, "n2: no work to do");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn shared_output_not_raced() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    // Both commands write `out`, and fail if they run at the same time.
+    space.write(
+        "build.ninja",
+        "
+rule w
+  command = mkdir lock && sleep 0.2 && touch out $extra && rmdir lock
+build out: w
+build out b: w
+  extra = b
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec![
+        "--dupbuild=warn",
+        "-j",
+        "2",
+        "out",
+        "b",
+    ]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    // Either may start first.
+    assert_output_contains(&out, "out is also written by running build build.ninja:");
+    assert_output_contains(&out, "; waiting for it to finish");
+    Ok(())
+}
+
+#[test]
+fn stop_after() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build a: touch",
+            "build b: touch a",
+            "build c: touch b",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["--stop-after", "2", "c"]))?;
+    assert_output_contains(
+        &out,
+        "n2: stopped after 2 tasks, as limited by --stop-after",
+    );
+    assert!(space.read("b").is_ok());
+    assert!(space.read("c").is_err());
+
+    let out = space.run_expect(&mut n2_command(vec!["--stop-after", "2", "c"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn default_pool_depth() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    // Each command fails if another one is running at the same time.
+    space.write(
+        "build.ninja",
+        "
+# n2: default_pool_depth = 1
+rule exclusive
+  command = mkdir lock && sleep 0.1 && rmdir lock && touch $out
+build a: exclusive
+build b: exclusive
+build c: exclusive
+build all: phony a b c
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["-j3", "all"]))?;
+    assert_output_contains(&out, "ran 3 tasks");
+    Ok(())
+}
+
+/// A -j without a number leaves the target after it alone.
+#[test]
+fn bare_parallelism_flag() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch", ""].join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["-j", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    space.read("out")?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn frontend_events() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule greet
+  command = echo \"hi\" && touch $out
+  description = GREET $out
+build out: greet
+",
+    )?;
+    space.run_expect(&mut n2_command(vec!["--frontend", "events.json", "out"]))?;
+    let events = String::from_utf8(space.read("events.json")?)?;
+    let lines: Vec<&str> = events.lines().collect();
+    let started = lines
+        .iter()
+        .find(|line| line.contains("\"event\":\"started\""))
+        .unwrap();
+    assert!(started.contains("\"description\":\"GREET out\""));
+    assert!(started.contains("\"command\":\"echo \\\"hi\\\" && touch out\""));
+    let id = started
+        .split("\"id\":")
+        .nth(1)
+        .unwrap()
+        .split(',')
+        .next()
+        .unwrap();
+    let finished = lines
+        .iter()
+        .find(|line| line.contains("\"event\":\"finished\""))
+        .unwrap();
+    assert_eq!(
+        *finished,
+        format!(
+            "{{\"event\":\"finished\",\"id\":{},\"success\":true,\"exit_code\":0,\"output\":\"hi\\n\"}}",
+            id
+        )
+    );
+    assert!(lines.contains(&"{\"event\":\"status\",\"total\":1,\"done\":1,\"running\":0}"));
+    assert_eq!(lines.last(), Some(&"{\"event\":\"done\"}"));
+    Ok(())
+}
+
+/// Failed and skipped builds count as done in status events.
+#[cfg(unix)]
+#[test]
+fn frontend_counts_failures() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "rule fail",
+            "  command = false",
+            "build bad: fail",
+            "build after: touch bad",
+            "build good: touch",
+            "build all: phony after good",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.run(&mut n2_command(vec![
+        "--frontend",
+        "events.json",
+        "-k",
+        "0",
+        "all",
+    ]))?;
+    let events = String::from_utf8(space.read("events.json")?)?;
+    assert!(events
+        .lines()
+        .any(|line| line.contains("\"event\":\"finished\"") && line.contains("\"success\":false")));
+    let last_status = events
+        .lines()
+        .filter(|line| line.contains("\"event\":\"status\""))
+        .last()
+        .unwrap();
+    assert_eq!(
+        last_status,
+        "{\"event\":\"status\",\"total\":4,\"done\":4,\"running\":0}"
+    );
+    Ok(())
+}
+
+#[test]
+fn rule_target() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "rule other",
+            "  command = touch $out",
+            "build a: touch",
+            "build b | b.implicit: touch",
+            "build c: other",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["@rule:touch"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    assert!(space.read("a").is_ok());
+    assert!(space.read("b").is_ok());
+    assert!(space.read("c").is_err());
+
+    let out = space.run(&mut n2_command(vec!["@rule:nope"]))?;
+    assert_output_contains(&out, "unknown rule \"nope\"");
+    Ok(())
+}
+
+#[test]
+fn debug_graph_cycle() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+build a: phony b
+build b: phony c d
+build c: phony a
+build d: phony b
+",
+    )?;
+    let out = space.run(&mut n2_command(vec!["a"]))?;
+    assert_output_contains(&out, "dependency cycle: a -> b -> c -> a\n");
+
+    let out = space.run(&mut n2_command(vec!["-d", "graph-cycle", "a"]))?;
+    assert_output_contains(
+        &out,
+        "dependency cycle: a -> b -> c -> a\nall 4 files in cycles with a:\n  a\n  b\n  c\n  d\n",
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn add_missing_deps() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule gen
+  command = echo gen > $out
+rule cc
+  command = touch out && echo 'out: gen.h' > out.d
+  depfile = out.d
+build gen.h: gen
+build out: cc
+",
+    )?;
+    space.run_expect(&mut n2_command(vec!["gen.h"]))?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(
+        &out,
+        "used generated file gen.h, but has no dependency path to it",
+    );
+
+    let out = space.run_expect(&mut n2_command(vec!["--add-missing-deps", "out"]))?;
+    assert_output_contains(&out, "warning: build.ninja:8 used generated file gen.h");
+    assert_output_contains(&out, "adding it as an order-only input");
+
+    // With gen.h gone, it's regenerated before out, which it dirties.
+    std::fs::remove_file(space.dir.path().join("gen.h"))?;
+    let out = space.run_expect(&mut n2_command(vec!["--add-missing-deps", "out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    assert_eq!(space.read("gen.h")?, b"gen\n");
+
+    let out = space.run_expect(&mut n2_command(vec!["--add-missing-deps", "out"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn failed_outputs_removed() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule partial
+  command = echo partial > $out && exit 1
+rule fail
+  command = exit 1
+build out: partial
+build untouched: fail
+",
+    )?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "failed");
+    assert!(space.read("out").is_err());
+
+    space.run(&mut n2_command(vec!["--keep-failed-outputs", "out"]))?;
+    assert_eq!(space.read("out")?, b"partial\n");
+
+    // An output the failed command didn't modify is kept.
+    space.write("untouched", "")?;
+    space.run(&mut n2_command(vec!["untouched"]))?;
+    assert!(space.read("untouched").is_ok());
+
+    // Likewise when running just the build, with --only.
+    let out = space.run(&mut n2_command(vec!["--only", "untouched"]))?;
+    assert_output_contains(&out, "failed");
+    assert!(space.read("untouched").is_ok());
+    space.run(&mut n2_command(vec!["--only", "out"]))?;
+    assert!(space.read("out").is_err());
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn check_content() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cp
+  command = cp $in $out
+build mid: cp in
+build out: cp mid
+",
+    )?;
+    space.write("in", "a")?;
+    let touch = |path: &str, secs: u64| -> std::io::Result<()> {
+        std::fs::File::options()
+            .write(true)
+            .open(space.dir.path().join(path))?
+            .set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(secs))
+    };
+    let out = space.run_expect(&mut n2_command(vec!["--check-content", "out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+
+    // Touching the input without changing it doesn't rebuild.
+    touch("in", 1)?;
+    let out = space.run_expect(&mut n2_command(vec!["--check-content", "out"]))?;
+    assert_output_contains(&out, "no work to do");
+    let out = space.run_expect(&mut n2_command(vec!["--check-content", "out"]))?;
+    assert_output_contains(&out, "no work to do");
+
+    // But changing it does.
+    space.write("in", "b")?;
+    let out = space.run_expect(&mut n2_command(vec!["--check-content", "out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+
+    // An intermediate rebuilt with the same content doesn't rebuild what
+    // depends on it.
+    touch("mid", 1)?;
+    let out = space.run_expect(&mut n2_command(vec!["--check-content", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    assert!(!std::str::from_utf8(&out.stdout)?.contains("cp mid out"));
+
+    // Without the flag, mtimes are compared as usual.
+    touch("in", 2)?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+
+    // A build file can ask for content checking itself, from any file.
+    space.write("build.ninja", "include inc.ninja\nbuild out: cp mid\n")?;
+    space.write(
+        "inc.ninja",
+        "# n2: hash = content\nrule cp\n  command = cp $in $out\nbuild mid: cp in\n",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    touch("in", 3)?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+/// Archive timestamps are only ignored when asked for.
+#[cfg(unix)]
+#[test]
+fn normalize_content() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cp
+  command = cp $in $out
+build out: cp in.gz
+",
+    )?;
+    // A gzip header whose bytes 4..8 are its timestamp.
+    let gzip = |mtime: u8| [0x1f, 0x8b, 8, 0, mtime, 0, 0, 0, 0, 3, b'x'];
+    let path = space.dir.path().join("in.gz");
+    std::fs::write(&path, gzip(1))?;
+    let args = vec!["--check-content", "--normalize-content", "out"];
+    let out = space.run_expect(&mut n2_command(args.clone()))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    let rewrite = |mtime: u8, secs: u64| -> std::io::Result<()> {
+        std::fs::write(&path, gzip(mtime))?;
+        std::fs::File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(secs))
+    };
+    rewrite(2, 1)?;
+    let out = space.run_expect(&mut n2_command(args))?;
+    assert_output_contains(&out, "no work to do");
+
+    // Plain --check-content compares the bytes as they are.
+    rewrite(3, 2)?;
+    let out = space.run_expect(&mut n2_command(vec!["--check-content", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+#[test]
+fn manifest_check() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule nocmd
+  description = nothing
+rule cc
+  command = cc $in
+  pool = nopool
+build a: phony b
+build b: phony a
+build c: nosuchrule
+build d: nocmd
+build e: cc
+build e: cc
+",
+    )?;
+    let out = space.run(&mut n2_command(vec!["--manifest-check"]))?;
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(
+        std::str::from_utf8(&out.stdout)?,
+        "n2: error: build.ninja:9:10: unknown rule \"nosuchrule\"
+n2: error: build.ninja:12:7: multiple rules generate e
+n2: error: build.ninja:10: rule \"nocmd\" has no command
+n2: error: build.ninja:11: unknown pool \"nopool\"
+n2: error: build.ninja:7: dependency cycle: a -> b -> a
+"
+    );
+
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch", ""].join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["--manifest-check"]))?;
+    assert_output_contains(&out, "no problems found");
+    assert!(space.read("out").is_err());
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupted_exit_code() -> anyhow::Result<()> {
+    use std::os::unix::process::CommandExt;
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule slow
+  command = touch started && sleep 10 && touch $out
+build out: slow
+",
+    )?;
+    // Run in a process group of its own, so that signalling the group
+    // reaches n2 and its commands as Ctrl-C would.
+    let child = n2_command(vec!["out"])
+        .current_dir(space.dir.path())
+        .stdout(std::process::Stdio::piped())
+        .process_group(0)
+        .spawn()?;
+    while space.read("started").is_err() {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    std::process::Command::new("kill")
+        .args(["-INT", "--", &format!("-{}", child.id())])
+        .status()?;
+    let out = child.wait_with_output()?;
+    assert_eq!(out.status.code(), Some(130));
+    assert_output_contains(&out, "n2: error: interrupted");
+    Ok(())
+}
+
+#[test]
+fn always_dirty() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build always: phony",
+            "  always_dirty = 1",
+            "build via: phony always",
+            "build stamp: touch | via",
+            "build order: touch || always",
+            "build other: touch",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["stamp", "order", "other"]))?;
+    assert_output_contains(&out, "ran 3 tasks");
+    // Only the build depending on the always-dirty one reruns; order-only
+    // dependencies don't count.
+    for _ in 0..2 {
+        let out = space.run_expect(&mut n2_command(vec!["stamp", "order", "other"]))?;
+        assert_output_contains(&out, "ran 1 task");
+    }
+    Ok(())
+}
+
+#[test]
+fn salt_file() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build salted: touch",
+            "  salt_file = toolchain.txt",
+            "build plain: touch",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("toolchain.txt", "v1")?;
+    let out = space.run_expect(&mut n2_command(vec!["salted", "plain"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    let out = space.run_expect(&mut n2_command(vec!["salted", "plain"]))?;
+    assert_output_contains(&out, "no work to do");
+
+    space.write("toolchain.txt", "v2")?;
+    let out = space.run_expect(&mut n2_command(vec!["salted", "plain"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    let out = space.run_expect(&mut n2_command(vec!["salted", "plain"]))?;
+    assert_output_contains(&out, "no work to do");
+
+    // A missing salt file dirties the build too.
+    std::fs::remove_file(space.dir.path().join("toolchain.txt"))?;
+    let out = space.run_expect(&mut n2_command(vec!["salted", "plain"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+#[test]
+fn print_directory() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    std::fs::create_dir(space.dir.path().join("sub"))?;
+    space.write(
+        "sub/build.ninja",
+        &[TOUCH_RULE, "build out: touch", ""].join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec![
+        "-C",
+        "sub",
+        "--print-directory",
+        "out",
+    ]))?;
+    let dir = space.dir.path().join("sub").canonicalize()?;
+    let stdout = std::str::from_utf8(&out.stdout)?;
+    let enter = format!("n2: Entering directory '{}'\n", dir.display());
+    let leave = format!("n2: Leaving directory '{}'\n", dir.display());
+    assert!(stdout.starts_with(&enter), "{}", stdout);
+    assert!(stdout.ends_with(&leave), "{}", stdout);
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+#[test]
+fn verbose_output_not_interleaved() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    let mut manifest = vec!["rule echo", "  command = echo $out"];
+    let outs: Vec<String> = (0..16).map(|i| format!("build out{}: echo", i)).collect();
+    manifest.extend(outs.iter().map(|line| line.as_str()));
+    manifest.push("");
+    space.write("build.ninja", &manifest.join("\n"))?;
+    let mut args = vec!["-v", "-j", "16"];
+    let targets: Vec<String> = (0..16).map(|i| format!("out{}", i)).collect();
+    args.extend(targets.iter().map(|t| t.as_str()));
+    let out = space.run_expect(&mut n2_command(args))?;
+    // Each echoed command line is immediately followed by its own output.
+    let stdout = std::str::from_utf8(&out.stdout)?;
+    let lines: Vec<&str> = stdout.lines().collect();
+    for target in &targets {
+        let pos = lines
+            .iter()
+            .position(|line| *line == format!("echo {}", target))
+            .unwrap_or_else(|| panic!("no command line for {} in {}", target, stdout));
+        assert_eq!(lines.get(pos + 1), Some(&target.as_str()), "{}", stdout);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn sandbox() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cat
+  command = cat $in extra > $out
+build sub/declared: cat in | extra
+build undeclared: cat in
+",
+    )?;
+    space.write("in", "in\n")?;
+    space.write("extra", "extra\n")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["--sandbox", "sub/declared"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    assert_eq!(space.read("sub/declared")?, b"in\nextra\n");
+    // The sandbox is removed once the command is done.
+    let sandboxes = std::fs::read_dir(space.dir.path().join(".n2_sandbox"))?;
+    assert_eq!(sandboxes.count(), 0);
+
+    // Without the sandbox the undeclared input is found...
+    let out = space.run_expect(&mut n2_command(vec!["undeclared"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    // ...but in it, the command can't read it.
+    space.write("in", "in2\n")?;
+    let out = space.run(&mut n2_command(vec!["--sandbox", "undeclared"]))?;
+    assert_output_contains(&out, "extra: No such file");
+    Ok(())
+}
+
+#[test]
+fn corrupt_db() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch", ""].join("\n"),
+    )?;
+    // A build record, with one output, referring to a file id that was
+    // never defined.
+    let mut db = vec![0x80, 0x01, 0x00, 0x00, 0x05, 0x00, 0x00];
+    db.extend_from_slice(&[0; 8]);
+    std::fs::write(space.dir.path().join(".n2_db"), db)?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "load .n2_db: corrupt database: unknown file id 5");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn ignore_output_mtimes() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    let manifest = |flags: &str| {
+        format!(
+            "
+rule gen
+  command = echo {} > $out && touch -d 2000-01-01 $out
+build out: gen in
+",
+            flags
+        )
+    };
+    space.write("build.ninja", &manifest("a"))?;
+    space.write("in", "")?;
+    let n2 = || n2_command(vec!["--ignore-output-mtimes", "out"]);
+    let out = space.run_expect(&mut n2())?;
+    assert_output_contains(&out, "ran 1 task");
+
+    // Resetting the output's timestamp doesn't matter.
+    std::fs::File::options()
+        .write(true)
+        .open(space.dir.path().join("out"))?
+        .set_modified(std::time::SystemTime::UNIX_EPOCH)?;
+    let out = space.run_expect(&mut n2())?;
+    assert_output_contains(&out, "no work to do");
+
+    // But a changed command still rebuilds, as does a missing output.
+    space.write("build.ninja", &manifest("b"))?;
+    let out = space.run_expect(&mut n2())?;
+    assert_output_contains(&out, "ran 1 task");
+    assert_eq!(space.read("out")?, b"b\n");
+    std::fs::remove_file(space.dir.path().join("out"))?;
+    let out = space.run_expect(&mut n2())?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+/// With --hash-relative-paths, moving the build directory doesn't dirty
+/// commands that name files by absolute path.
+#[cfg(unix)]
+#[test]
+fn hash_relative_paths() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    let manifest = |dir: &str| {
+        let dir = space.dir.path().join(dir);
+        format!(
+            "rule touch\n  command = touch {}/out\nbuild out: touch\n",
+            dir.display()
+        )
+    };
+    std::fs::create_dir(space.dir.path().join("one"))?;
+    space.write("one/build.ninja", &manifest("one"))?;
+    space.run_expect(&mut n2_command(vec![
+        "-C",
+        "one",
+        "--hash-relative-paths",
+        "out",
+    ]))?;
+
+    std::fs::rename(space.dir.path().join("one"), space.dir.path().join("two"))?;
+    space.write("two/build.ninja", &manifest("two"))?;
+    let out = space.run_expect(&mut n2_command(vec![
+        "-C",
+        "two",
+        "--hash-relative-paths",
+        "out",
+    ]))?;
+    assert_output_contains(&out, "no work to do");
+
+    // Without the flag, the changed command dirties the build.
+    let out = space.run_expect(&mut n2_command(vec!["-C", "two", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+/// Running out of file descriptors holds back new commands until running
+/// ones finish, rather than failing them.
+#[cfg(unix)]
+#[test]
+fn out_of_fds_backs_off() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    let outs: Vec<String> = (0..40).map(|i| format!("out{}", i)).collect();
+    let mut manifest = "rule slow\n  command = sleep 0.2 && touch $out\n".to_string();
+    for out in &outs {
+        manifest.push_str(&format!("build {}: slow\n", out));
+    }
+    manifest.push_str(&format!("build all: phony {}\n", outs.join(" ")));
+    space.write("build.ninja", &manifest)?;
+    let out = space.run_expect(std::process::Command::new("/bin/sh").arg("-c").arg(format!(
+        "ulimit -n 16 && exec {} -j 32 all",
+        n2_binary().display()
+    )))?;
+    assert_output_contains(&out, "ran 40 tasks");
+    Ok(())
+}
+
+/// An implicit output marked optional by extension may never be produced
+/// without the build rerunning every time.
+#[cfg(unix)]
+#[test]
+fn optional_outputs() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule link
+  command = touch out
+  optional_outputs = .map
+build out | out.map: link
+",
+    )?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+
+    // The optional output showing up dirties the build.
+    space.write("out.map", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    // Nothing may depend on an optional output, as it may never exist.
+    space.write(
+        "build.ninja",
+        "
+rule link
+  command = touch out
+  optional_outputs = .map
+build out | out.map: link
+build map.txt: link | out.map || out
+",
+    )?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(
+        &out,
+        "build.ninja:6: input out.map is an optional output of the build at build.ninja:5",
+    );
+
+    // Order-only dependencies on it are fine.
+    space.write(
+        "build.ninja",
+        "
+rule link
+  command = touch out
+  optional_outputs = .map
+build out | out.map: link
+rule use
+  command = touch $out
+build after: use || out.map
+",
+    )?;
+    space.run_expect(&mut n2_command(vec!["after"]))?;
+    Ok(())
+}
+
+/// --verify-targets fails a build whose command succeeded without writing
+/// the wanted output.
+#[test]
+fn verify_targets() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "
+rule forget
+  command = echo forgot
+build out: forget
+build touched: touch
+build all: phony out touched
+",
+        ]
+        .join("\n"),
+    )?;
+    // Without the flag the build passes, and just reruns next time.
+    space.run_expect(&mut n2_command(vec!["all"]))?;
+
+    let out = space.run(&mut n2_command(vec!["--verify-targets", "out", "touched"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(
+        &out,
+        "build.ninja:8: out is missing though its command succeeded",
+    );
+    assert!(!String::from_utf8_lossy(&out.stdout).contains("touched is missing"));
+
+    // Phony targets needn't exist, but the outputs they name must.
+    let out = space.run_expect(&mut n2_command(vec!["--verify-targets", "touched"]))?;
+    assert!(!String::from_utf8_lossy(&out.stdout).contains("is missing"));
+    let out = space.run(&mut n2_command(vec!["--verify-targets", "all"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(
+        &out,
+        "build.ninja:8: out is missing though its command succeeded",
+    );
+    assert!(!String::from_utf8_lossy(&out.stdout).contains("all is missing"));
+    Ok(())
+}
+
+/// -k keeps building what doesn't depend on a failed command, and reports
+/// what was skipped.
+#[cfg(unix)]
+#[test]
+fn keep_going() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "
+rule fail
+  command = false
+build bad: fail
+build good: touch
+build top: touch bad good
+",
+        ]
+        .join("\n"),
+    )?;
+    // By default the build stops at the failure.
+    let out = space.run(&mut n2_command(vec!["-j1", "top"]))?;
+    assert!(!out.status.success());
+
+    let out = space.run(&mut n2_command(vec!["-k", "0", "top"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "n2: error: 1 failed, 1 skipped");
+    assert!(space.read("good").is_ok());
+    assert!(space.read("top").is_err());
+    Ok(())
+}
+
+/// Ctrl-C stops the build even with -k, rather than counting the interrupted
+/// commands as failures and starting more.
+#[cfg(unix)]
+#[test]
+fn keep_going_interrupted() -> anyhow::Result<()> {
+    use std::os::unix::process::CommandExt;
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule slow
+  command = touch started_$out && sleep 10 && touch $out
+build a: slow
+build b: slow
+build all: phony a b
+",
+    )?;
+    let child = n2_command(vec!["-k", "0", "-j1", "all"])
+        .current_dir(space.dir.path())
+        .stdout(std::process::Stdio::piped())
+        .process_group(0)
+        .spawn()?;
+    while space.read("started_a").is_err() && space.read("started_b").is_err() {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    std::process::Command::new("kill")
+        .args(["-INT", "--", &format!("-{}", child.id())])
+        .status()?;
+    let out = child.wait_with_output()?;
+    assert_eq!(out.status.code(), Some(130));
+    // Only the first command ever started.
+    assert!(space.read("started_a").is_err() || space.read("started_b").is_err());
+    Ok(())
+}